@@ -1,10 +1,11 @@
 use std::{fmt, ops::Deref, path::PathBuf};
 
 use clap::{Parser, Subcommand, ValueEnum};
-use color_eyre::eyre::{Result, WrapErr};
+use color_eyre::eyre::{bail, Result, WrapErr};
 use llm::{
-    ggml_format, ElementType, InferenceParameters, InferenceSessionConfig, InvalidTokenBias,
-    LoadProgress, Model, ModelKVMemoryType, ModelParameters, TokenBias,
+    ggml_format, ContextOverflowPolicy, ElementType, InferenceParameters, InferenceSessionConfig,
+    InvalidTokenBias, LoadProgress, Model, ModelDynamicOverrides, ModelKVMemoryType,
+    ModelParameters, TokenBias,
 };
 use rand::SeedableRng;
 
@@ -246,6 +247,16 @@ pub struct Generate {
     #[arg(long, default_value_t = false)]
     pub float16: bool,
 
+    /// Regenerate the last token of the prompt, so that a prompt that ends in
+    /// the middle of a word is continued as if the word had not been split.
+    #[arg(long, default_value_t = false)]
+    pub token_healing: bool,
+
+    /// Keep generating once the context window is full, by discarding the oldest
+    /// tokens after the first N. Without this, generation stops when the context is full.
+    #[arg(long, value_name = "N", default_value = None)]
+    pub context_keep: Option<usize>,
+
     /// A comma separated list of token biases. The list should be in the format
     /// "TID=BIAS,TID=BIAS" where TID is an integer token ID and BIAS is a
     /// floating point number.
@@ -292,6 +303,11 @@ impl Generate {
         InferenceSessionConfig {
             memory_k_type: mem_typ,
             memory_v_type: mem_typ,
+            rng_seed: self.seed,
+            context_overflow: match self.context_keep {
+                Some(keep) => ContextOverflowPolicy::ShiftLeft { keep },
+                None => ContextOverflowPolicy::Error,
+            },
         }
     }
 
@@ -318,6 +334,7 @@ impl Generate {
                     TokenBias::default()
                 }
             }),
+            logit_bias: Default::default(),
             repetition_penalty_last_n: self.repeat_last_n,
         }
     }
@@ -353,6 +370,12 @@ pub struct ModelLoad {
     /// LoRA adapter to use for the model
     #[arg(long, num_args(0..))]
     pub lora_paths: Option<Vec<PathBuf>>,
+
+    /// Check that the model has this many key/value heads, as LLaMA models with
+    /// grouped-query attention such as LLaMA 2 70B do. The number is read from the
+    /// model's weights, and loading fails if it differs.
+    #[arg(long, default_value = None)]
+    pub n_head_kv: Option<usize>,
 }
 impl ModelLoad {
     pub fn load<M: llm::KnownModel + 'static>(
@@ -362,10 +385,27 @@ impl ModelLoad {
         let params = ModelParameters {
             prefer_mmap: !self.no_mmap,
             n_context_tokens: self.num_ctx_tokens,
-            lora_adapters: self.lora_paths.clone(),
+            lora_adapters: self
+                .lora_paths
+                .as_ref()
+                .map(|paths| paths.iter().map(|path| (path.clone(), 1.0)).collect()),
             ..Default::default()
         };
 
+        let overrides = match self.n_head_kv {
+            Some(n_head_kv) => {
+                let mut dynamic: ModelDynamicOverrides = overrides.unwrap_or_default().into();
+                dynamic.insert("n_head_kv", n_head_kv as i64);
+                // Overrides the architecture does not have are dropped by the conversion.
+                let dynamic: ModelDynamicOverrides = M::Overrides::from(dynamic).into();
+                if dynamic.get::<i64>("n_head_kv").is_none() {
+                    bail!("--n-head-kv is not supported by this model architecture");
+                }
+                Some(dynamic.into())
+            }
+            None => overrides,
+        };
+
         let mut sp = Some(spinoff::Spinner::new(
             spinoff::spinners::Dots2,
             "Loading model...",
@@ -388,6 +428,12 @@ impl ModelLoad {
                     "ggml ctx size = {}",
                     bytesize::to_string(bytes as u64, false)
                 ),
+                LoadProgress::FileTypeMismatch {
+                    file_type,
+                    weight_type,
+                } => log::warn!(
+                    "The model's file type is {file_type}, but its weights are mostly {weight_type}"
+                ),
                 LoadProgress::LoraApplied { name, source } => {
                     if let Some(sp) = sp.as_mut() {
                         sp.update_text(format!(
@@ -513,7 +559,7 @@ impl From<FileType> for llm::FileTypeFormat {
 
 #[derive(Parser, Debug)]
 pub struct Quantize {
-    /// The path to the model to quantize
+    /// The path to the model to quantize, in a GGML, GGMF or GGJT container
     #[arg()]
     pub source: PathBuf,
 
@@ -73,6 +73,7 @@ fn infer<M: llm::KnownModel + 'static>(
             parameters: Some(&inference_params),
             play_back_previous_tokens: session_loaded,
             maximum_token_count: args.generate.num_predict,
+            token_healing: args.generate.token_healing,
         },
         // OutputRequest
         &mut Default::default(),
@@ -106,6 +107,12 @@ fn infer<M: llm::KnownModel + 'static>(
         Err(InferenceError::TokenizationFailed(err)) => {
             log::error!("A tokenization-related failure occurred: {}", err);
         }
+        Err(InferenceError::InvalidParameters(err)) => {
+            log::error!("Invalid inference parameters: {}", err);
+        }
+        Err(InferenceError::Cancelled) => {
+            log::warn!("Inference was cancelled.")
+        }
         Err(InferenceError::UserCallback(_)) | Err(InferenceError::EndOfText) => {
             unreachable!("cannot fail")
         }
@@ -275,6 +282,7 @@ fn interactive<M: llm::KnownModel + 'static>(
                         parameters: Some(&inference_params),
                         play_back_previous_tokens: session_loaded,
                         maximum_token_count: args.generate.num_predict,
+                        token_healing: false,
                     },
                     // EvaluateOuputRequest
                     &mut Default::default(),
@@ -1,10 +1,16 @@
 use std::{
     os::raw::{c_int, c_void},
     ptr::NonNull,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-use crate::{sys, usize_to_i32, usize_to_i64, Buffer, ComputationGraph, Tensor, Type};
+use crate::{
+    sys, usize_to_i32, usize_to_i64, Buffer, Cancelled, ComputationGraph, ComputeStats,
+    MatMulPrecision, Tensor, Type,
+};
 
 /// Acts as a RAII-guard over a `sys::ggml_context`, allocating via
 /// `ggml_init` and dropping via `ggml_free`.
@@ -14,6 +20,13 @@ pub struct Context {
     /// contains a `Weak` reference underneath and doesn't let you do anything
     /// with it if the underlying context has been deallocated.
     ptr: Arc<NonNull<sys::ggml_context>>,
+
+    /// The size of the memory pool this context was created with.
+    mem_size: usize,
+
+    /// The number of bytes of tensor data that would have been allocated
+    /// if this context was not created with `alloc: false`.
+    unallocated_data: AtomicUsize,
 }
 
 impl Context {
@@ -30,11 +43,33 @@ impl Context {
         };
         Self {
             ptr: Arc::new(NonNull::new(raw).expect("Should not be null")),
+            mem_size,
+            unallocated_data: AtomicUsize::new(0),
         }
     }
 
+    /// Creates a new [Context] that does not allocate any tensor data, and
+    /// instead keeps track of how much memory would be required.
+    ///
+    /// `mem_size` only needs to be large enough for the tensor metadata.
+    /// After building a graph with this context, use [Context::required_mem]
+    /// to size a real context for the same graph. The tensors created by this
+    /// context have no data, and must not be read from, written to, or computed.
+    pub fn new_measure(mem_size: usize) -> Self {
+        Self::init(mem_size, false)
+    }
+
     /// Wraps a raw tensor with a weak pointer to the context.
     fn new_tensor_raw(&self, raw: *mut sys::ggml_tensor) -> Tensor {
+        // SAFETY: `raw` was just returned by ggml and points to a valid tensor.
+        unsafe {
+            if (*raw).data.is_null() {
+                // Round up to ggml's allocation alignment of 16 bytes.
+                let n_bytes = (sys::ggml_nbytes(raw) + 15) & !15;
+                self.unallocated_data.fetch_add(n_bytes, Ordering::Relaxed);
+            }
+        }
+
         Tensor {
             ptr: NonNull::new(raw).expect("Should not be null"),
             ctx: Arc::downgrade(&self.ptr),
@@ -75,20 +110,68 @@ impl Context {
         self.new_tensor_raw(raw)
     }
 
+    /// Creates a new 4D tensor.
+    pub fn new_tensor_4d(
+        &self,
+        typ: Type,
+        ne0: usize,
+        ne1: usize,
+        ne2: usize,
+        ne3: usize,
+    ) -> Tensor {
+        let raw = unsafe {
+            sys::ggml_new_tensor_4d(
+                self.ptr.as_ptr(),
+                typ.into(),
+                usize_to_i64(ne0),
+                usize_to_i64(ne1),
+                usize_to_i64(ne2),
+                usize_to_i64(ne3),
+            )
+        };
+        self.new_tensor_raw(raw)
+    }
+
     /// Creates a new 1D tensor with the specified value.
     pub fn new_f32(&self, x: f32) -> Tensor {
         let raw = unsafe { sys::ggml_new_f32(self.ptr.as_ptr(), x) };
         self.new_tensor_raw(raw)
     }
 
-    /// Unknown, aside from the obvious. It's transposing something!
+    /// Swaps the first two dimensions of `a`, so that a `[ne0, ne1]` matrix becomes `[ne1, ne0]`.
+    ///
+    /// This only swaps the strides of a view onto `a`'s data, so the result is not
+    /// contiguous; use [Context::op_cont] to copy it into a contiguous tensor before
+    /// operations that require one.
     pub fn op_transpose(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_transpose(self.ptr.as_ptr(), a.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
-    /// Unknown.
+    /// Gathers the rows of `a` selected by the indices in `b` into a new F32 tensor.
+    ///
+    /// `a` may be an F32, F16, Q4_0, Q4_1, Q5_0, Q5_1 or Q8_0 tensor; quantized
+    /// rows are dequantized as they are gathered. `b` must be an I32 tensor.
+    ///
+    /// # Panics
+    ///
+    /// - If `a` or `b` have an unsupported type.
     pub fn op_get_rows(&self, a: &Tensor, b: &Tensor) -> Tensor {
+        assert!(
+            matches!(
+                a.get_type(),
+                Type::F32
+                    | Type::F16
+                    | Type::Q4_0
+                    | Type::Q4_1
+                    | Type::Q5_0
+                    | Type::Q5_1
+                    | Type::Q8_0
+            ),
+            "cannot gather rows from a {} tensor",
+            a.get_type()
+        );
+        assert_eq!(b.get_type(), Type::I32, "row indices must be I32");
         let tensor =
             unsafe { sys::ggml_get_rows(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
@@ -118,6 +201,50 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// The inverse of [Context::op_repeat]: reduces `a` to the shape of `b` by summing over the
+    /// repeated blocks.
+    ///
+    /// Each of the first three dimensions of `a` must be a multiple of the matching dimension of `b`,
+    /// and the fourth dimension of both must be 1.
+    ///
+    /// The version of `ggml` in use does not provide `ggml_repeat_back`, so this is built out of
+    /// one view and one addition per repeated block; avoid using it for large repeat counts.
+    pub fn op_repeat_back(&self, a: &Tensor, b: &Tensor) -> Tensor {
+        let ne = a.dims();
+        let target = b.dims();
+        let nb = a.get_nb();
+        assert!(
+            ne[3] == 1 && target[3] == 1,
+            "op_repeat_back only supports tensors with up to 3 dimensions"
+        );
+        assert!(
+            (0..3).all(|i| ne[i] % target[i] == 0),
+            "cannot repeat_back a tensor of shape {ne:?} to shape {target:?}"
+        );
+
+        let mut result: Option<Tensor> = None;
+        for i2 in 0..ne[2] / target[2] {
+            for i1 in 0..ne[1] / target[1] {
+                for i0 in 0..ne[0] / target[0] {
+                    let offset =
+                        i0 * target[0] * nb[0] + i1 * target[1] * nb[1] + i2 * target[2] * nb[2];
+                    let block = self.op_view_3d(
+                        a,
+                        (target[0], target[1], target[2]),
+                        (nb[1], nb[2]),
+                        offset,
+                    );
+                    result = Some(match result {
+                        Some(sum) => self.op_add(&sum, &block),
+                        None => self.op_cont(&block),
+                    });
+                }
+            }
+        }
+
+        self.op_reshape(&result.expect("there is at least one block"), b)
+    }
+
     /// Creates a new tensor with the multiplication of `a` and `b` as if they were matrices.
     ///
     /// `a`: m rows, n columns
@@ -125,18 +252,98 @@ impl Context {
     /// `b`: p rows, n columns (i.e. we transpose it internally)
     ///
     /// Result is m columns, p rows
+    ///
+    /// `a` may be an F32, F16, Q4_0, Q4_1, Q5_0, Q5_1 or Q8_0 tensor, and must not be
+    /// transposed. `b` must be an F32 tensor; it is converted to the type that `a` is
+    /// multiplied with internally. Both must have the same number of columns, and the
+    /// same extent in their third and fourth dimensions.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if the types or shapes of `a` and `b` are incompatible. ggml
+    /// would otherwise abort the process or produce garbage.
     pub fn op_mul_mat(&self, a: &Tensor, b: &Tensor) -> Tensor {
+        if cfg!(debug_assertions) {
+            assert!(
+                matches!(
+                    a.get_type(),
+                    Type::F32
+                        | Type::F16
+                        | Type::Q4_0
+                        | Type::Q4_1
+                        | Type::Q5_0
+                        | Type::Q5_1
+                        | Type::Q8_0
+                ),
+                "cannot multiply a {} matrix",
+                a.get_type()
+            );
+            assert_eq!(
+                b.get_type(),
+                Type::F32,
+                "cannot multiply by a {} matrix; it must be F32",
+                b.get_type()
+            );
+            let (a_dims, b_dims) = (a.dims(), b.dims());
+            assert!(
+                a_dims[0] == b_dims[0] && a_dims[2..] == b_dims[2..],
+                "cannot multiply matrices of incompatible shapes {a_dims:?} and {b_dims:?}"
+            );
+            let a_nb = a.get_nb();
+            assert!(a_nb[0] <= a_nb[1], "cannot multiply a transposed matrix");
+        }
+
         let tensor =
             unsafe { sys::ggml_mul_mat(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
+    /// Like [Self::op_mul_mat], but multiplies `a` and `b` with the given `precision`.
+    ///
+    /// See [MatMulPrecision] for the trade-off between speed and accuracy.
+    ///
+    /// With [MatMulPrecision::F16], an `F32` `a` is copied into a new `F16` tensor of the same
+    /// shape, so each call allocates that copy in this context, and the graph converts `a`
+    /// into it again on every computation. An `a` that is already `F16` is used as is.
+    pub fn op_mul_mat_precision(
+        &self,
+        a: &Tensor,
+        b: &Tensor,
+        precision: MatMulPrecision,
+    ) -> Tensor {
+        match (precision, a.get_type()) {
+            (MatMulPrecision::F16, Type::F32) => {
+                let ne = a.get_ne();
+                let a_f16 = self.new_tensor_raw(unsafe {
+                    sys::ggml_new_tensor(
+                        self.ptr.as_ptr(),
+                        Type::F16.into(),
+                        usize_to_i32(a.n_dims()),
+                        ne.as_ptr(),
+                    )
+                });
+                self.op_mul_mat(&self.op_cpy(a, &a_f16), b)
+            }
+            _ => self.op_mul_mat(a, b),
+        }
+    }
+
     /// Creates a new tensor with the addition of `a` and `b`.
     pub fn op_add(&self, a: &Tensor, b: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_add(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
+    /// Creates a new tensor with the single-element tensor `scalar` added to every element of `a`.
+    ///
+    /// Unlike adding an [op_repeat](Self::op_repeat)ed tensor, this does not allocate a
+    /// copy of `scalar` for every element.
+    pub fn op_add1(&self, a: &Tensor, scalar: &Tensor) -> Tensor {
+        let tensor =
+            unsafe { sys::ggml_add1(self.ptr.as_ptr(), a.ptr.as_ptr(), scalar.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
     /// Creates a new tensor with the [SiLU](https://pytorch.org/docs/stable/generated/torch.nn.SiLU.html) activation function applied to `a`.
     pub fn op_silu(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_silu(self.ptr.as_ptr(), a.ptr.as_ptr()) };
@@ -149,6 +356,13 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Scales `a` by `scale`.
+    ///
+    /// This is equivalent to [Self::op_scale] with a scalar tensor created by [Self::new_f32].
+    pub fn op_scale_f32(&self, a: &Tensor, scale: f32) -> Tensor {
+        self.op_scale(a, &self.new_f32(scale))
+    }
+
     /// In-place, scales `a` by the 1D tensor `b`.
     pub fn op_scale_inplace(&self, a: &Tensor, b: &Tensor) -> Tensor {
         let tensor =
@@ -164,6 +378,14 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Sets the elements above the diagonal to 0.
+    pub fn op_diag_mask_zero(&self, a: &Tensor, n_past: usize) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_diag_mask_zero(self.ptr.as_ptr(), a.ptr.as_ptr(), usize_to_i32(n_past))
+        };
+        self.new_tensor_raw(tensor)
+    }
+
     /// In-place, sets the elements above the diagonal to -INF.
     pub fn op_diag_mask_inf_inplace(&self, a: &Tensor, n_past: usize) -> Tensor {
         let tensor = unsafe {
@@ -172,12 +394,83 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Like [Self::op_diag_mask_inf], but additionally sets the elements for keys more
+    /// than `window` positions before their query to -INF, for sliding-window attention.
+    ///
+    /// `a` holds the attention scores with the keys along its first dimension and the
+    /// queries, starting at position `n_past`, along its second. The query at position
+    /// `p` can attend to the keys at positions `p - window` to `p`.
+    ///
+    /// ggml has no native operation for this, so the window is applied by adding a mask
+    /// that is built within the graph.
+    pub fn op_diag_mask_inf_window(&self, a: &Tensor, n_past: usize, window: usize) -> Tensor {
+        let [n_kv, n, ..] = a.dims();
+        let causal = self.op_diag_mask_inf(a, n_past);
+
+        // The query in row `i` must not see the keys in the columns `j <= i + offset`.
+        let offset = usize_to_i64(n_past) - usize_to_i64(window) - 1;
+        if offset + usize_to_i64(n) - 1 < 0 {
+            // Every key before the queries is within the window.
+            return causal;
+        }
+
+        // ggml can only select the columns `j <= i + k` for `k >= 0`, so the selection is
+        // made in a tensor of ones that is `shift` columns wider, and then viewed.
+        let shift = usize::try_from(-offset.min(0)).unwrap();
+        let k = usize::try_from(offset + usize_to_i64(shift)).unwrap();
+        let shape = self.new_tensor_2d(Type::F32, n_kv + shift, n);
+        let selected = self.op_diag_mask_zero(&self.op_repeat(&self.new_f32(1.0), &shape), k);
+        let [_, nb1, ..] = selected.get_nb();
+        let outside = self.op_cont(&self.op_view_2d(
+            &selected,
+            (n_kv, n),
+            nb1,
+            shift * std::mem::size_of::<f32>(),
+        ));
+
+        // log(1 - 1) is -inf for the keys outside the window, and log(1 - 0) is 0.
+        let one = self.new_f32(1.0);
+        let mask = self.op_log(&self.op_add1(&self.op_neg(&outside), &one));
+        self.op_add_mask(&causal, &mask)
+    }
+
     /// Applies the [Softmax function](https://en.wikipedia.org/wiki/Softmax_function) to `a`.
     pub fn op_soft_max(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_soft_max(self.ptr.as_ptr(), a.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
 
+    /// Applies the [Softmax function](https://en.wikipedia.org/wiki/Softmax_function) to
+    /// `a * scale + mask`, as is done to the scores of an attention head.
+    ///
+    /// `mask` is added after scaling with [Context::op_add_mask]. The softmax subtracts the
+    /// maximum of each row before exponentiating, so large scores do not overflow.
+    ///
+    /// The version of `ggml` in use does not provide the fused `ggml_soft_max_ext`, so this is
+    /// built out of [Context::op_scale_f32], [Context::op_add_mask] and [Context::op_soft_max].
+    pub fn op_soft_max_ext(&self, a: &Tensor, scale: f32, mask: Option<&Tensor>) -> Tensor {
+        let scaled = self.op_scale_f32(a, scale);
+        let masked = match mask {
+            Some(mask) => self.op_add_mask(&scaled, mask),
+            None => scaled,
+        };
+        self.op_soft_max(&masked)
+    }
+
+    /// Adds the additive `mask` to the attention `scores`, so that the positions where `mask`
+    /// is `-inf` get no weight from a following [Context::op_soft_max]. This generalizes
+    /// [Context::op_diag_mask_inf] to arbitrary patterns, such as padding.
+    ///
+    /// `mask` is repeated to the shape of `scores` if it is smaller (e.g. one mask shared by
+    /// all heads), so each of its dimensions must divide the matching dimension of `scores`.
+    pub fn op_add_mask(&self, scores: &Tensor, mask: &Tensor) -> Tensor {
+        if mask.get_ne() == scores.get_ne() {
+            self.op_add(scores, mask)
+        } else {
+            self.op_add(scores, &self.op_repeat(mask, scores))
+        }
+    }
+
     /// In-place, applies the [Softmax function](https://en.wikipedia.org/wiki/Softmax_function) to `a`.
     pub fn op_soft_max_inplace(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_soft_max_inplace(self.ptr.as_ptr(), a.ptr.as_ptr()) };
@@ -285,6 +578,167 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Creates a 4d view over `a`.
+    pub fn op_view_4d(
+        &self,
+        a: &Tensor,
+        ne: (usize, usize, usize, usize),
+        nb: (usize, usize, usize),
+        offset: usize,
+    ) -> Tensor {
+        let (ne0, ne1, ne2, ne3) = ne;
+        let (nb1, nb2, nb3) = nb;
+        let tensor = unsafe {
+            sys::ggml_view_4d(
+                self.ptr.as_ptr(),
+                a.ptr.as_ptr(),
+                usize_to_i64(ne0),
+                usize_to_i64(ne1),
+                usize_to_i64(ne2),
+                usize_to_i64(ne3),
+                nb1,
+                nb2,
+                nb3,
+                offset,
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Returns a copy of `a` in which `b` has been added to a region of the same shape as `b`.
+    ///
+    /// As with the view functions, the region starts `offset` bytes into `a`, and `nb1`,
+    /// `nb2` and `nb3` are the strides in bytes between its rows, matrices and batches; pass
+    /// the strides of `a` itself to accumulate into a contiguous block of its rows. Both
+    /// tensors must be of type [Type::F32], and `a` must be contiguous.
+    pub fn op_acc(
+        &self,
+        a: &Tensor,
+        b: &Tensor,
+        nb1: usize,
+        nb2: usize,
+        nb3: usize,
+        offset: usize,
+    ) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_acc(
+                self.ptr.as_ptr(),
+                a.ptr.as_ptr(),
+                b.ptr.as_ptr(),
+                nb1,
+                nb2,
+                nb3,
+                offset,
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Returns a copy of `a` in which a region of the same shape as `b` has been replaced
+    /// with `b`. The region is described as for [Self::op_acc], and the same requirements apply.
+    pub fn op_set(
+        &self,
+        a: &Tensor,
+        b: &Tensor,
+        nb1: usize,
+        nb2: usize,
+        nb3: usize,
+        offset: usize,
+    ) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_set(
+                self.ptr.as_ptr(),
+                a.ptr.as_ptr(),
+                b.ptr.as_ptr(),
+                nb1,
+                nb2,
+                nb3,
+                offset,
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Returns a copy of `target` in which the elements starting at the byte `offset`
+    /// are replaced with those of `value`. This is the counterpart to [Self::op_view_1d];
+    /// `target` itself is left unchanged.
+    ///
+    /// Both tensors must be of type [Type::F32], and `target` must be contiguous.
+    /// Padding a sequence can be done by setting it into a zeroed tensor.
+    pub fn op_set_1d(&self, target: &Tensor, value: &Tensor, offset: usize) -> Tensor {
+        assert_eq!(
+            target.get_type(),
+            Type::F32,
+            "op_set_1d requires an F32 target"
+        );
+        assert_eq!(
+            value.get_type(),
+            Type::F32,
+            "op_set_1d requires an F32 value"
+        );
+        assert!(
+            offset + value.nbytes() <= target.nbytes(),
+            "op_set_1d writes past the end of the target"
+        );
+
+        let tensor = unsafe {
+            sys::ggml_set_1d(
+                self.ptr.as_ptr(),
+                target.ptr.as_ptr(),
+                value.ptr.as_ptr(),
+                offset,
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Creates a new tensor with the elements of `b` following those of `a` along dimension
+    /// `dim`, e.g. tensors of shapes `[4, 2]` and `[4, 3]` are concatenated along dimension 1
+    /// to `[4, 5]`, and tensors of shapes `[2, 3]` and `[4, 3]` along dimension 0 to `[6, 3]`.
+    ///
+    /// Both tensors must be of type [Type::F32], and all of their other dimensions must match.
+    /// There is no concatenation operation in this version of ggml, so the result is built
+    /// when the graph is computed by accumulating both tensors into a tensor of zeroes with
+    /// [Self::op_acc].
+    pub fn op_concat(&self, a: &Tensor, b: &Tensor, dim: usize) -> Tensor {
+        let (a_dims, b_dims) = (a.dims(), b.dims());
+        assert!(
+            dim < a_dims.len(),
+            "cannot concatenate along dimension {dim}"
+        );
+        assert!(
+            (0..a_dims.len()).all(|i| i == dim || a_dims[i] == b_dims[i]),
+            "cannot concatenate tensors of incompatible shapes {a_dims:?} and {b_dims:?}"
+        );
+        assert!(
+            a.get_type() == Type::F32 && b.get_type() == Type::F32,
+            "op_concat requires F32 tensors"
+        );
+
+        let mut ne = a_dims;
+        ne[dim] += b_dims[dim];
+        // Only the shape of this tensor is used, to repeat a zero into.
+        let shape = match a.n_dims().max(b.n_dims()).max(dim + 1) {
+            1 => self.new_tensor_1d(Type::F32, ne[0]),
+            2 => self.new_tensor_2d(Type::F32, ne[0], ne[1]),
+            3 => self.new_tensor_3d(Type::F32, ne[0], ne[1], ne[2]),
+            _ => self.new_tensor_4d(Type::F32, ne[0], ne[1], ne[2], ne[3]),
+        };
+        let zeroes = self.op_repeat(&self.new_f32(0.0), &shape);
+
+        let nb = zeroes.get_nb();
+        let with_a = self.op_acc(&zeroes, a, nb[1], nb[2], nb[3], 0);
+        self.op_acc(&with_a, b, nb[1], nb[2], nb[3], a_dims[dim] * nb[dim])
+    }
+
+    /// Creates a copy of `a` with the same shape and type, without needing a destination
+    /// tensor as [Context::op_cpy] does. The copy is made when the graph is computed, and is
+    /// independent of `a` afterwards.
+    pub fn op_dup(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_dup(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
     /// Copies `a` to `b` and returns `b`.
     pub fn op_cpy(&self, a: &Tensor, b: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_cpy(self.ptr.as_ptr(), a.ptr.as_ptr(), b.ptr.as_ptr()) };
@@ -321,7 +775,20 @@ impl Context {
     }
 
     /// In-place; reshapes `a` in accordance with the specified dimensions.
+    ///
+    /// Panics if `a` does not have `ne0` elements.
+    pub fn op_reshape_1d(&self, a: &Tensor, ne0: usize) -> Tensor {
+        assert_reshape(a, &[ne0]);
+        let tensor =
+            unsafe { sys::ggml_reshape_1d(self.ptr.as_ptr(), a.ptr.as_ptr(), usize_to_i64(ne0)) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// In-place; reshapes `a` in accordance with the specified dimensions.
+    ///
+    /// Panics if `a` does not have `ne0 * ne1` elements.
     pub fn op_reshape_2d(&self, a: &Tensor, ne0: usize, ne1: usize) -> Tensor {
+        assert_reshape(a, &[ne0, ne1]);
         let tensor = unsafe {
             sys::ggml_reshape_2d(
                 self.ptr.as_ptr(),
@@ -334,7 +801,10 @@ impl Context {
     }
 
     /// In-place; reshapes `a` in accordance with the specified dimensions.
+    ///
+    /// Panics if `a` does not have `ne0 * ne1 * ne2` elements.
     pub fn op_reshape_3d(&self, a: &Tensor, ne0: usize, ne1: usize, ne2: usize) -> Tensor {
+        assert_reshape(a, &[ne0, ne1, ne2]);
         let tensor = unsafe {
             sys::ggml_reshape_3d(
                 self.ptr.as_ptr(),
@@ -347,6 +817,31 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// In-place; reshapes `a` in accordance with the specified dimensions.
+    ///
+    /// Panics if `a` does not have `ne0 * ne1 * ne2 * ne3` elements.
+    pub fn op_reshape_4d(
+        &self,
+        a: &Tensor,
+        ne0: usize,
+        ne1: usize,
+        ne2: usize,
+        ne3: usize,
+    ) -> Tensor {
+        assert_reshape(a, &[ne0, ne1, ne2, ne3]);
+        let tensor = unsafe {
+            sys::ggml_reshape_4d(
+                self.ptr.as_ptr(),
+                a.ptr.as_ptr(),
+                usize_to_i64(ne0),
+                usize_to_i64(ne1),
+                usize_to_i64(ne2),
+                usize_to_i64(ne3),
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
     /// ggml_cont
     pub fn op_cont(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_cont(self.ptr.as_ptr(), a.ptr.as_ptr()) };
@@ -381,11 +876,125 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// Applies ROtary Positional Encoding with a custom frequency base (theta) and
+    /// position scale, as used by models with extended contexts.
+    ///
+    /// Dimension pair `i` of the token at position `p` is rotated by
+    /// `freq_scale * p * freq_base^(-2i / ndims)`, so `freq_base = 10000.0` and
+    /// `freq_scale = 1.0` match [Context::op_rope]. As with that operation, a `mode`
+    /// of 0 rotates adjacent pairs and a `mode` of 2 rotates the two halves of
+    /// the first `ndims` dimensions (GPT-NeoX style).
+    ///
+    /// ggml does not expose these parameters, so this is assembled from other operations,
+    /// and the sines and cosines of the rotation angles are computed within the graph.
+    /// `a` must be a contiguous F32 tensor of shape `[head_dim, n_head, n]`.
+    pub fn op_rope_custom(
+        &self,
+        a: &Tensor,
+        npast: usize,
+        ndims: usize,
+        mode: i32,
+        freq_base: f32,
+        freq_scale: f32,
+    ) -> Tensor {
+        assert!(mode & 1 == 0, "op_rope_custom does not support mode {mode}");
+        let [ne0, ne1, ne2, _] = a.dims();
+        assert!(
+            (2..=ne0).contains(&ndims),
+            "cannot rotate {ndims} of {ne0} dimensions"
+        );
+        let half = ndims / 2;
+
+        // The angle of pair `i` of token `i2` is `position(i2) * frequency(i)`.
+        let positions = self.op_arange(npast as f32, ne2);
+        let exponents = self.op_scale_f32(
+            &self.op_arange(0.0, half),
+            -2.0 * freq_base.ln() / ndims as f32,
+        );
+        let frequencies =
+            self.op_scale_f32(&unsafe { self.op_map_unary(&exponents, exp) }, freq_scale);
+        let angles = self.op_mul_mat(
+            &self.op_reshape_2d(&frequencies, 1, half),
+            &self.op_reshape_2d(&positions, 1, ne2),
+        );
+
+        // View the first and second elements of every rotated pair with the shape
+        // `[1, half, n_head, n]` (adjacent pairs) or `[half, 1, n_head, n]` (halves), and
+        // lay out the tables so that they are broadcast over the heads.
+        let element_size = std::mem::size_of::<f32>();
+        let (pair_ne, pair_nb1, second_offset) = if mode & 2 == 0 {
+            ((1, half), 2 * element_size, element_size)
+        } else {
+            ((half, 1), ne0 * element_size, half * element_size)
+        };
+        let nb = (pair_nb1, ne0 * element_size, ne0 * ne1 * element_size);
+        let pair_view = |offset| self.op_view_4d(a, (pair_ne.0, pair_ne.1, ne1, ne2), nb, offset);
+        let (x0, x1) = (pair_view(0), pair_view(second_offset));
+        let table = |fun| {
+            let table = unsafe { self.op_map_unary(&angles, fun) };
+            self.op_reshape_4d(&table, pair_ne.0, pair_ne.1, 1, ne2)
+        };
+        let (cos, sin) = (table(cos), table(sin));
+
+        let y0 = self.op_add(
+            &self.op_mul(&x0, &cos),
+            &self.op_neg(&self.op_mul(&x1, &sin)),
+        );
+        let y1 = self.op_add(&self.op_mul(&x0, &sin), &self.op_mul(&x1, &cos));
+        let with_y0 = self.op_set(a, &y0, nb.0, nb.1, nb.2, 0);
+        self.op_set(&with_y0, &y1, nb.0, nb.1, nb.2, second_offset)
+    }
+
+    /// Creates a 1D tensor of `n` values counting up from `start`. The values are computed
+    /// within the graph, by doubling the sequence with [Self::op_concat].
+    fn op_arange(&self, start: f32, n: usize) -> Tensor {
+        let mut values = self.new_f32(start);
+        let mut len = 1;
+        while len < n {
+            let next = self.op_add1(&values, &self.new_f32(len as f32));
+            values = self.op_concat(&values, &next, 0);
+            len *= 2;
+        }
+        self.op_view_1d(&values, n, 0)
+    }
+
     /// Computes the specified graph. Must be run in order to evaluate the graph.
     pub fn graph_compute(&self, graph: &mut ComputationGraph) {
         unsafe {
             sys::ggml_graph_compute(self.ptr.as_ptr(), &mut graph.inner);
         }
+        // A work buffer allocated by this computation is sized for the current thread count.
+        graph.work_n_threads = graph.work_n_threads.max(graph.inner.n_threads);
+    }
+
+    /// Computes the specified graph like [Context::graph_compute], and reports how long it
+    /// took, how many nodes it had and how much memory the [Context] uses afterwards.
+    pub fn graph_compute_with_stats(&self, graph: &mut ComputationGraph) -> ComputeStats {
+        let start = std::time::Instant::now();
+        self.graph_compute(graph);
+        ComputeStats {
+            duration: start.elapsed(),
+            n_nodes: graph.n_nodes(),
+            used_mem: self.used_mem(),
+        }
+    }
+
+    /// Computes the specified graph, unless `cancel` has been set.
+    ///
+    /// `ggml` cannot interrupt a graph once it has started computing, so `cancel` is only
+    /// checked before the computation starts. If it has been set (e.g. by another thread),
+    /// no tensors are written to and [Cancelled] is returned.
+    pub fn graph_compute_cancellable(
+        &self,
+        graph: &mut ComputationGraph,
+        cancel: &AtomicBool,
+    ) -> Result<(), Cancelled> {
+        if cancel.load(Ordering::SeqCst) {
+            return Err(Cancelled);
+        }
+
+        self.graph_compute(graph);
+        Ok(())
     }
 
     /// Retrieves the memory used by this [Context].
@@ -393,8 +1002,33 @@ impl Context {
         unsafe { sys::ggml_used_mem(self.ptr.as_ptr()) }
     }
 
+    /// Retrieves the memory that this [Context] would need to hold everything
+    /// created in it so far, including tensor data that was not allocated.
+    ///
+    /// For a context created with [Context::new_measure], this is the size
+    /// to pass to [Context::init] to build the same graph for real. Otherwise,
+    /// this is the same as [Context::used_mem].
+    pub fn required_mem(&self) -> usize {
+        self.used_mem() + self.unallocated_data.load(Ordering::Relaxed)
+    }
+
+    /// Retrieves the size of the memory pool this [Context] was created with.
+    ///
+    /// [Context::used_mem] will never exceed this value; `ggml` aborts if an
+    /// allocation would not fit.
+    pub fn mem_size(&self) -> usize {
+        self.mem_size
+    }
+
     /// Sets the scratch buffer to be used by this [Context].
     ///
+    /// While a scratch buffer is in use, the data of new tensors is placed in the
+    /// buffer rather than in the context's own memory, starting from the beginning
+    /// of the buffer each time this is called. Those tensors point into the buffer,
+    /// so it must outlive every use of them, including any computation of a graph
+    /// that contains them; the borrow only lasts for this call and does not enforce this.
+    /// Prefer [Context::with_scratch], which makes the scope of the buffer explicit.
+    ///
     /// If `scratch_buffer` is `None`, the scratch buffer will be disabled.
     pub fn use_scratch<'a>(&'a self, scratch_buffer: Option<&'a mut Buffer>) {
         let (size, data) = if let Some(buffer) = scratch_buffer {
@@ -415,7 +1049,45 @@ impl Context {
         }
     }
 
+    /// Uses `buffer` as the scratch buffer while `f` runs, and disables the scratch
+    /// buffer afterwards, even if `f` panics. See [Context::use_scratch].
+    ///
+    /// The data of tensors created by `f` lives in `buffer`, so `buffer` must not be
+    /// dropped or reused before those tensors have been computed and read.
+    pub fn with_scratch<T>(&self, buffer: &mut Buffer, f: impl FnOnce() -> T) -> T {
+        struct ScratchGuard<'a>(&'a Context);
+        impl Drop for ScratchGuard<'_> {
+            fn drop(&mut self) {
+                self.0.use_scratch(None);
+            }
+        }
+
+        self.use_scratch(Some(buffer));
+        let _guard = ScratchGuard(self);
+        f()
+    }
+
+    /// Clamps every element of `a` to the range `[min, max]`.
+    ///
+    /// The bundled ggml's clamp truncates its bounds to integers, modifies `a` in place and
+    /// fails an assertion in debug builds, so this is computed as
+    /// `max - relu(max - (min + relu(a - min)))` instead. Infinite elements are clamped too.
+    pub fn op_clamp(&self, a: &Tensor, min: f32, max: f32) -> Tensor {
+        let at_least_min = self.op_add1(
+            &self.op_relu(&self.op_add1(a, &self.new_f32(-min))),
+            &self.new_f32(min),
+        );
+        let excess = self.op_relu(&self.op_add1(&self.op_neg(&at_least_min), &self.new_f32(max)));
+        self.op_add1(&self.op_neg(&excess), &self.new_f32(max))
+    }
+
     /// Attention with LInear BIases (Ref: <https://arxiv.org/pdf/2108.12409.pdf>)
+    ///
+    /// Adds a bias proportional to the key position to every row of `a`, which is
+    /// expected to have one `[n_past + n, n]` matrix per head. For a power of two
+    /// `n_head`, head `h` (counting from 1) uses the slope `2^(-bias_max * h / n_head)`;
+    /// otherwise, the slopes for the nearest lower power of two are used, followed by
+    /// the odd-numbered slopes of the next power of two.
     pub fn op_alibi(&self, a: &Tensor, n_past: usize, n_head: usize, bias_max: f32) -> Tensor {
         let tensor = unsafe {
             sys::ggml_alibi(
@@ -435,6 +1107,185 @@ impl Context {
         let tensor = unsafe { sys::ggml_gelu(self.ptr.as_ptr(), a.ptr.as_ptr()) };
         self.new_tensor_raw(tensor)
     }
+
+    /// The "quick" approximation of [GELU](Self::op_gelu), `x * sigmoid(1.702 * x)`, as used
+    /// by some models instead of the exact GELU.
+    ///
+    /// `ggml` has no native quick GELU operation, so this is computed with a mapped function.
+    /// `a` must be a contiguous `f32` tensor.
+    pub fn op_gelu_quick(&self, a: &Tensor) -> Tensor {
+        unsafe extern "C" fn gelu_quick(n: c_int, dst: *mut f32, src: *const f32) {
+            for i in 0..n as usize {
+                let x = *src.add(i);
+                *dst.add(i) = x / (1.0 + (-1.702 * x).exp());
+            }
+        }
+
+        // SAFETY: `gelu_quick` only touches the `n` elements it is given.
+        unsafe { self.op_map_unary(a, gelu_quick) }
+    }
+
+    /// Rectified Linear Unit: creates a new tensor with the negative elements of `a` set to 0.
+    pub fn op_relu(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_relu(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Creates a new tensor with the hyperbolic tangent of each element of `a`.
+    ///
+    /// `ggml` has no native tanh operation, so this is computed with a mapped function.
+    /// `a` must be a contiguous `f32` tensor.
+    pub fn op_tanh(&self, a: &Tensor) -> Tensor {
+        unsafe extern "C" fn tanh(n: c_int, dst: *mut f32, src: *const f32) {
+            for i in 0..n as usize {
+                *dst.add(i) = (*src.add(i)).tanh();
+            }
+        }
+
+        // SAFETY: `tanh` only touches the `n` elements it is given.
+        unsafe { self.op_map_unary(a, tanh) }
+    }
+
+    /// Creates a new tensor with the absolute value of each element of `a`.
+    pub fn op_abs(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_abs(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Creates a new tensor with the natural logarithm of each element of `a`.
+    pub fn op_log(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_log(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Creates a new tensor with each element of `a` negated.
+    pub fn op_neg(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_neg(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Creates a new tensor with the unit step function applied to each element of `a`:
+    /// 1 if the element is positive, and 0 otherwise.
+    pub fn op_step(&self, a: &Tensor) -> Tensor {
+        let tensor = unsafe { sys::ggml_step(self.ptr.as_ptr(), a.ptr.as_ptr()) };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Applies a 1D convolution of `kernel` over `input` with a stride of 1.
+    ///
+    /// `kernel` has the shape `[kernel_size, in_channels, out_channels]`, and `kernel_size` must be odd.
+    /// `input` has the shape `[n, in_channels]`. The input is padded by half of the kernel size on
+    /// each side, so the result has the shape `[n, out_channels]`.
+    pub fn op_conv_1d_1s(&self, kernel: &Tensor, input: &Tensor) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_conv_1d_1s(self.ptr.as_ptr(), kernel.ptr.as_ptr(), input.ptr.as_ptr())
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Applies a 1D convolution of `kernel` over `input` with a stride of 2.
+    ///
+    /// The shapes are the same as for [Context::op_conv_1d_1s], except that only every second
+    /// position is computed, so the result has the shape `[n / 2, out_channels]`.
+    pub fn op_conv_1d_2s(&self, kernel: &Tensor, input: &Tensor) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_conv_1d_2s(self.ptr.as_ptr(), kernel.ptr.as_ptr(), input.ptr.as_ptr())
+        };
+        self.new_tensor_raw(tensor)
+    }
+
+    /// Splits an image into non-overlapping patches and embeds each of them with
+    /// `kernel`, as the patch embedding of a vision transformer does.
+    ///
+    /// This is a 2D convolution whose stride is the size of the kernel and which
+    /// has no padding. ggml does not provide a general 2D convolution, so other
+    /// strides and padding are not supported.
+    ///
+    /// The shapes follow PyTorch's NCHW layout with the dimensions reversed, as
+    /// is usual in ggml: `input` is a contiguous `[width, height, in_channels]` image,
+    /// and `kernel` has the shape `[patch_width, patch_height, in_channels, out_channels]`.
+    /// The result has the shape `[out_channels, n_patches]`, where the patches are
+    /// ordered row by row.
+    pub fn op_patch_embed(&self, kernel: &Tensor, input: &Tensor) -> Tensor {
+        let [kw, kh, channels, out_channels] = kernel.dims();
+        let [width, height, in_channels, _] = input.dims();
+        assert_eq!(channels, in_channels, "kernel and input channels differ");
+        assert!(
+            width % kw == 0 && height % kh == 0,
+            "a {width}x{height} image cannot be split into {kw}x{kh} patches"
+        );
+        let (ow, oh) = (width / kw, height / kh);
+        let input_row_size = width * input.element_size();
+        let kernel_nb = kernel.get_nb();
+
+        // Each row of a patch is handled separately, as splitting both image
+        // dimensions at once would need more than four dimensions.
+        let mut output: Option<Tensor> = None;
+        for row in 0..kh {
+            // [kw * ow, oh, in_channels] -> [kw * in_channels, ow * oh]
+            let rows = self.op_view_3d(
+                input,
+                (width, oh, in_channels),
+                (input_row_size * kh, input_row_size * height),
+                input_row_size * row,
+            );
+            let rows = self.op_reshape_4d(&self.op_cont(&rows), kw, ow, oh, in_channels);
+            let patches = self.op_reshape_2d(
+                &self.op_cont(&self.op_permute(&rows, 0, 2, 3, 1)),
+                kw * in_channels,
+                ow * oh,
+            );
+
+            // [kw, in_channels, out_channels] -> [kw * in_channels, out_channels]
+            let weights = self.op_view_3d(
+                kernel,
+                (kw, in_channels, out_channels),
+                (kernel_nb[2], kernel_nb[3]),
+                kernel_nb[1] * row,
+            );
+            let weights =
+                self.op_reshape_2d(&self.op_cont(&weights), kw * in_channels, out_channels);
+
+            let embedded = self.op_mul_mat(&weights, &patches);
+            output = Some(match output {
+                Some(output) => self.op_add(&output, &embedded),
+                None => embedded,
+            });
+        }
+        output.expect("kernel has no rows")
+    }
+}
+
+unsafe extern "C" fn exp(n: c_int, dst: *mut f32, src: *const f32) {
+    for i in 0..n as usize {
+        *dst.add(i) = (*src.add(i)).exp();
+    }
+}
+
+unsafe extern "C" fn cos(n: c_int, dst: *mut f32, src: *const f32) {
+    for i in 0..n as usize {
+        *dst.add(i) = (*src.add(i)).cos();
+    }
+}
+
+unsafe extern "C" fn sin(n: c_int, dst: *mut f32, src: *const f32) {
+    for i in 0..n as usize {
+        *dst.add(i) = (*src.add(i)).sin();
+    }
+}
+
+/// Panics with a descriptive message if `a` can not be reshaped to the dimensions `ne`,
+/// instead of leaving it to an assertion inside `ggml`.
+fn assert_reshape(a: &Tensor, ne: &[usize]) {
+    let n_elements: usize = ne.iter().product();
+    assert_eq!(
+        a.nelements(),
+        n_elements,
+        "cannot reshape a tensor of {} elements to {:?} ({} elements)",
+        a.nelements(),
+        ne,
+        n_elements
+    );
 }
 
 impl Drop for Context {
@@ -10,7 +10,9 @@ use std::{
 };
 
 use crate::{
-    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32},
+    util::{
+        has_data_left, read_bytes_with_len, read_bytes_with_u32_len, read_f32, read_i32, read_u32,
+    },
     ContainerType, ElementType,
 };
 
@@ -105,7 +107,7 @@ pub(crate) fn data_size(element_type: ElementType, n_elements: usize) -> usize {
 
 /// Returns the size of the ggml tensor header in bytes.
 pub(crate) fn header_size() -> usize {
-    crate::Tensor::C_TYPE_SIZE + crate::OBJECT_SIZE
+    crate::tensor_overhead()
 }
 
 /// Returns the size of a tensor in bytes given the element type and number of elements. This includes the tensor's header.
@@ -164,8 +166,7 @@ pub fn load<E: Error, R: BufRead + Seek>(
 
     // Load vocabulary
     for i in 0..n_vocab {
-        let len = read_u32(reader)?.try_into()?;
-        let token = read_bytes_with_len(reader, len)?;
+        let token = read_bytes_with_u32_len(reader)?;
         let token_score = match container_type {
             ContainerType::Ggmf(_version) | ContainerType::Ggjt(_version) => read_f32(reader)?,
             ContainerType::Ggml | ContainerType::Ggla(_) => {
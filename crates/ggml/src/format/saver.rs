@@ -108,8 +108,7 @@ pub fn save<E: Error, W: Write + Seek>(
 
     // Write vocabulary
     for (token, score) in vocabulary {
-        util::write_u32(writer, token.len().try_into()?)?;
-        writer.write_all(token)?;
+        util::write_bytes_with_u32_len(writer, token)?;
 
         if container_type != SaveContainerType::Ggml {
             util::write_f32(writer, *score)?;
@@ -197,6 +197,30 @@ impl std::fmt::Display for Type {
         }
     }
 }
+impl std::str::FromStr for Type {
+    type Err = TypeError;
+
+    /// Parses the name of a type, as printed by its [Display](std::fmt::Display)
+    /// implementation (e.g. `q4_0` or `f32`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "q4_0" => Type::Q4_0,
+            "q4_1" => Type::Q4_1,
+            "q5_0" => Type::Q5_0,
+            "q5_1" => Type::Q5_1,
+            "q8_0" => Type::Q8_0,
+            "q8_1" => Type::Q8_1,
+            "i32" => Type::I32,
+            "f16" => Type::F16,
+            "f32" => Type::F32,
+            // Legacy
+            "q4_2" => Type::LegacyQ4_2,
+
+            "f64" => return Err(TypeError::F64Unsupported),
+            _ => return Err(TypeError::Unknown(s.to_owned())),
+        })
+    }
+}
 impl Type {
     /// Returns whether this type is quantized.
     pub fn is_quantized(&self) -> bool {
@@ -213,6 +237,40 @@ impl Type {
             Type::LegacyQ4_2 => true,
         }
     }
+
+    /// Returns the size and block size of this type, and whether it is quantized.
+    ///
+    /// This does not need a [Context], so it can be used to lay out buffers before one is created.
+    pub fn info(&self) -> TypeInfo {
+        TypeInfo {
+            size: type_size(*self),
+            block_size: blck_size(*self),
+            quantized: self.is_quantized(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The layout of a [Type], as returned by [Type::info].
+pub struct TypeInfo {
+    /// The size in bytes of a block of values (see [type_size]).
+    pub size: usize,
+    /// The number of values in a block (see [blck_size]). This is 1 for unquantized types.
+    pub block_size: usize,
+    /// Whether the type is quantized (see [Type::is_quantized]).
+    pub quantized: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
+/// Errors encountered while parsing a [Type].
+pub enum TypeError {
+    #[error("ggml does not support 64-bit floats; convert the data to f32 first")]
+    /// The type was `f64`. `ggml` has no double-precision type, so double-precision
+    /// data must be converted to [Type::F32] before it is stored in a [Tensor].
+    F64Unsupported,
+    #[error("unknown ggml type {0:?}")]
+    /// The type is not known to `ggml`.
+    Unknown(String),
 }
 
 /// A buffer of memory that can be used as a scratch buffer for a [Context].
@@ -240,9 +298,48 @@ impl Buffer {
     }
 }
 
+/// Returned by [Context::graph_compute_cancellable] when a computation was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("the computation was cancelled")]
+pub struct Cancelled;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Statistics about a computation by [Context::graph_compute_with_stats].
+pub struct ComputeStats {
+    /// The wall-clock time that the computation took.
+    pub duration: std::time::Duration,
+    /// The number of nodes (operations) of the graph that were computed.
+    pub n_nodes: usize,
+    /// The memory used by the [Context] after the computation, including the graph's
+    /// work buffer. As a [Context] never frees memory, this is its peak usage so far.
+    pub used_mem: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The precision with which [Context::op_mul_mat_precision] multiplies matrices.
+pub enum MatMulPrecision {
+    /// The matrices are multiplied in the precision of their types.
+    #[default]
+    F32,
+    /// An `F32` first matrix is rounded to `F16` before the multiplication, which makes `ggml`
+    /// convert the second matrix to `F16` as well.
+    ///
+    /// This halves the memory traffic of the operands, which can speed up bandwidth-bound
+    /// multiplications, but `F16` only keeps about three significant decimal digits, so the
+    /// results are less accurate. Matrices that are already `F16` or quantized are unaffected.
+    ///
+    /// The rounding is not free: every multiplication allocates an `F16` copy of the first
+    /// matrix in the [Context] and converts it each time the graph is computed. For weights,
+    /// which are multiplied in every graph, load them as `F16` instead, so that they are
+    /// converted only once.
+    F16,
+}
+
 /// A `ggml` computation graph. Keeps track of all state during computation.
 pub struct ComputationGraph {
     inner: sys::ggml_cgraph,
+    /// The number of threads that the graph's work buffer was allocated for.
+    work_n_threads: i32,
 }
 
 impl ComputationGraph {
@@ -255,13 +352,170 @@ impl ComputationGraph {
                 // just leaves it uninitialized
                 ..unsafe { std::mem::zeroed::<sys::ggml_cgraph>() }
             },
+            work_n_threads: 0,
+        }
+    }
+
+    /// The number of threads that this graph is computed with.
+    pub fn n_threads(&self) -> usize {
+        i32_to_usize(self.inner.n_threads)
+    }
+
+    /// Sets the number of threads that this graph is computed with, overriding the value it
+    /// was created with. The results of the computation do not depend on the number of threads.
+    ///
+    /// `ggml` sizes the graph's work buffer for the number of threads of its first computation.
+    /// If more threads are requested afterwards, a new work buffer is allocated from the
+    /// [Context] on the next computation.
+    pub fn set_n_threads(&mut self, n_threads: usize) {
+        let n_threads = usize_to_i32(n_threads);
+        if !self.inner.work.is_null() && n_threads > self.work_n_threads {
+            self.inner.work = std::ptr::null_mut();
+            self.inner.work_size = 0;
+            self.work_n_threads = 0;
         }
+        self.inner.n_threads = n_threads;
+    }
+
+    /// The number of nodes (operations) in this graph, excluding its leafs (inputs and weights).
+    pub fn n_nodes(&self) -> usize {
+        i32_to_usize(self.inner.n_nodes)
     }
 
     /// Build this computational graph in the forward direction in preparation for computation.
     pub fn build_forward_expand(&mut self, tensor: &Tensor) {
         unsafe { sys::ggml_build_forward_expand(&mut self.inner, tensor.ptr.as_ptr()) }
     }
+
+    /// Exports the structure of this graph in the [Graphviz](https://graphviz.org/) DOT
+    /// format, similarly to `ggml_graph_dump_dot`.
+    ///
+    /// Every operation is drawn as a box labelled with its name, operation and
+    /// dimensions, and every leaf (input or weight) as an ellipse labelled with its name,
+    /// type and dimensions. Edges point from the operands to the operation using them.
+    ///
+    /// The tensors of the graph must still be alive.
+    pub fn export_dot(&self) -> String {
+        use std::{collections::HashMap, fmt::Write};
+
+        fn dims(tensor: &sys::ggml_tensor) -> String {
+            let ne = &tensor.ne[..i32_to_usize(tensor.n_dims).max(1)];
+            ne.iter()
+                .map(|n| n.to_string())
+                .collect::<Vec<_>>()
+                .join(" x ")
+        }
+        fn escape(label: &str) -> String {
+            label.replace('\\', "\\\\").replace('"', "\\\"")
+        }
+
+        let n_nodes = i32_to_usize(self.inner.n_nodes);
+        let n_leafs = i32_to_usize(self.inner.n_leafs);
+        let nodes = &self.inner.nodes[..n_nodes];
+        let leafs = &self.inner.leafs[..n_leafs];
+        let ids = nodes
+            .iter()
+            .map(|&node| (node, "node"))
+            .chain(leafs.iter().map(|&leaf| (leaf, "leaf")))
+            .enumerate()
+            .map(|(i, (tensor, kind))| (tensor, format!("{kind}_{i}")))
+            .collect::<HashMap<_, _>>();
+
+        let mut dot = String::from("digraph G {\n  newrank = true;\n  rankdir = LR;\n");
+        for &leaf in leafs {
+            // SAFETY: the leafs of a built graph point to live tensors.
+            let tensor = unsafe { &*leaf };
+            let _ = writeln!(
+                dot,
+                "  {} [shape = ellipse; label = \"{} | {} | {}\"];",
+                ids[&leaf],
+                escape(&crate::tensor::tensor_name(tensor)),
+                Type::try_from(tensor.type_).map_or("?".to_string(), |t| t.to_string()),
+                dims(tensor),
+            );
+        }
+        for &node in nodes {
+            // SAFETY: the nodes of a built graph point to live tensors.
+            let tensor = unsafe { &*node };
+            let id = &ids[&node];
+            let _ = writeln!(
+                dot,
+                "  {id} [shape = box; label = \"{} | {} | {}\"];",
+                escape(&crate::tensor::tensor_name(tensor)),
+                op_name(tensor.op),
+                dims(tensor),
+            );
+            let sources = [tensor.src0, tensor.src1]
+                .into_iter()
+                .chain(tensor.opt)
+                .filter(|source| !source.is_null());
+            for source in sources {
+                if let Some(source_id) = ids.get(&source) {
+                    let _ = writeln!(dot, "  {source_id} -> {id};");
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// The name of a ggml operation, as used by `ggml_graph_dump_dot`.
+fn op_name(op: sys::ggml_op) -> &'static str {
+    const NAMES: [&str; sys::ggml_op_GGML_OP_COUNT as usize] = [
+        "NONE",
+        "DUP",
+        "ADD",
+        "ADD1",
+        "ACC",
+        "SUB",
+        "MUL",
+        "DIV",
+        "SQR",
+        "SQRT",
+        "LOG",
+        "SUM",
+        "SUM_ROWS",
+        "MEAN",
+        "REPEAT",
+        "ABS",
+        "SGN",
+        "NEG",
+        "STEP",
+        "RELU",
+        "GELU",
+        "SILU",
+        "SILU_BACK",
+        "NORM",
+        "RMS_NORM",
+        "RMS_NORM_BACK",
+        "MUL_MAT",
+        "SCALE",
+        "SET",
+        "CPY",
+        "CONT",
+        "RESHAPE",
+        "VIEW",
+        "PERMUTE",
+        "TRANSPOSE",
+        "GET_ROWS",
+        "GET_ROWS_BACK",
+        "DIAG",
+        "DIAG_MASK_INF",
+        "DIAG_MASK_ZERO",
+        "SOFT_MAX",
+        "ROPE",
+        "ROPE_BACK",
+        "ALIBI",
+        "CLAMP",
+        "CONV_1D_1S",
+        "CONV_1D_2S",
+        "FLASH_ATTN",
+        "FLASH_FF",
+        "MAP_UNARY",
+        "MAP_BINARY",
+    ];
+    NAMES.get(op as usize).copied().unwrap_or("UNKNOWN")
 }
 
 /// The size of `t` as bytes.
@@ -279,6 +533,60 @@ pub fn blck_size(t: Type) -> usize {
     i32_to_usize(unsafe { sys::ggml_blck_size(t.into()) })
 }
 
+/// The number of bytes a [Context] uses for each tensor in addition to the tensor's data.
+///
+/// Add this to [Tensor::nbytes] for every tensor to size a [Context] precisely.
+pub fn tensor_overhead() -> usize {
+    Tensor::C_TYPE_SIZE + OBJECT_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// The SIMD and BLAS code paths that ggml was compiled with, as reported by [cpu_features].
+///
+/// ggml selects its vectorized kernels (including the dequantization of `Q4_0` and `Q4_1`
+/// during matrix multiplication) at compile time, so a feature that is `false` here is
+/// not used even if the CPU supports it. This is the usual cause of unexpectedly slow
+/// inference, e.g. when cross-compiling without specifying target features.
+pub struct CpuFeatures {
+    /// AVX is used.
+    pub avx: bool,
+    /// AVX2 is used.
+    pub avx2: bool,
+    /// AVX-512 is used.
+    pub avx512: bool,
+    /// Fused multiply-add on x86 is used.
+    pub fma: bool,
+    /// Conversions between half and single precision on x86 (F16C) are used.
+    pub f16c: bool,
+    /// SSE3 is used.
+    pub sse3: bool,
+    /// NEON is used.
+    pub neon: bool,
+    /// Fused multiply-add on ARM is used.
+    pub arm_fma: bool,
+    /// WebAssembly SIMD is used.
+    pub wasm_simd: bool,
+    /// A BLAS library is used for large matrix multiplications.
+    pub blas: bool,
+}
+
+/// Reports which SIMD and BLAS code paths are active in this build of ggml.
+pub fn cpu_features() -> CpuFeatures {
+    let has = |f: unsafe extern "C" fn() -> c_int| unsafe { f() } != 0;
+    CpuFeatures {
+        avx: has(sys::ggml_cpu_has_avx),
+        avx2: has(sys::ggml_cpu_has_avx2),
+        avx512: has(sys::ggml_cpu_has_avx512),
+        fma: has(sys::ggml_cpu_has_fma),
+        f16c: has(sys::ggml_cpu_has_f16c),
+        sse3: has(sys::ggml_cpu_has_sse3),
+        neon: has(sys::ggml_cpu_has_neon),
+        arm_fma: has(sys::ggml_cpu_has_arm_fma),
+        wasm_simd: has(sys::ggml_cpu_has_wasm_simd),
+        blas: has(sys::ggml_cpu_has_blas),
+    }
+}
+
 fn usize_to_i32(val: usize) -> i32 {
     i32::try_from(val).unwrap()
 }
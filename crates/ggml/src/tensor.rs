@@ -85,6 +85,19 @@ impl Tensor {
         self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.ne)
     }
 
+    /// Number of elements in each dimension, as sizes. Unused dimensions are 1.
+    pub fn dims(&self) -> [usize; 4] {
+        self.get_ne().map(i64_to_usize)
+    }
+
+    /// Number of dimensions of this tensor.
+    pub fn n_dims(&self) -> usize {
+        self.with_alive_ctx(|| {
+            let n_dims = unsafe { *self.ptr.as_ptr() }.n_dims;
+            usize::try_from(n_dims).unwrap()
+        })
+    }
+
     /// Stride of each dimension.
     pub fn get_nb(&self) -> [usize; 4] {
         self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.nb)
@@ -95,6 +108,22 @@ impl Tensor {
         self.with_alive_ctx(|| unsafe { *self.ptr.as_ptr() }.type_.try_into().unwrap())
     }
 
+    /// Sets the name of this tensor, which is shown when debugging graphs. Names
+    /// longer than 31 bytes are truncated.
+    ///
+    /// # Panics
+    ///
+    /// If `name` contains a nul byte.
+    pub fn set_name(&mut self, name: &str) {
+        let name = std::ffi::CString::new(name).expect("tensor names cannot contain nul bytes");
+        self.with_alive_ctx(|| unsafe { sys::ggml_set_name(self.ptr.as_ptr(), name.as_ptr()) })
+    }
+
+    /// The name of this tensor, or an empty string if it has not been named.
+    pub fn name(&self) -> String {
+        self.with_alive_ctx(|| tensor_name(unsafe { self.ptr.as_ref() }))
+    }
+
     /// The size of the element type in bytes.
     pub fn element_size(&self) -> usize {
         self.with_alive_ctx(|| unsafe { sys::ggml_element_size(self.ptr.as_ptr()) })
@@ -109,11 +138,124 @@ impl Tensor {
         std::ptr::copy_nonoverlapping(src.as_ptr(), self.data() as *mut u8, src.len())
     }
 
+    /// Reads the `i`th element of this one-dimensional F32 tensor.
+    ///
+    /// The tensor's data must have been allocated.
+    ///
+    /// # Panics
+    ///
+    /// - If the tensor is not of type [Type::F32].
+    /// - If `i` is out of range for the first dimension.
+    pub fn get_f32_1d(&self, i: usize) -> f32 {
+        self.get_f32([i, 0])
+    }
+
+    /// Reads the element in column `i` and row `j` of this two-dimensional F32 tensor.
+    ///
+    /// The offset is computed from the tensor's strides, so this also works on
+    /// non-contiguous views. The tensor's data must have been allocated.
+    ///
+    /// # Panics
+    ///
+    /// - If the tensor is not of type [Type::F32].
+    /// - If `i` or `j` are out of range for the first or second dimension.
+    pub fn get_f32_2d(&self, i: usize, j: usize) -> f32 {
+        self.get_f32([i, j])
+    }
+
+    fn get_f32(&self, index: [usize; 2]) -> f32 {
+        assert_eq!(
+            self.get_type(),
+            Type::F32,
+            "get_f32 can only be used with F32 tensors"
+        );
+        let ne = self.get_ne();
+        let nb = self.get_nb();
+        for (dim, (&i, &n)) in index.iter().zip(&ne).enumerate() {
+            assert!(
+                i < i64_to_usize(n),
+                "index {i} is out of range for dimension {dim} of size {n}"
+            );
+        }
+
+        let offset = index[0] * nb[0] + index[1] * nb[1];
+        self.with_alive_ctx(|| {
+            // SAFETY: The with_alive_call guarantees the context is alive, and the
+            // bounds checks above guarantee that the offset is within the tensor.
+            unsafe {
+                let data = (*self.ptr.as_ptr()).data as *const u8;
+                std::ptr::read_unaligned(data.add(offset) as *const f32)
+            }
+        })
+    }
+
+    /// Borrows the data of this contiguous F32 tensor as a slice, without copying it.
+    ///
+    /// The data is only meaningful once the tensor has been computed with
+    /// [Context::graph_compute](crate::Context::graph_compute).
+    ///
+    /// # Safety
+    ///
+    /// The context this tensor belongs to must outlive the slice, and the tensor's data
+    /// must not be written to (e.g. by computing a graph) while the slice is alive.
+    ///
+    /// # Panics
+    ///
+    /// - If the tensor is not of type [Type::F32].
+    /// - If the tensor is not contiguous, e.g. because it is a transposed view.
+    pub unsafe fn as_slice_f32(&self) -> &[f32] {
+        assert_eq!(
+            self.get_type(),
+            Type::F32,
+            "as_slice_f32 can only be used with F32 tensors"
+        );
+        let ne = self.dims();
+        let nb = self.get_nb();
+        let mut expected_stride = std::mem::size_of::<f32>();
+        for (dim, (&n, &stride)) in ne.iter().zip(&nb).enumerate() {
+            assert!(
+                n == 1 || stride == expected_stride,
+                "as_slice_f32 can only be used with contiguous tensors, but dimension {dim} has stride {stride}"
+            );
+            expected_stride *= n;
+        }
+
+        let n_elements = self.nelements();
+        self.with_alive_ctx(|| {
+            let data = sys::ggml_get_data_f32(self.ptr.as_ptr());
+            std::slice::from_raw_parts(data, n_elements)
+        })
+    }
+
     /// Zeroes out this tensor.
     pub fn zero_data(&mut self) {
         unsafe { std::ptr::write_bytes(self.data() as *mut u8, 0, self.nbytes()) }
     }
 
+    /// Sets every element of this tensor to `value`.
+    ///
+    /// The tensor must be contiguous and its data must have been allocated.
+    ///
+    /// # Panics
+    ///
+    /// - If the tensor is not of type [Type::F32].
+    pub fn fill_f32(&self, value: f32) {
+        assert_eq!(
+            self.get_type(),
+            Type::F32,
+            "fill_f32 can only be used with F32 tensors"
+        );
+        let n_elements = self.nelements();
+        self.with_alive_ctx(|| {
+            // SAFETY: The with_alive_call guarantees the context is alive, and the
+            // tensor holds `n_elements` contiguous f32 values.
+            unsafe {
+                let data = (*self.ptr.as_ptr()).data as *mut f32;
+                std::slice::from_raw_parts_mut(data, n_elements).fill(value)
+            }
+        })
+    }
+
     /// Reads this tensor into `dst`, starting from `offset`. The size of `dst`
     /// will be used to determine how many bytes to read.
     ///
@@ -125,3 +267,14 @@ impl Tensor {
         std::ptr::copy_nonoverlapping(data, dst as *mut _ as _, dst.len())
     }
 }
+
+/// Reads the nul-terminated name of `tensor`.
+pub(crate) fn tensor_name(tensor: &sys::ggml_tensor) -> String {
+    let bytes = tensor
+        .name
+        .iter()
+        .map(|&c| c as u8)
+        .take_while(|&c| c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -121,6 +121,1076 @@ fn roundtrip_test(
     Ok(())
 }
 
+#[test]
+fn can_convolve_1d() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    let mut kernel = ctx.new_tensor_3d(Type::F32, 3, 1, 1);
+    write_f32s(&mut kernel, &[1.0, 2.0, 3.0]);
+    let mut input = ctx.new_tensor_2d(Type::F32, 6, 1);
+    write_f32s(&mut input, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+    let stride_1 = ctx.op_conv_1d_1s(&kernel, &input);
+    let stride_2 = ctx.op_conv_1d_2s(&kernel, &input);
+    compute(&ctx, &[&stride_1, &stride_2]);
+
+    let stride_1 = read_f32s(&stride_1);
+    assert_eq!(stride_1.len(), 6);
+    assert_eq!(stride_1[0], 8.0);
+    assert_eq!(stride_1[2], 20.0);
+
+    let stride_2 = read_f32s(&stride_2);
+    assert_eq!(stride_2.len(), 3);
+    assert_eq!(stride_2[1], 20.0);
+}
+
+#[test]
+fn can_embed_image_patches() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    // A 4x2 image with two channels, split into two 2x2 patches.
+    let mut input = ctx.new_tensor_3d(Type::F32, 4, 2, 2);
+    write_f32s(&mut input, &(0..16).map(|i| i as f32).collect::<Vec<_>>());
+    let mut kernel = ctx.new_tensor_3d(Type::F32, 2, 2, 2);
+    write_f32s(&mut kernel, &[1.0, 2.0, 3.0, 4.0, 1.0, 1.0, 1.0, 1.0]);
+
+    let embedded = ctx.op_patch_embed(&kernel, &input);
+    compute(&ctx, &[&embedded]);
+
+    assert_eq!(embedded.get_ne(), [1, 2, 1, 1]);
+    // (0 * 1 + 1 * 2 + 4 * 3 + 5 * 4) + (8 + 9 + 12 + 13)
+    // (2 * 1 + 3 * 2 + 6 * 3 + 7 * 4) + (10 + 11 + 14 + 15)
+    assert_eq!(read_f32s(&embedded), vec![76.0, 104.0]);
+}
+
+#[test]
+fn can_zero_tensor_data() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    let mut tensor = ctx.new_tensor_2d(Type::F32, 4, 3);
+    tensor.fill_f32(1.0);
+    tensor.zero_data();
+
+    assert_eq!(read_f32s(&tensor), vec![0.0; 12]);
+}
+
+#[test]
+fn can_read_tensor_elements() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    let mut tensor = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut tensor, &[0.0, 1.0, 2.0, 10.0, 11.0, 12.0]);
+
+    assert_eq!(tensor.get_f32_1d(2), 2.0);
+    assert_eq!(tensor.get_f32_2d(0, 1), 10.0);
+    assert_eq!(tensor.get_f32_2d(2, 1), 12.0);
+
+    // Transposing swaps the strides, so the same element is found at swapped indices.
+    let transposed = ctx.op_transpose(&tensor);
+    assert_eq!(transposed.get_f32_2d(1, 2), 12.0);
+    assert_eq!(transposed.get_f32_2d(1, 0), 10.0);
+}
+
+#[test]
+#[should_panic(expected = "out of range")]
+fn reading_tensor_element_out_of_range_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let tensor = ctx.new_tensor_2d(Type::F32, 3, 2);
+    tensor.get_f32_2d(3, 0);
+}
+
+#[test]
+fn can_get_rows_from_quantized_table() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    // Three rows of a single quantization block each, with very different scales.
+    let n_embd = 32;
+    let rows = (0..3)
+        .map(|r| {
+            (0..n_embd)
+                .map(|i| 10f32.powi(r) * (i as f32 - 16.0) / 16.0)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    let values = rows.concat();
+
+    let mut table = ctx.new_tensor_2d(Type::Q4_0, n_embd, rows.len());
+    let quantized = crate::quantize_q4_0(&values, values.len(), n_embd);
+    assert_eq!(quantized.output.len(), table.nbytes());
+    unsafe { table.write_data(&quantized.output) };
+
+    let mut indices = ctx.new_tensor_1d(Type::I32, 2);
+    let index_bytes = [2i32, 0]
+        .iter()
+        .flat_map(|i| i.to_ne_bytes())
+        .collect::<Vec<_>>();
+    unsafe { indices.write_data(&index_bytes) };
+
+    let gathered = ctx.op_get_rows(&table, &indices);
+    compute(&ctx, &[&gathered]);
+    assert_eq!(gathered.get_type(), Type::F32);
+
+    let gathered = read_f32s(&gathered);
+    for (row, expected) in gathered.chunks(n_embd).zip([&rows[2], &rows[0]]) {
+        // 4-bit quantization is accurate to within a step of the block's scale.
+        let step = expected.iter().fold(0f32, |m, v| m.max(v.abs())) / 7.0;
+        for (actual, expected) in row.iter().zip(expected.iter()) {
+            assert!(
+                (actual - expected).abs() <= step,
+                "expected {expected}, got {actual}"
+            );
+        }
+    }
+}
+
+#[test]
+#[should_panic(expected = "cannot gather rows")]
+fn getting_rows_from_unsupported_type_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let table = ctx.new_tensor_2d(Type::Q8_1, 32, 2);
+    let indices = ctx.new_tensor_1d(Type::I32, 1);
+    ctx.op_get_rows(&table, &indices);
+}
+
+#[test]
+fn can_reshape_to_any_dimensionality() {
+    let ctx = Context::init(1024 * 1024, true);
+    let values = (0..12).map(|i| i as f32).collect::<Vec<_>>();
+    let mut input = ctx.new_tensor_1d(Type::F32, 12);
+    write_f32s(&mut input, &values);
+
+    let matrix = ctx.op_reshape_2d(&input, 3, 4);
+    assert_eq!(matrix.get_ne()[..2], [3, 4]);
+    let flat = ctx.op_reshape_1d(&ctx.op_reshape_4d(&matrix, 1, 3, 2, 2), 12);
+    assert_eq!(flat.get_ne()[0], 12);
+    compute(&ctx, &[&flat]);
+    assert_eq!(read_f32s(&flat), values);
+}
+
+#[test]
+#[should_panic(expected = "cannot reshape a tensor of 12 elements to [3, 5] (15 elements)")]
+fn reshaping_to_a_different_element_count_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let input = ctx.new_tensor_1d(Type::F32, 12);
+    ctx.op_reshape_2d(&input, 3, 5);
+}
+
+#[test]
+fn scratch_buffer_is_only_used_within_with_scratch() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut buffer = crate::Buffer::new(64 * 1024);
+    let data_size = 1024 * std::mem::size_of::<f32>();
+
+    let before = ctx.used_mem();
+    let in_scratch = ctx.with_scratch(&mut buffer, || {
+        let mut tensor = ctx.new_tensor_1d(Type::F32, 1024);
+        write_f32s(&mut tensor, &[1.0; 1024]);
+        ctx.used_mem()
+    });
+    // Only the tensor's metadata is allocated in the context.
+    assert!(in_scratch - before < data_size);
+
+    ctx.new_tensor_1d(Type::F32, 1024);
+    assert!(ctx.used_mem() - in_scratch >= data_size);
+}
+
+#[test]
+fn can_read_tensor_dimensions() {
+    let ctx = Context::init(1024 * 1024, true);
+    let tensor = ctx.new_tensor_2d(Type::F32, 3, 5);
+    assert_eq!(tensor.dims(), [3, 5, 1, 1]);
+    assert_eq!(tensor.n_dims(), 2);
+
+    let reshaped = ctx.op_reshape_3d(&tensor, 5, 1, 3);
+    assert_eq!(reshaped.dims(), [5, 1, 3, 1]);
+    assert_eq!(reshaped.n_dims(), 3);
+}
+
+#[test]
+fn can_fill_tensor_with_constant() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    let tensor = ctx.new_tensor_2d(Type::F32, 4, 3);
+    tensor.fill_f32(1.0);
+
+    let output = ctx.op_cpy(&tensor, &ctx.new_tensor_2d(Type::F32, 4, 3));
+    compute(&ctx, &[&output]);
+
+    assert_eq!(read_f32s(&output), vec![1.0; 12]);
+}
+
+#[test]
+fn can_repeat_back_to_original_shape() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    let mut row = ctx.new_tensor_2d(Type::F32, 3, 1);
+    write_f32s(&mut row, &[1.0, 2.0, 3.0]);
+
+    let repeated = ctx.op_repeat(&row, &ctx.new_tensor_2d(Type::F32, 3, 2));
+    let summed = ctx.op_repeat_back(&repeated, &row);
+    compute(&ctx, &[&summed]);
+
+    assert_eq!(summed.get_ne(), row.get_ne());
+    assert_eq!(read_f32s(&summed), vec![2.0, 4.0, 6.0]);
+}
+
+#[test]
+fn can_report_memory_usage() {
+    let ctx = Context::init(1024 * 1024, true);
+    assert_eq!(ctx.mem_size(), 1024 * 1024);
+
+    let a = ctx.new_tensor_2d(Type::F32, 16, 16);
+    let b = ctx.new_tensor_2d(Type::F32, 16, 16);
+    let _ = ctx.op_mul_mat(&a, &b);
+
+    let used_mem = ctx.used_mem();
+    assert!(used_mem > 0);
+    assert!(used_mem < ctx.mem_size());
+}
+
+#[test]
+fn can_measure_required_memory() {
+    fn build_graph(ctx: &Context) -> Tensor {
+        let a = ctx.new_tensor_2d(Type::F32, 16, 16);
+        let b = ctx.new_tensor_2d(Type::F32, 16, 16);
+        ctx.op_soft_max(&ctx.op_mul_mat(&a, &b))
+    }
+
+    let measure = Context::new_measure(1024 * 1024);
+    build_graph(&measure);
+    let required = measure.required_mem();
+    assert!(required >= 4 * 16 * 16 * std::mem::size_of::<f32>());
+
+    let ctx = Context::init(required, true);
+    build_graph(&ctx);
+    assert!(ctx.used_mem() <= required);
+}
+
+#[test]
+fn can_map_custom_unary_function() {
+    unsafe extern "C" fn square_plus_one(n: std::os::raw::c_int, dst: *mut f32, src: *const f32) {
+        for i in 0..n as usize {
+            let x = *src.add(i);
+            *dst.add(i) = x * x + 1.0;
+        }
+    }
+
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut input, &[-2.0, 0.0, 3.0]);
+
+    let output = unsafe { ctx.op_map_unary(&input, square_plus_one) };
+    compute(&ctx, &[&output]);
+
+    assert_eq!(read_f32s(&output), vec![5.0, 1.0, 10.0]);
+}
+
+#[test]
+fn can_soft_max_in_place() {
+    let values = [1.0, 2.0, 3.0, 4.0, -1.0, 0.0, 0.5, 2.0, 3.0];
+    let ctx = Context::init(1024 * 1024, true);
+
+    let mut a = ctx.new_tensor_2d(Type::F32, 3, 3);
+    write_f32s(&mut a, &values);
+    let out_of_place = ctx.op_soft_max(&ctx.op_diag_mask_inf(&a, 0));
+
+    let mut b = ctx.new_tensor_2d(Type::F32, 3, 3);
+    write_f32s(&mut b, &values);
+    let in_place = ctx.op_soft_max_inplace(&ctx.op_diag_mask_inf(&b, 0));
+
+    compute(&ctx, &[&out_of_place, &in_place]);
+
+    let out_of_place = read_f32s(&out_of_place);
+    assert_eq!(out_of_place, read_f32s(&in_place));
+    // The masked entries above the diagonal have no weight.
+    assert_eq!(&out_of_place[..3], &[1.0, 0.0, 0.0]);
+}
+
+#[test]
+fn can_apply_abs_neg_and_step() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut input, &[-1.0, 0.0, 2.0]);
+
+    let abs = ctx.op_abs(&input);
+    let neg = ctx.op_neg(&input);
+    let step = ctx.op_step(&input);
+    compute(&ctx, &[&abs, &neg, &step]);
+
+    assert_eq!(read_f32s(&abs), vec![1.0, 0.0, 2.0]);
+    assert_eq!(read_f32s(&neg), vec![1.0, -0.0, -2.0]);
+    assert_eq!(read_f32s(&step), vec![0.0, 0.0, 1.0]);
+}
+
+#[test]
+fn can_apply_relu_and_tanh() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut input, &[-1.0, 0.0, 2.0]);
+
+    let relu = ctx.op_relu(&input);
+    let tanh = ctx.op_tanh(&input);
+    compute(&ctx, &[&relu, &tanh]);
+
+    assert_eq!(read_f32s(&relu), vec![0.0, 0.0, 2.0]);
+    assert_eq!(read_f32s(&tanh), vec![(-1.0f32).tanh(), 0.0, 2.0f32.tanh()]);
+}
+
+#[test]
+fn can_apply_quick_gelu() {
+    let ctx = Context::init(1024 * 1024, true);
+    let values = [-3.0f32, -0.5, 0.0, 0.5, 3.0];
+    let mut input = ctx.new_tensor_1d(Type::F32, values.len());
+    write_f32s(&mut input, &values);
+
+    let gelu_quick = ctx.op_gelu_quick(&input);
+    compute(&ctx, &[&gelu_quick]);
+
+    let sigmoid = |x: f32| 1.0 / (1.0 + (-x).exp());
+    for (actual, x) in read_f32s(&gelu_quick).into_iter().zip(values) {
+        let expected = x * sigmoid(1.702 * x);
+        assert!((actual - expected).abs() < 1e-6, "{actual} != {expected}");
+    }
+}
+
+#[test]
+fn scaled_and_masked_soft_max_matches_reference() {
+    let ctx = Context::init(1024 * 1024, true);
+    // Two heads of 2x3 scores, large enough to overflow `exp` without scaling.
+    let scores = [
+        100.0, 200.0, 300.0, 400.0, 500.0, 600.0, //
+        -100.0, -200.0, -300.0, -400.0, -500.0, -600.0,
+    ];
+    let mut input = ctx.new_tensor_3d(Type::F32, 3, 2, 2);
+    write_f32s(&mut input, &scores);
+    // A causal mask shared by both heads.
+    let inf = f32::INFINITY;
+    let mask_values = [0.0, 0.0, -inf, 0.0, 0.0, 0.0];
+    let mut mask = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut mask, &mask_values);
+
+    let scale = 0.125;
+    let output = ctx.op_soft_max_ext(&input, scale, Some(&mask));
+    compute(&ctx, &[&output]);
+
+    let expected = scores
+        .chunks(3)
+        .zip(mask_values.chunks(3).cycle())
+        .flat_map(|(row, mask)| {
+            let row = row
+                .iter()
+                .zip(mask)
+                .map(|(s, m)| s * scale + m)
+                .collect::<Vec<_>>();
+            let max = row.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+            let exps = row.iter().map(|x| (x - max).exp()).collect::<Vec<_>>();
+            let sum = exps.iter().sum::<f32>();
+            exps.into_iter().map(move |e| e / sum)
+        })
+        .collect::<Vec<_>>();
+    let actual = read_f32s(&output);
+    assert!(actual.iter().all(|v| v.is_finite()));
+    assert_eq!(actual[2], 0.0);
+    for (actual, expected) in actual.iter().zip(&expected) {
+        assert!((actual - expected).abs() < 1e-5, "{actual} != {expected}");
+    }
+}
+
+#[test]
+fn type_info_describes_layout() {
+    assert_eq!(
+        Type::Q4_0.info(),
+        TypeInfo {
+            size: type_size(Type::Q4_0),
+            block_size: 32,
+            quantized: true,
+        }
+    );
+    assert_eq!(
+        Type::F32.info(),
+        TypeInfo {
+            size: 4,
+            block_size: 1,
+            quantized: false,
+        }
+    );
+    assert_eq!(Type::F16.info().size, 2);
+}
+
+#[test]
+fn masked_positions_get_no_attention() {
+    let ctx = Context::init(1024 * 1024, true);
+    // Two heads of 4x2 scores.
+    let mut scores = ctx.new_tensor_3d(Type::F32, 4, 2, 2);
+    write_f32s(
+        &mut scores,
+        &(0..16).map(|i| i as f32 * 0.25).collect::<Vec<_>>(),
+    );
+    // The last key is padding for both queries, and the first query cannot see the second key.
+    let inf = f32::INFINITY;
+    let mut mask = ctx.new_tensor_2d(Type::F32, 4, 2);
+    write_f32s(&mut mask, &[0.0, -inf, 0.0, -inf, 0.0, 0.0, 0.0, -inf]);
+
+    let weights = ctx.op_soft_max(&ctx.op_add_mask(&scores, &mask));
+    compute(&ctx, &[&weights]);
+
+    for (i, row) in read_f32s(&weights).chunks(4).enumerate() {
+        assert_eq!(row[3], 0.0);
+        if i % 2 == 0 {
+            assert_eq!(row[1], 0.0);
+        }
+        assert!(row[0] > 0.0 && row[2] > 0.0);
+        assert!((row.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn duplicate_is_independent_of_its_source() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut input, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+    let copy = ctx.op_dup(&input);
+    compute(&ctx, &[&copy]);
+    assert_eq!(copy.get_ne(), input.get_ne());
+    assert_eq!(copy.get_type(), Type::F32);
+
+    write_f32s(&mut input, &[0.0; 6]);
+    assert_eq!(read_f32s(&copy), vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+}
+
+#[test]
+fn transpose_swaps_the_first_two_dimensions() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    // Two rows of three columns.
+    let mut a = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut a, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+
+    let transposed = ctx.op_transpose(&a);
+    assert_eq!(transposed.get_ne()[..2], [2, 3]);
+
+    let contiguous = ctx.op_cont(&transposed);
+    compute(&ctx, &[&contiguous]);
+    assert_eq!(contiguous.get_ne()[..2], [2, 3]);
+    assert_eq!(read_f32s(&contiguous), [1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+}
+
+#[test]
+fn acc_adds_into_a_region() {
+    let ctx = Context::init(1024 * 1024, true);
+
+    // Three rows of four columns.
+    let mut a = ctx.new_tensor_2d(Type::F32, 4, 3);
+    write_f32s(&mut a, &[1.0; 12]);
+    let mut b = ctx.new_tensor_2d(Type::F32, 2, 2);
+    write_f32s(&mut b, &[1.0, 2.0, 3.0, 4.0]);
+
+    // The second and third columns of the first two rows.
+    let [_, nb1, nb2, nb3] = a.get_nb();
+    let sum = ctx.op_acc(&a, &b, nb1, nb2, nb3, std::mem::size_of::<f32>());
+    compute(&ctx, &[&sum]);
+
+    assert_eq!(sum.get_ne(), a.get_ne());
+    let expected = [
+        [1.0, 2.0, 3.0, 1.0],
+        [1.0, 4.0, 5.0, 1.0],
+        [1.0, 1.0, 1.0, 1.0],
+    ]
+    .concat();
+    assert_eq!(read_f32s(&sum), expected);
+    assert_eq!(read_f32s(&a), [1.0; 12]);
+}
+
+#[test]
+fn can_clamp() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_1d(Type::F32, 6);
+    write_f32s(
+        &mut input,
+        &[f32::NEG_INFINITY, -8.0, -0.5, 0.5, 8.0, f32::INFINITY],
+    );
+
+    let clamped = ctx.op_clamp(&input, -1.0, 1.0);
+    let fractional = ctx.op_clamp(&input, -0.25, 0.75);
+    compute(&ctx, &[&clamped, &fractional]);
+
+    assert_eq!(read_f32s(&clamped), vec![-1.0, -1.0, -0.5, 0.5, 1.0, 1.0]);
+    assert_eq!(
+        read_f32s(&fractional),
+        vec![-0.25, -0.25, -0.25, 0.5, 0.75, 0.75]
+    );
+    // The input is left unchanged.
+    assert_eq!(read_f32s(&input)[2], -0.5);
+}
+
+#[test]
+fn alibi_slopes_follow_reference_formula() {
+    fn reference_slope(head: usize, n_head: usize, bias_max: f32) -> f32 {
+        2f32.powf(-bias_max * (head + 1) as f32 / n_head as f32)
+    }
+
+    for (n_head, bias_max) in [(8, 8.0), (16, 8.0), (8, 4.0)] {
+        let n_kv = 4;
+        let ctx = Context::init(1024 * 1024, true);
+        let mut input = ctx.new_tensor_3d(Type::F32, n_kv, 1, n_head);
+        write_f32s(&mut input, &vec![0.0; n_kv * n_head]);
+
+        let biased = ctx.op_alibi(&input, n_kv - 1, n_head, bias_max);
+        compute(&ctx, &[&biased]);
+
+        let bias = read_f32s(&biased);
+        for (head, row) in bias.chunks(n_kv).enumerate() {
+            let expected = reference_slope(head, n_head, bias_max);
+            for pair in row.windows(2) {
+                assert!(
+                    (pair[1] - pair[0] - expected).abs() < 1e-6,
+                    "head {head} of {n_head}: expected slope {expected}, got {row:?}"
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn rope_custom_matches_rope_with_default_frequencies() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_3d(Type::F32, 8, 2, 3);
+    let values = (0..48).map(|i| (i as f32 * 0.37).sin()).collect::<Vec<_>>();
+    write_f32s(&mut input, &values);
+
+    for mode in [0, 2] {
+        let expected = ctx.op_rope(&input, 5, 8, mode);
+        let actual = ctx.op_rope_custom(&input, 5, 8, mode, 10000.0, 1.0);
+        compute(&ctx, &[&expected, &actual]);
+
+        for (e, a) in read_f32s(&expected).into_iter().zip(read_f32s(&actual)) {
+            assert!((e - a).abs() < 1e-4, "mode {mode}: expected {e}, got {a}");
+        }
+    }
+
+    // Only the first `ndims` dimensions of each row are rotated.
+    for mode in [0, 2] {
+        let partial = ctx.op_rope_custom(&input, 5, 4, mode, 10000.0, 1.0);
+        compute(&ctx, &[&partial]);
+
+        let partial = read_f32s(&partial);
+        for (row, input_row) in partial.chunks(8).zip(values.chunks(8)) {
+            assert_ne!(row[..4], input_row[..4]);
+            assert_eq!(row[4..], input_row[4..]);
+        }
+    }
+}
+
+#[test]
+fn rope_custom_freq_scale_scales_rotation_angles() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_3d(Type::F32, 4, 1, 1);
+    write_f32s(&mut input, &[1.0, 0.0, 1.0, 0.0]);
+
+    // Halving the frequency scale at position 6 gives the angles of position 3.
+    let halved = ctx.op_rope_custom(&input, 6, 4, 0, 10000.0, 0.5);
+    let default = ctx.op_rope_custom(&input, 3, 4, 0, 10000.0, 1.0);
+    compute(&ctx, &[&halved, &default]);
+
+    let halved = read_f32s(&halved);
+    for (h, d) in halved.iter().zip(read_f32s(&default)) {
+        assert!((h - d).abs() < 1e-5, "expected {d}, got {h}");
+    }
+    assert!((halved[1].atan2(halved[0]) - 3.0).abs() < 1e-5);
+}
+
+#[test]
+fn can_scale_by_f32() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_1d(Type::F32, 4);
+    write_f32s(&mut a, &[1.0, -2.0, 3.0, 5.0]);
+
+    let scaled = ctx.op_scale_f32(&a, 0.5);
+    let two_step = ctx.op_scale(&a, &ctx.new_f32(0.5));
+    compute(&ctx, &[&scaled, &two_step]);
+
+    assert_eq!(read_f32s(&scaled), vec![0.5, -1.0, 1.5, 2.5]);
+    assert_eq!(read_f32s(&scaled), read_f32s(&two_step));
+}
+
+#[test]
+fn can_add_scalar() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut a, &[1.0, -2.0, 3.0, 0.0, 0.5, -1.0]);
+
+    let sum = ctx.op_add1(&a, &ctx.new_f32(1.0));
+    compute(&ctx, &[&sum]);
+
+    assert_eq!(read_f32s(&sum), vec![2.0, -1.0, 4.0, 1.0, 1.5, 0.0]);
+}
+
+#[test]
+fn can_set_1d_range() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut target = ctx.new_tensor_1d(Type::F32, 8);
+    let target_values = [
+        1.0,
+        f32::INFINITY,
+        3.0,
+        f32::NEG_INFINITY,
+        5.0,
+        6.0,
+        7.0,
+        8.0,
+    ];
+    write_f32s(&mut target, &target_values);
+    let mut value = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut value, &[10.0, -20.0, f32::INFINITY]);
+
+    let element_size = std::mem::size_of::<f32>();
+    let set = ctx.op_set_1d(&target, &value, 2 * element_size);
+    // Ranges at the start and at the end of the target.
+    let set_start = ctx.op_set_1d(&target, &value, 0);
+    let set_end = ctx.op_set_1d(&target, &value, 5 * element_size);
+    compute(&ctx, &[&set, &set_start, &set_end]);
+
+    assert_eq!(
+        read_f32s(&set),
+        vec![
+            1.0,
+            f32::INFINITY,
+            10.0,
+            -20.0,
+            f32::INFINITY,
+            6.0,
+            7.0,
+            8.0
+        ]
+    );
+    assert_eq!(
+        read_f32s(&set_start),
+        vec![
+            10.0,
+            -20.0,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            5.0,
+            6.0,
+            7.0,
+            8.0
+        ]
+    );
+    assert_eq!(
+        read_f32s(&set_end),
+        vec![
+            1.0,
+            f32::INFINITY,
+            3.0,
+            f32::NEG_INFINITY,
+            5.0,
+            10.0,
+            -20.0,
+            f32::INFINITY
+        ]
+    );
+    // The target itself is not modified.
+    assert_eq!(read_f32s(&target), target_values);
+}
+
+#[test]
+fn f16_matrix_multiplication_is_close_to_f32() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 4, 3);
+    write_f32s(
+        &mut a,
+        &(0..12).map(|i| 0.1 * i as f32 - 0.55).collect::<Vec<_>>(),
+    );
+    let mut b = ctx.new_tensor_2d(Type::F32, 4, 2);
+    write_f32s(
+        &mut b,
+        &(0..8).map(|i| 1.0 / (i + 3) as f32).collect::<Vec<_>>(),
+    );
+
+    let full = ctx.op_mul_mat_precision(&a, &b, MatMulPrecision::F32);
+    let half = ctx.op_mul_mat_precision(&a, &b, MatMulPrecision::F16);
+    compute(&ctx, &[&full, &half]);
+
+    assert_eq!(half.dims(), full.dims());
+    for (half, full) in read_f32s(&half).into_iter().zip(read_f32s(&full)) {
+        assert!((half - full).abs() < 1e-2, "{half} != {full}");
+    }
+
+    // An `F16` matrix is multiplied as is, without another copy.
+    let a_f16 = ctx.new_tensor_2d(Type::F16, 4, 3);
+    let used_mem = ctx.used_mem();
+    ctx.op_mul_mat_precision(&a_f16, &b, MatMulPrecision::F16);
+    let plain_used_mem = ctx.used_mem() - used_mem;
+    ctx.op_mul_mat(&a_f16, &b);
+    assert_eq!(ctx.used_mem() - used_mem, 2 * plain_used_mem);
+}
+
+#[test]
+fn can_concatenate_along_second_dimension() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut a, &[1.0, 2.0, 3.0, 4.0, 5.0, 6.0]);
+    let mut b = ctx.new_tensor_2d(Type::F32, 3, 1);
+    write_f32s(&mut b, &[-1.0, -2.0, -3.0]);
+
+    let concatenated = ctx.op_concat(&a, &b, 1);
+    compute(&ctx, &[&concatenated]);
+
+    assert_eq!(concatenated.n_dims(), 2);
+    assert_eq!(concatenated.dims(), [3, 3, 1, 1]);
+    assert_eq!(
+        read_f32s(&concatenated),
+        vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, -1.0, -2.0, -3.0]
+    );
+}
+
+#[test]
+fn can_concatenate_along_first_dimension() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 2, 2);
+    write_f32s(&mut a, &[1.0, 2.0, 3.0, 4.0]);
+    let mut b = ctx.new_tensor_2d(Type::F32, 1, 2);
+    write_f32s(&mut b, &[f32::INFINITY, f32::NEG_INFINITY]);
+
+    let concatenated = ctx.op_concat(&a, &b, 0);
+    compute(&ctx, &[&concatenated]);
+
+    assert_eq!(concatenated.dims(), [3, 2, 1, 1]);
+    assert_eq!(
+        read_f32s(&concatenated),
+        vec![1.0, 2.0, f32::INFINITY, 3.0, 4.0, f32::NEG_INFINITY]
+    );
+}
+
+#[test]
+#[should_panic(expected = "cannot concatenate tensors of incompatible shapes")]
+fn concatenating_incompatible_shapes_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let a = ctx.new_tensor_2d(Type::F32, 3, 2);
+    let b = ctx.new_tensor_2d(Type::F32, 4, 2);
+    ctx.op_concat(&a, &b, 1);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(
+    expected = "cannot multiply matrices of incompatible shapes [4, 2, 1, 1] and [3, 2, 1, 1]"
+)]
+fn multiplying_incompatible_shapes_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let a = ctx.new_tensor_2d(Type::F32, 4, 2);
+    let b = ctx.new_tensor_2d(Type::F32, 3, 2);
+    ctx.op_mul_mat(&a, &b);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "cannot multiply by a f16 matrix; it must be F32")]
+fn multiplying_by_non_f32_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let a = ctx.new_tensor_2d(Type::F16, 4, 2);
+    let b = ctx.new_tensor_2d(Type::F16, 4, 2);
+    ctx.op_mul_mat(&a, &b);
+}
+
+#[test]
+fn can_mask_outside_sliding_window() {
+    let ctx = Context::init(1024 * 1024, true);
+    // Two heads of scores for 2 queries at positions 3 and 4 over 5 keys.
+    let mut a = ctx.new_tensor_3d(Type::F32, 5, 2, 2);
+    write_f32s(&mut a, &[1.0; 20]);
+
+    let masked = ctx.op_diag_mask_inf_window(&a, 3, 2);
+    compute(&ctx, &[&masked]);
+
+    let inf = f32::NEG_INFINITY;
+    let head = [
+        [inf, 1.0, 1.0, 1.0, inf], // position 3 sees positions 1 to 3
+        [inf, inf, 1.0, 1.0, 1.0], // position 4 sees positions 2 to 4
+    ]
+    .concat();
+    assert_eq!(read_f32s(&masked), [head.clone(), head].concat());
+
+    // The window can start before the first key for some queries and not for others.
+    let early = ctx.op_diag_mask_inf_window(&a, 1, 1);
+    let wide = ctx.op_diag_mask_inf_window(&a, 3, 8);
+    let causal = ctx.op_diag_mask_inf(&a, 3);
+    compute(&ctx, &[&early, &wide, &causal]);
+
+    let head = [
+        [1.0, 1.0, inf, inf, inf], // position 1 sees positions 0 to 1
+        [inf, 1.0, 1.0, inf, inf], // position 2 sees positions 1 to 2
+    ]
+    .concat();
+    assert_eq!(read_f32s(&early), [head.clone(), head].concat());
+    assert_eq!(read_f32s(&wide), read_f32s(&causal));
+}
+
+#[test]
+fn tensor_names_survive_graph_construction() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_1d(Type::F32, 4);
+    let b = ctx.new_tensor_1d(Type::F32, 4);
+    assert_eq!(b.name(), "");
+
+    a.set_name("attention_scores");
+    let mut sum = ctx.op_add(&a, &b);
+    sum.set_name("a_name_that_is_much_longer_than_ggml_allows");
+
+    let mut graph = ComputationGraph::new(1);
+    graph.build_forward_expand(&sum);
+
+    assert_eq!(a.name(), "attention_scores");
+    assert_eq!(sum.name(), "a_name_that_is_much_longer_than");
+}
+
+#[test]
+fn can_export_graph_as_dot() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 4, 3);
+    a.set_name("weight");
+    let b = ctx.new_tensor_2d(Type::F32, 4, 2);
+    let c = ctx.new_tensor_2d(Type::F32, 3, 2);
+    let mut sum = ctx.op_add(&ctx.op_mul_mat(&a, &b), &c);
+    sum.set_name("output");
+    assert_eq!(sum.name(), "output");
+
+    let mut graph = ComputationGraph::new(1);
+    graph.build_forward_expand(&sum);
+    let dot = graph.export_dot();
+
+    assert!(dot.starts_with("digraph G {"));
+    assert_eq!(dot.matches("shape = box").count(), 2);
+    assert_eq!(dot.matches("shape = ellipse").count(), 3);
+    assert_eq!(dot.matches(" -> ").count(), 4);
+    assert!(dot.contains("\"weight | f32 | 4 x 3\""));
+    assert!(dot.contains("| MUL_MAT | 3 x 2\""));
+    assert!(dot.contains("\"output | ADD | 3 x 2\""));
+}
+
+#[test]
+fn can_parse_type_names() {
+    for typ in [
+        Type::Q4_0,
+        Type::Q4_1,
+        Type::Q5_0,
+        Type::Q5_1,
+        Type::Q8_0,
+        Type::Q8_1,
+        Type::I32,
+        Type::F16,
+        Type::F32,
+        Type::LegacyQ4_2,
+    ] {
+        assert_eq!(typ.to_string().parse::<Type>(), Ok(typ));
+    }
+
+    assert_eq!("f64".parse::<Type>(), Err(TypeError::F64Unsupported));
+    assert_eq!(
+        "q3_k".parse::<Type>(),
+        Err(TypeError::Unknown("q3_k".to_string()))
+    );
+}
+
+#[test]
+fn can_borrow_computed_data_as_slice() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut weights = ctx.new_tensor_2d(Type::F32, 3, 4);
+    write_f32s(&mut weights, &(0..12).map(|x| x as f32).collect::<Vec<_>>());
+    let mut input = ctx.new_tensor_2d(Type::F32, 3, 2);
+    write_f32s(&mut input, &[1.0, 0.0, -1.0, 0.5, 0.5, 0.5]);
+    let logits = ctx.op_mul_mat(&weights, &input);
+    compute(&ctx, &[&logits]);
+
+    let slice = unsafe { logits.as_slice_f32() };
+    assert_eq!(slice, read_f32s(&logits));
+    assert_eq!(slice, [-2.0, -2.0, -2.0, -2.0, 1.5, 6.0, 10.5, 15.0]);
+}
+
+#[test]
+#[should_panic(expected = "contiguous")]
+fn borrowing_transposed_view_as_slice_panics() {
+    let ctx = Context::init(1024 * 1024, true);
+    let a = ctx.new_tensor_2d(Type::F32, 3, 2);
+    let transposed = ctx.op_transpose(&a);
+    unsafe { transposed.as_slice_f32() };
+}
+
+#[test]
+fn reports_cpu_features_of_the_build() {
+    let features = cpu_features();
+
+    // ggml is compiled for the host's features, which the tests run on.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        assert_eq!(features.avx, std::is_x86_feature_detected!("avx"));
+        assert_eq!(features.avx2, std::is_x86_feature_detected!("avx2"));
+        assert!(!features.neon);
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        assert!(features.neon);
+        assert!(!features.avx);
+    }
+}
+
+#[test]
+fn thread_count_does_not_change_results() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_2d(Type::F32, 16, 16);
+    let mut b = ctx.new_tensor_2d(Type::F32, 16, 16);
+    write_f32s(
+        &mut a,
+        &(0..256).map(|i| (i % 7) as f32 - 3.0).collect::<Vec<_>>(),
+    );
+    write_f32s(
+        &mut b,
+        &(0..256).map(|i| (i % 5) as f32 * 0.5).collect::<Vec<_>>(),
+    );
+    let output = ctx.op_soft_max(&ctx.op_mul_mat(&a, &b));
+
+    let mut graph = ComputationGraph::new(1);
+    graph.build_forward_expand(&output);
+    ctx.graph_compute(&mut graph);
+    let single_threaded = read_f32s(&output);
+
+    graph.set_n_threads(4);
+    assert_eq!(graph.n_threads(), 4);
+    ctx.graph_compute(&mut graph);
+    assert_eq!(read_f32s(&output), single_threaded);
+
+    graph.set_n_threads(2);
+    ctx.graph_compute(&mut graph);
+    assert_eq!(read_f32s(&output), single_threaded);
+}
+
+#[test]
+fn graph_compute_reports_stats() {
+    let ctx = Context::init(1024 * 1024, true);
+    let mut a = ctx.new_tensor_1d(Type::F32, 4);
+    let mut b = ctx.new_tensor_1d(Type::F32, 4);
+    write_f32s(&mut a, &[1.0, 2.0, 3.0, 4.0]);
+    write_f32s(&mut b, &[0.5; 4]);
+    let outputs = [ctx.op_add(&a, &b), ctx.op_mul(&a, &b), ctx.op_silu(&a)];
+
+    let mut graph = ComputationGraph::new(1);
+    for output in &outputs {
+        graph.build_forward_expand(output);
+    }
+    let used_before = ctx.used_mem();
+    let stats = ctx.graph_compute_with_stats(&mut graph);
+
+    assert_eq!(stats.n_nodes, outputs.len());
+    assert_eq!(graph.n_nodes(), outputs.len());
+    assert!(stats.used_mem >= used_before);
+    assert_eq!(stats.used_mem, ctx.used_mem());
+    assert_eq!(read_f32s(&outputs[1]), vec![0.5, 1.0, 1.5, 2.0]);
+}
+
+#[test]
+fn can_cancel_graph_computation() {
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    let ctx = Context::init(1024 * 1024, true);
+    let mut input = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut input, &[1.0, 2.0, 3.0]);
+
+    let mut output = ctx.new_tensor_1d(Type::F32, 3);
+    write_f32s(&mut output, &[0.0; 3]);
+    let copy = ctx.op_cpy(&ctx.op_add(&input, &input), &output);
+
+    let cancel = Arc::new(AtomicBool::new(false));
+    std::thread::spawn({
+        let cancel = cancel.clone();
+        move || cancel.store(true, std::sync::atomic::Ordering::SeqCst)
+    })
+    .join()
+    .unwrap();
+
+    let mut graph = ComputationGraph::new(1);
+    graph.build_forward_expand(&copy);
+    assert_eq!(
+        ctx.graph_compute_cancellable(&mut graph, &cancel),
+        Err(Cancelled)
+    );
+    assert_eq!(read_f32s(&output), vec![0.0; 3]);
+
+    cancel.store(false, std::sync::atomic::Ordering::SeqCst);
+    assert_eq!(ctx.graph_compute_cancellable(&mut graph, &cancel), Ok(()));
+    assert_eq!(read_f32s(&output), vec![2.0, 4.0, 6.0]);
+}
+
+#[test]
+fn can_calculate_quantized_tensor_size() {
+    let ctx = Context::init(1024 * 1024, true);
+    let tensor = ctx.new_tensor_2d(Type::Q4_0, 64, 4);
+
+    assert_eq!(tensor.nelements(), 256);
+    assert_eq!(
+        tensor.nbytes(),
+        tensor.nelements() / blck_size(Type::Q4_0) * type_size(Type::Q4_0)
+    );
+
+    let used_mem = ctx.used_mem();
+    assert!(used_mem >= tensor.nbytes() + tensor_overhead());
+}
+
+#[test]
+fn can_round_trip_little_endian_values() {
+    use crate::util;
+
+    let mut buffer = vec![];
+    util::write_i32(&mut buffer, -2).unwrap();
+    util::write_u32(&mut buffer, 3).unwrap();
+    util::write_f32(&mut buffer, 0.5).unwrap();
+    util::write_i64(&mut buffer, -(1 << 40)).unwrap();
+    util::write_u64(&mut buffer, 1 << 50).unwrap();
+    util::write_f64(&mut buffer, std::f64::consts::PI).unwrap();
+    util::write_string(&mut buffer, "héllo").unwrap();
+    util::write_bytes_with_u32_len(&mut buffer, &[0xC3, b'!']).unwrap();
+
+    // The values must be stored in little-endian order, regardless of the platform.
+    assert_eq!(buffer[..4], [0xFE, 0xFF, 0xFF, 0xFF]);
+    assert_eq!(buffer[4..8], [3, 0, 0, 0]);
+
+    let mut reader = std::io::Cursor::new(buffer);
+    assert_eq!(util::read_i32(&mut reader).unwrap(), -2);
+    assert_eq!(util::read_u32(&mut reader).unwrap(), 3);
+    assert_eq!(util::read_f32(&mut reader).unwrap(), 0.5);
+    assert_eq!(util::read_i64(&mut reader).unwrap(), -(1 << 40));
+    assert_eq!(util::read_u64(&mut reader).unwrap(), 1 << 50);
+    assert_eq!(util::read_f64(&mut reader).unwrap(), std::f64::consts::PI);
+    assert_eq!(util::read_string(&mut reader).unwrap(), "héllo");
+    // Byte arrays may hold partial UTF-8 sequences, as tokens do.
+    assert_eq!(
+        util::read_bytes_with_u32_len(&mut reader).unwrap(),
+        vec![0xC3, b'!']
+    );
+    assert!(!util::has_data_left(&mut reader).unwrap());
+}
+
+fn compute(ctx: &Context, outputs: &[&Tensor]) {
+    let mut graph = ComputationGraph::new(1);
+    for output in outputs {
+        graph.build_forward_expand(output);
+    }
+    ctx.graph_compute(&mut graph);
+}
+
+fn write_f32s(tensor: &mut Tensor, values: &[f32]) {
+    let bytes = values
+        .iter()
+        .flat_map(|v| v.to_ne_bytes())
+        .collect::<Vec<_>>();
+    assert_eq!(bytes.len(), tensor.nbytes());
+    unsafe { tensor.write_data(&bytes) };
+}
+
+fn read_f32s(tensor: &Tensor) -> Vec<f32> {
+    unsafe { tensor.as_slice_f32() }.to_vec()
+}
+
 #[derive(Default, PartialEq, Debug)]
 struct Hyperparameters {
     some_hyperparameter: u32,
@@ -202,6 +1272,11 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
     }
 
     fn tensor_buffer(&mut self, info: format::TensorLoadInfo) -> Result<(), DummyError> {
+        if self.expected_container_type.support_mmap() {
+            // Tensor data must be aligned so that it can be used directly from an mmap.
+            assert_eq!(info.start_offset % 32, 0);
+        }
+
         let data = format::TensorSaveInfo {
             n_dims: info.n_dims,
             dims: info.dims,
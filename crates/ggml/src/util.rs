@@ -24,6 +24,21 @@ pub fn read_f32(reader: &mut dyn BufRead) -> Result<f32, std::io::Error> {
     Ok(f32::from_le_bytes(read_bytes::<4>(reader)?))
 }
 
+/// Read a `i64` from a reader.
+pub fn read_i64(reader: &mut dyn BufRead) -> Result<i64, std::io::Error> {
+    Ok(i64::from_le_bytes(read_bytes::<8>(reader)?))
+}
+
+/// Read a `u64` from a reader.
+pub fn read_u64(reader: &mut dyn BufRead) -> Result<u64, std::io::Error> {
+    Ok(u64::from_le_bytes(read_bytes::<8>(reader)?))
+}
+
+/// Read a `f64` from a reader.
+pub fn read_f64(reader: &mut dyn BufRead) -> Result<f64, std::io::Error> {
+    Ok(f64::from_le_bytes(read_bytes::<8>(reader)?))
+}
+
 /// Read a `bool` represented as an `i32` from a reader.
 pub fn read_bool(reader: &mut dyn BufRead) -> Result<bool, std::io::Error> {
     let val = i32::from_le_bytes(read_bytes::<4>(reader)?);
@@ -47,6 +62,32 @@ pub fn read_bytes_with_len(
     Ok(bytes)
 }
 
+/// Read an array of bytes prefixed with its length as a `u32` from a reader, as used for
+/// the tokens of the GGML family of formats.
+///
+/// Tokens are not necessarily valid UTF-8, so no validation is performed.
+pub fn read_bytes_with_u32_len(reader: &mut dyn BufRead) -> Result<Vec<u8>, std::io::Error> {
+    let len = read_u32(reader)?.try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "byte array length does not fit in memory",
+        )
+    })?;
+    read_bytes_with_len(reader, len)
+}
+
+/// Read a UTF-8 string prefixed with its length in bytes as a `u64` from a reader.
+pub fn read_string(reader: &mut dyn BufRead) -> Result<String, std::io::Error> {
+    let len = read_u64(reader)?.try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "string length does not fit in memory",
+        )
+    })?;
+    String::from_utf8(read_bytes_with_len(reader, len)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
 /// Write a `i32` from a writer.
 pub fn write_i32(writer: &mut dyn Write, value: i32) -> Result<(), std::io::Error> {
     writer.write_all(&value.to_le_bytes())
@@ -62,6 +103,42 @@ pub fn write_f32(writer: &mut dyn Write, value: f32) -> Result<(), std::io::Erro
     writer.write_all(&value.to_le_bytes())
 }
 
+/// Write a `i64` to a writer.
+pub fn write_i64(writer: &mut dyn Write, value: i64) -> Result<(), std::io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// Write a `u64` to a writer.
+pub fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), std::io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// Write a `f64` to a writer.
+pub fn write_f64(writer: &mut dyn Write, value: f64) -> Result<(), std::io::Error> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+/// Write a string prefixed with its length in bytes as a `u64` to a writer.
+pub fn write_string(writer: &mut dyn Write, value: &str) -> Result<(), std::io::Error> {
+    write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+/// Write an array of bytes prefixed with its length as a `u32` to a writer.
+pub fn write_bytes_with_u32_len(
+    writer: &mut dyn Write,
+    value: &[u8],
+) -> Result<(), std::io::Error> {
+    let len = value.len().try_into().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "byte array is too long for a u32 length",
+        )
+    })?;
+    write_u32(writer, len)?;
+    writer.write_all(value)
+}
+
 /// Write a `bool` represented as an `i32` to a writer.
 pub fn write_bool(writer: &mut dyn Write, value: bool) -> Result<(), std::io::Error> {
     let int_value: i32 = if value { 1 } else { 0 };
@@ -0,0 +1,182 @@
+//! Metadata for the GGUF format, which stores hyperparameters as named key-value
+//! pairs instead of the fixed-field layout used by the older GGML containers.
+
+use std::collections::HashMap;
+
+use crate::{FileType, LoadError};
+
+/// The metadata key under which the [FileType] of a model is stored.
+pub const FILE_TYPE_KEY: &str = "general.file_type";
+
+macro_rules! define_gguf_metadata_value {
+    ($(($name:ident, $type:ty, $doc:literal)),*) => {
+        #[derive(Debug, PartialEq, Clone)]
+        /// Valid value types for GGUF metadata.
+        pub enum GgufMetadataValue {
+            $(#[doc=$doc] $name($type),)*
+        }
+
+        $(
+            impl From<$type> for GgufMetadataValue {
+                fn from(value: $type) -> Self {
+                    Self::$name(value)
+                }
+            }
+        )*
+    };
+}
+
+define_gguf_metadata_value!(
+    (UInt32, u32, "An unsigned 32-bit integer"),
+    (Int32, i32, "A signed 32-bit integer"),
+    (UInt64, u64, "An unsigned 64-bit integer"),
+    (Float32, f32, "A 32-bit float"),
+    (Bool, bool, "A boolean value"),
+    (String, String, "A string value"),
+    (Array, Vec<GgufMetadataValue>, "An array of values")
+);
+
+/// The metadata of a GGUF model, keyed by name (e.g. `bloom.embedding_length`).
+#[derive(Debug, PartialEq, Default, Clone)]
+pub struct GgufMetadata(pub HashMap<String, GgufMetadataValue>);
+impl GgufMetadata {
+    /// Get the value of the metadata with the given `key`.
+    pub fn get(&self, key: &str) -> Option<&GgufMetadataValue> {
+        self.0.get(key)
+    }
+
+    /// Get the value of the metadata with the given `key` as a count or size.
+    ///
+    /// Any non-negative integer value is accepted.
+    pub fn get_countable(&self, key: &str) -> Result<usize, LoadError> {
+        let value = self.get_required(key)?;
+        match *value {
+            GgufMetadataValue::UInt32(value) => Ok(value.try_into()?),
+            GgufMetadataValue::Int32(value) => Ok(value.try_into()?),
+            GgufMetadataValue::UInt64(value) => Ok(value.try_into()?),
+            _ => Err(self.invalid_type(key, "integer")),
+        }
+    }
+
+    /// Get the value of the metadata with the given `key` as a float.
+    pub fn get_f32(&self, key: &str) -> Result<f32, LoadError> {
+        match *self.get_required(key)? {
+            GgufMetadataValue::Float32(value) => Ok(value),
+            _ => Err(self.invalid_type(key, "f32")),
+        }
+    }
+
+    /// Get the value of the metadata with the given `key` as a string.
+    pub fn get_str(&self, key: &str) -> Result<&str, LoadError> {
+        match self.get_required(key)? {
+            GgufMetadataValue::String(value) => Ok(value),
+            _ => Err(self.invalid_type(key, "string")),
+        }
+    }
+
+    /// Get the value of the metadata with the given `key` as an array.
+    pub fn get_array(&self, key: &str) -> Result<&[GgufMetadataValue], LoadError> {
+        match self.get_required(key)? {
+            GgufMetadataValue::Array(values) => Ok(values),
+            _ => Err(self.invalid_type(key, "array")),
+        }
+    }
+
+    /// Get the [FileType] of the model, stored under [FILE_TYPE_KEY].
+    pub fn get_file_type(&self) -> Result<FileType, LoadError> {
+        let ftype = match *self.get_required(FILE_TYPE_KEY)? {
+            GgufMetadataValue::UInt32(value) => value.try_into()?,
+            GgufMetadataValue::Int32(value) => value,
+            _ => return Err(self.invalid_type(FILE_TYPE_KEY, "integer")),
+        };
+        FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))
+    }
+
+    fn get_required(&self, key: &str) -> Result<&GgufMetadataValue, LoadError> {
+        self.get(key).ok_or_else(|| LoadError::MissingMetadata {
+            key: key.to_owned(),
+        })
+    }
+
+    fn invalid_type(&self, key: &str, expected: &'static str) -> LoadError {
+        LoadError::InvalidMetadataType {
+            key: key.to_owned(),
+            expected,
+            value: self.0[key].clone(),
+        }
+    }
+}
+
+/// Used to build the [GgufMetadata] of a model when writing it.
+#[derive(Debug, Default)]
+pub struct GgufMetadataBuilder(GgufMetadata);
+impl GgufMetadataBuilder {
+    /// Creates a new, empty [GgufMetadataBuilder].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the metadata `key` to `value`, replacing any previous value.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: impl Into<GgufMetadataValue>,
+    ) -> &mut Self {
+        self.0 .0.insert(key.into(), value.into());
+        self
+    }
+
+    /// Sets the [FileType] of the model under [FILE_TYPE_KEY].
+    pub fn insert_file_type(&mut self, file_type: FileType) -> &mut Self {
+        self.insert(FILE_TYPE_KEY, i32::from(file_type))
+    }
+
+    /// Finishes building the [GgufMetadata].
+    pub fn build(self) -> GgufMetadata {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FileTypeFormat;
+
+    #[test]
+    fn test_metadata_getters() {
+        let file_type = FileType {
+            format: FileTypeFormat::MostlyQ4_0,
+            quantization_version: 2,
+        };
+        let mut builder = GgufMetadataBuilder::new();
+        builder
+            .insert("test.u32", 4u32)
+            .insert("test.i32", 5i32)
+            .insert("test.negative", -1i32)
+            .insert("test.f32", 0.5f32)
+            .insert("test.name", "bloom".to_string())
+            .insert_file_type(file_type);
+        let metadata = builder.build();
+
+        assert_eq!(metadata.get_countable("test.u32").unwrap(), 4);
+        assert_eq!(metadata.get_countable("test.i32").unwrap(), 5);
+        assert_eq!(metadata.get_f32("test.f32").unwrap(), 0.5);
+        assert_eq!(metadata.get_file_type().unwrap(), file_type);
+
+        assert!(matches!(
+            metadata.get_countable("test.negative"),
+            Err(LoadError::InvalidIntegerConversion(_))
+        ));
+        assert!(matches!(
+            metadata.get_countable("test.name"),
+            Err(LoadError::InvalidMetadataType {
+                expected: "integer",
+                ..
+            })
+        ));
+        assert!(matches!(
+            metadata.get_countable("test.missing"),
+            Err(LoadError::MissingMetadata { key }) if key == "test.missing"
+        ));
+    }
+}
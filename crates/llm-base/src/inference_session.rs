@@ -1,12 +1,18 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
 use partial_sort::PartialSort;
-use rand::{distributions::WeightedIndex, prelude::Distribution};
+use rand::{distributions::WeightedIndex, prelude::Distribution, rngs::StdRng, SeedableRng};
 use thiserror::Error;
 
 use crate::{
-    mulf, util, InferenceParameters, Model, OutputRequest, Prompt, TokenId, TokenUtf8Buffer,
-    TokenizationError,
+    mulf, util, InferenceParameters, Model, OutputRequest, ParameterError, Prompt, TokenId,
+    TokenLogprob, TokenUtf8Buffer, TokenizationError, Vocabulary,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
@@ -35,6 +41,9 @@ pub struct InferenceSession {
     // Configuration for the session.
     pub(crate) config: InferenceSessionConfig,
 
+    // The number of tokens that fit in the key/value memory.
+    pub(crate) n_ctx: usize,
+
     /// Memory K
     #[doc(hidden)]
     pub memory_k: ggml::Tensor,
@@ -59,15 +68,43 @@ pub struct InferenceSession {
     #[doc(hidden)]
     pub last_logits: Vec<f32>,
 
+    /// The random number generator all tokens are sampled with, if the session was
+    /// seeded. See [InferenceSessionConfig::rng_seed].
+    pub(crate) rng: Option<StdRng>,
+
     /// Scratch buffers used during inference.
     ///
     /// The number of scratch buffers was copied from `llama.cpp`.
     /// There is no specific reason for this number, but one is insufficient.
     #[doc(hidden)]
     pub scratch: [ggml::Buffer; 2],
+
+    /// Set to cancel the evaluations of this session, see [InferenceSession::set_cancel_flag].
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
 }
 unsafe impl Send for InferenceSession {}
 impl InferenceSession {
+    /// Sets a flag that cancels the evaluations of this session, or removes it if `cancel`
+    /// is `None`.
+    ///
+    /// The flag can be set from another thread. It is checked before every evaluation of the
+    /// model: by [feed_prompt](Self::feed_prompt) before each batch of the prompt, by
+    /// [infer_next_token](Self::infer_next_token) (and so [infer](Self::infer)) before each
+    /// token, and at the start of each step of guided or speculative inference. Once it is
+    /// set, these return [InferenceError::Cancelled] without evaluating anything more. The
+    /// session keeps the tokens that were evaluated before that, and can be used again after
+    /// the flag has been cleared.
+    pub fn set_cancel_flag(&mut self, cancel: Option<Arc<AtomicBool>>) {
+        self.cancel = cancel;
+    }
+
+    fn check_cancelled(&self) -> Result<(), InferenceError> {
+        match &self.cancel {
+            Some(cancel) if cancel.load(Ordering::SeqCst) => Err(InferenceError::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
     /// Feed a prompt to the model for this session.
     pub fn feed_prompt<'a, E: std::error::Error + 'static, P: Into<Prompt<'a>>>(
         &mut self,
@@ -82,11 +119,12 @@ impl InferenceSession {
         let vocab = model.vocabulary();
         let prompt_tokens = prompt.into().to_tokens(vocab, beginning_of_sentence)?;
 
-        if self.n_past + prompt_tokens.len() >= model.n_context_tokens() {
+        if self.n_past + prompt_tokens.len() > model.n_context_tokens() {
             return Err(InferenceError::ContextFull);
         }
 
-        for batch in prompt_tokens.chunks(params.n_batch) {
+        for batch in prompt_tokens.chunks(params.n_batch.max(1)) {
+            self.check_cancelled()?;
             model.evaluate(self, params, batch, output_request);
             for &tk in batch {
                 let should_call_callback = Some(tk) != model.bot_token_id();
@@ -111,6 +149,55 @@ impl InferenceSession {
         Ok(())
     }
 
+    /// Feed a prompt that has already been tokenized to the model for this session.
+    ///
+    /// The `tokens` are fed as-is, in batches of [InferenceParameters::n_batch], without
+    /// a round-trip through text: no beginning-of-sentence token is added, so include it
+    /// in `tokens` if the model expects one. This is equivalent to calling
+    /// [feed_prompt](Self::feed_prompt) with [Prompt::Tokens].
+    pub fn feed_prompt_tokens<E: std::error::Error + 'static>(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+        callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<(), InferenceError> {
+        self.feed_prompt(
+            model,
+            params,
+            Prompt::Tokens(tokens),
+            output_request,
+            callback,
+        )
+    }
+
+    /// Returns the bytes of the token `id` in the vocabulary of `model`, or `None` if it is
+    /// not in the vocabulary. See [Vocabulary::token_bytes](crate::Vocabulary::token_bytes).
+    pub fn decode_token<'v>(&self, model: &'v dyn Model, id: TokenId) -> Option<&'v [u8]> {
+        model.vocabulary().token_bytes(id)
+    }
+
+    /// Evaluates a single token with `model` and then discards it, so that the work done
+    /// on the first evaluation, such as measuring the temporary memory needed per token
+    /// and paging in the weights, is not counted against the first real request.
+    ///
+    /// The session is left as it was, and nothing is evaluated if its context is full.
+    pub fn warmup(&mut self, model: &dyn Model, params: &InferenceParameters) {
+        if self.n_past >= model.n_context_tokens() {
+            return;
+        }
+
+        let n_past = self.n_past;
+        let last_logits = self.last_logits.clone();
+        let token = model.bot_token_id().unwrap_or(model.eot_token_id());
+        model.evaluate(self, params, &[token], &mut OutputRequest::default());
+
+        // The token's key/value memory will be overwritten by the next evaluation.
+        self.n_past = n_past;
+        self.last_logits = last_logits;
+    }
+
     /// Infer the next token for this session.
     pub fn infer_next_token<'v>(
         &mut self,
@@ -119,27 +206,204 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
     ) -> Result<&'v [u8], InferenceError> {
-        if self.n_past + 1 >= model.n_context_tokens() {
+        self.check_cancelled()?;
+        self.make_room_for_next_token(model, params, self.config.context_overflow)?;
+
+        // First, sample the next token, using the stored last_logits;
+        let next_token = self.sample_next_token(None, params, rng);
+        if output_request.want_logprobs {
+            output_request.logprobs.push(token_logprob(
+                &self.last_logits,
+                next_token,
+                output_request.n_top_logprobs,
+            ));
+        }
+
+        self.push_next_token(model, params, next_token, output_request)
+    }
+
+    /// Infer the next token for this session with classifier-free guidance.
+    ///
+    /// `negative` is a second session for the same model that holds the
+    /// unconditional (or negative) prompt. The token is sampled from the logits
+    /// of both sessions combined with [util::guided_logits], and is then
+    /// evaluated by both sessions so that they stay in lockstep.
+    ///
+    /// This session's [InferenceSessionConfig::context_overflow] applies to both
+    /// sessions. If an error is returned before a token is sampled, neither session
+    /// is changed, apart from a shifted context.
+    pub fn infer_next_token_with_guidance<'v>(
+        &mut self,
+        model: &'v dyn Model,
+        negative: &mut InferenceSession,
+        guidance_scale: f32,
+        params: &InferenceParameters,
+        output_request: &mut OutputRequest,
+        rng: &mut impl rand::Rng,
+    ) -> Result<&'v [u8], InferenceError> {
+        self.check_cancelled()?;
+        negative.check_cancelled()?;
+        let policy = self.config.context_overflow;
+        if policy == ContextOverflowPolicy::Error
+            && (self.n_past + 1 > model.n_context_tokens()
+                || negative.n_past + 1 > model.n_context_tokens())
+        {
             return Err(InferenceError::ContextFull);
         }
+        self.make_room_for_next_token(model, params, policy)?;
+        negative.make_room_for_next_token(model, params, policy)?;
+
+        let logits = util::guided_logits(&self.last_logits, &negative.last_logits, guidance_scale);
+        let next_token = self.sample_next_token(Some(&logits), params, rng);
+        if output_request.want_logprobs {
+            output_request.logprobs.push(token_logprob(
+                &logits,
+                next_token,
+                output_request.n_top_logprobs,
+            ));
+        }
 
-        // First, sample the next token, using the stored last_logits;
-        let next_token = self.sample_top_p_top_k(params, rng);
+        negative.tokens.push(next_token);
+        model.evaluate(negative, params, &[next_token], &mut Default::default());
+        self.push_next_token(model, params, next_token, output_request)
+    }
 
-        // Update the tokens for this session
-        self.tokens.push(next_token);
+    /// Applies `policy` if there is no room left in the context for another token.
+    fn make_room_for_next_token(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        policy: ContextOverflowPolicy,
+    ) -> Result<(), InferenceError> {
+        if self.n_past < model.n_context_tokens() {
+            return Ok(());
+        }
+        match policy {
+            ContextOverflowPolicy::Error => Err(InferenceError::ContextFull),
+            ContextOverflowPolicy::ShiftLeft { keep } => self.shift_context(model, params, keep),
+        }
+    }
 
-        // Then, evaluate the network again to compute the new last_logits
-        model.evaluate(self, params, &[next_token], output_request);
+    /// Adds the sampled `token` to this session and evaluates it, which computes
+    /// the logits for the token after it.
+    fn push_next_token<'v>(
+        &mut self,
+        model: &'v dyn Model,
+        params: &InferenceParameters,
+        token: TokenId,
+        output_request: &mut OutputRequest,
+    ) -> Result<&'v [u8], InferenceError> {
+        self.tokens.push(token);
+        model.evaluate(self, params, &[token], output_request);
 
-        // Return the next token
-        if next_token as TokenId == model.eot_token_id() {
+        if token == model.eot_token_id() {
             Err(InferenceError::EndOfText)
         } else {
-            Ok(model.vocabulary().token(next_token as usize))
+            Ok(model.vocabulary().token(token as usize))
         }
     }
 
+    /// Infer up to `n_draft + 1` tokens for this session with speculative decoding.
+    ///
+    /// The `draft` model, which should be much smaller than `model` and must share its
+    /// vocabulary, proposes `n_draft` tokens one at a time in `draft_session`. `model` then
+    /// evaluates all of them at once, which gives its logits for each draft token and for
+    /// the token after them. Tokens are sampled from those logits as usual; draft tokens
+    /// are accepted for as long as they match what was sampled, and the first token that
+    /// differs, or the one after the last draft token, ends the step. The output therefore
+    /// follows the same distribution as calling [Self::infer_next_token] repeatedly, while
+    /// evaluating `model` far less often when the draft model predicts it well.
+    ///
+    /// `draft_session` must have evaluated the same tokens as this session, and is kept
+    /// in step with it. The returned tokens have been added to both sessions; if the last
+    /// one is [Model::eot_token_id], the text has ended. A full context is always reported
+    /// as [InferenceError::ContextFull], regardless of [InferenceSessionConfig::context_overflow].
+    pub fn infer_next_tokens_speculative(
+        &mut self,
+        model: &dyn Model,
+        draft: &dyn Model,
+        draft_session: &mut InferenceSession,
+        n_draft: usize,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+    ) -> Result<Vec<TokenId>, InferenceError> {
+        self.check_cancelled()?;
+        let remaining = model.n_context_tokens().saturating_sub(self.n_past);
+        let draft_remaining = draft
+            .n_context_tokens()
+            .saturating_sub(draft_session.n_past);
+        let remaining = remaining.min(draft_remaining);
+        if remaining == 0 {
+            return Err(InferenceError::ContextFull);
+        }
+        // Leave room to evaluate the token that is sampled after the last draft token.
+        let n_draft = n_draft.min(remaining - 1);
+
+        let n_past = self.n_past;
+        let n_tokens = self.tokens.len();
+        let draft_n_past = draft_session.n_past;
+        let draft_n_tokens = draft_session.tokens.len();
+
+        let mut drafted = Vec::with_capacity(n_draft);
+        while drafted.len() < n_draft {
+            let token = draft_session.sample_next_token(None, params, rng);
+            draft_session.tokens.push(token);
+            draft.evaluate(
+                draft_session,
+                params,
+                &[token],
+                &mut OutputRequest::default(),
+            );
+            drafted.push(token);
+            if token == draft.eot_token_id() {
+                break;
+            }
+        }
+
+        // The logits from before the draft tokens predict the first of them, and the
+        // logits at each draft token predict the token after it.
+        let mut predictions = vec![self.last_logits.clone()];
+        if !drafted.is_empty() {
+            let n_vocab = self.last_logits.len();
+            let mut output_request = OutputRequest {
+                all_logits: Some(vec![]),
+                ..Default::default()
+            };
+            self.tokens.extend_from_slice(&drafted);
+            model.evaluate(self, params, &drafted, &mut output_request);
+            let all_logits = output_request.all_logits.unwrap_or_default();
+            predictions.extend(all_logits.chunks(n_vocab).map(|logits| logits.to_vec()));
+        }
+
+        let mut tokens = vec![];
+        for (i, logits) in predictions.into_iter().enumerate() {
+            // Sample with the same tokens and logits that normal decoding would have.
+            self.tokens.truncate(n_tokens + i);
+            self.last_logits = logits;
+            let token = self.sample_next_token(None, params, rng);
+            tokens.push(token);
+            if drafted.get(i) != Some(&token) || token == model.eot_token_id() {
+                break;
+            }
+        }
+
+        // Every token but the last matched a draft token, so both models have already
+        // evaluated them. Discard the rest of the draft, then evaluate the last token.
+        let n_accepted = tokens.len() - 1;
+        let last = tokens[n_accepted];
+        for (session, model, n_past, n_tokens) in [
+            (&mut *self, model, n_past, n_tokens),
+            (draft_session, draft, draft_n_past, draft_n_tokens),
+        ] {
+            session.tokens.truncate(n_tokens + n_accepted);
+            session.n_past = n_past + n_accepted;
+            session.tokens.push(last);
+            model.evaluate(session, params, &[last], &mut OutputRequest::default());
+        }
+
+        Ok(tokens)
+    }
+
     /// Generate text by using the provided [Model] to evaluate the `prompt`.
     ///
     /// The `callback` is called with each new token until an end-of-text (EOT)
@@ -155,6 +419,9 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
     ) -> Result<InferenceStats, InferenceError> {
+        let parameters = request.parameters.unwrap_or(model.inference_parameters());
+        parameters.validate(self.last_logits.len())?;
+
         let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
         if request.play_back_previous_tokens {
             // "Play back" the existing tokens, so that loading from an inference snapshot works
@@ -173,21 +440,36 @@ impl InferenceSession {
         }
 
         let mut stats = InferenceStats::default();
-        let start_at = std::time::SystemTime::now();
-
-        let parameters = request.parameters.unwrap_or(model.inference_parameters());
+        let start_at = std::time::Instant::now();
+
+        // With token healing, the last token of the prompt is held back and
+        // regenerated, so that a prompt ending mid-word is not stuck with the
+        // tokenization of its partial last word.
+        let mut prompt_tokens = request
+            .prompt
+            .to_tokens(model.vocabulary(), self.n_past == 0)?;
+        let healed_token = match prompt_tokens.last() {
+            Some(&token) if request.token_healing && Some(token) != model.bot_token_id() => {
+                prompt_tokens.pop()
+            }
+            _ => None,
+        };
 
         // Feed the initial prompt through the transformer, to update its
         // context window with new data.
         self.feed_prompt(
             model,
             parameters,
-            request.prompt,
+            Prompt::Tokens(&prompt_tokens),
             output_request,
             feed_prompt_callback(&mut callback),
         )?;
-        stats.feed_prompt_duration = start_at.elapsed().unwrap();
+        if let Some(token) = healed_token {
+            self.restrict_next_token_to_prefix(model.vocabulary(), token);
+        }
+        stats.feed_prompt_duration = start_at.elapsed();
         stats.prompt_tokens = self.n_past;
+        let predict_start_at = std::time::Instant::now();
 
         // After the prompt is consumed, sample tokens by repeatedly calling
         // `infer_next_token`. We generate tokens until the model returns an
@@ -195,33 +477,106 @@ impl InferenceSession {
         // or we reach the specified limit.
         let mut tokens_processed = 0;
         let mut token_utf8_buf = TokenUtf8Buffer::new();
-        while tokens_processed < maximum_token_count {
-            let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
-            {
+        'generate: while tokens_processed < maximum_token_count {
+            // Only the log-probabilities are requested for each token, as the
+            // other outputs would be overwritten by the next token.
+            let mut next_token_request = OutputRequest {
+                want_logprobs: output_request.want_logprobs,
+                n_top_logprobs: output_request.n_top_logprobs,
+                ..Default::default()
+            };
+            let result = self.infer_next_token(model, parameters, &mut next_token_request, rng);
+            let n_logprobs = output_request.logprobs.len();
+            output_request
+                .logprobs
+                .append(&mut next_token_request.logprobs);
+
+            let token = match result {
                 Ok(token) => token,
                 Err(InferenceError::EndOfText) => break,
                 Err(e) => return Err(e),
             };
 
-            // Buffer the token until it's valid UTF-8, then call the callback.
-            if let Some(tokens) = token_utf8_buf.push(token) {
-                match callback(InferenceResponse::InferredToken(tokens)) {
+            // Send the log-probability of the token, if it was requested, then buffer
+            // the token until it's valid UTF-8, and send its text.
+            let logprobs = output_request.logprobs[n_logprobs..]
+                .iter()
+                .cloned()
+                .map(InferenceResponse::InferredTokenLogprob);
+            let text = token_utf8_buf
+                .push(token)
+                .map(InferenceResponse::InferredToken);
+            for response in logprobs.chain(text) {
+                match callback(response) {
                     Err(e) => return Err(InferenceError::UserCallback(Some(Box::new(e)))),
                     Ok(f) => match f {
                         InferenceFeedback::Continue => (),
-                        InferenceFeedback::Halt => break,
+                        InferenceFeedback::Halt => break 'generate,
                     },
                 }
             }
 
             tokens_processed += 1;
         }
-        stats.predict_duration = start_at.elapsed().unwrap();
-        stats.predict_tokens = self.n_past;
+        stats.predict_duration = predict_start_at.elapsed();
+        stats.predict_tokens = tokens_processed;
 
         Ok(stats)
     }
 
+    /// Prevents every token whose text does not start with the text of `token` from
+    /// being sampled next. Used for token healing by [Self::infer].
+    fn restrict_next_token_to_prefix(&mut self, vocab: &Vocabulary, token: TokenId) {
+        let prefix = vocab.token_bytes(token).unwrap_or_default();
+        for (id, logit) in self.last_logits.iter_mut().enumerate() {
+            let allowed = vocab
+                .token_bytes(id as TokenId)
+                .map_or(false, |bytes| bytes.starts_with(prefix));
+            if !allowed {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+    }
+
+    /// Generate text by using the provided [Model] to evaluate the `prompt`, and
+    /// return the generated text (excluding the prompt) as a [String].
+    ///
+    /// Generation stops at an end-of-text (EOT) token or after `maximum_token_count`
+    /// tokens have been generated. Any trailing bytes that do not form a complete
+    /// UTF-8 character by then are discarded.
+    ///
+    /// This is a wrapper around [Self::infer] for when a token callback is not needed.
+    pub fn infer_text(
+        &mut self,
+        model: &dyn Model,
+        prompt: &str,
+        params: &InferenceParameters,
+        maximum_token_count: usize,
+        rng: &mut impl rand::Rng,
+    ) -> Result<String, InferenceError> {
+        let mut text = String::new();
+        self.infer::<std::convert::Infallible>(
+            model,
+            rng,
+            &InferenceRequest {
+                prompt: prompt.into(),
+                parameters: Some(params),
+                play_back_previous_tokens: false,
+                maximum_token_count: Some(maximum_token_count),
+                token_healing: false,
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(token) = response {
+                    text.push_str(&token);
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        )?;
+
+        Ok(text)
+    }
+
     /// Calculate perplexity over a given prompt, with a value reported for each
     /// chunk that has been processed.
     ///
@@ -244,7 +599,7 @@ impl InferenceSession {
         let n_ctx = model.n_context_tokens();
         let n_chunk = tokens.len() / n_ctx;
         let n_vocab = model.vocabulary().len();
-        let n_batch = parameters.n_batch;
+        let n_batch = parameters.n_batch.max(1);
 
         let mut nll = 0.0;
 
@@ -254,6 +609,9 @@ impl InferenceSession {
 
             let num_batches = (n_ctx + n_batch - 1) / n_batch;
 
+            // Each chunk is evaluated in a fresh context.
+            self.n_past = 0;
+
             let mut logits = vec![];
 
             for j in 0..num_batches {
@@ -302,17 +660,35 @@ impl InferenceSession {
     }
 
     /// Sample a token using Top-P/Top-K sampling and the last logits from this session.
+    ///
+    /// If the temperature is zero or less, the token with the highest logit is
+    /// chosen after applying the biases and repetition penalty, and `rng` is unused.
     pub fn sample_top_p_top_k(
         &self,
         params: &InferenceParameters,
         rng: &mut impl rand::Rng,
     ) -> TokenId {
-        let logits = &self.last_logits;
+        self.sample_from_logits(&self.last_logits, params, rng)
+    }
+
+    /// Like [Self::sample_top_p_top_k], but samples from `logits` instead of the
+    /// last logits of this session.
+    fn sample_from_logits(
+        &self,
+        logits: &[f32],
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+    ) -> TokenId {
         let n_logits = logits.len();
         let mut logits_id = Vec::<(f32, TokenId)>::with_capacity(n_logits);
 
+        let greedy = params.temperature <= 0.0;
         {
-            let scale = 1.0 / params.temperature;
+            let scale = if greedy {
+                1.0
+            } else {
+                1.0 / params.temperature
+            };
             for (i, &logit) in logits.iter().enumerate() {
                 let tid = i as TokenId;
 
@@ -336,17 +712,27 @@ impl InferenceSession {
                 } else {
                     logit * scale
                 };
+                let val = val + params.logit_bias.get(&tid).copied().unwrap_or(0.0);
                 logits_id.push((val, tid));
             }
         }
 
-        // find the top K tokens
+        if greedy {
+            return logits_id
+                .into_iter()
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, tid)| tid)
+                .unwrap();
+        }
+
+        // find the top K tokens; there may be fewer than K tokens in the vocabulary
         {
-            logits_id.partial_sort(params.top_k, |a, b| {
+            let top_k = params.top_k.min(n_logits);
+            logits_id.partial_sort(top_k, |a, b| {
                 // Sort descending
                 b.0.total_cmp(&a.0)
             });
-            logits_id.truncate(params.top_k);
+            logits_id.truncate(top_k);
         }
 
         let maxl = logits_id
@@ -355,11 +741,12 @@ impl InferenceSession {
             .max_by(f32::total_cmp)
             .unwrap();
 
-        // compute probs for the top K tokens
+        // compute probs for the top K tokens; the maximum is compared directly so
+        // that tokens biased to infinity do not produce NaNs
         let mut probs: Vec<f32> = logits_id
             .iter()
             .copied()
-            .map(|(k, _)| (k - maxl).exp())
+            .map(|(k, _)| if k == maxl { 1.0 } else { (k - maxl).exp() })
             .collect();
         let sum: f32 = probs.iter().copied().sum();
 
@@ -392,6 +779,114 @@ impl InferenceSession {
         logits_id[idx].1
     }
 
+    /// Sample a token from the last logits using this session's random number
+    /// generator, which can be seeded with [InferenceSessionConfig::rng_seed] or
+    /// [InferenceSession::set_rng_seed] for reproducible results. If it was not
+    /// seeded, the token is sampled with a random number generator seeded from entropy.
+    pub fn sample(&mut self, params: &InferenceParameters) -> TokenId {
+        self.sample_next_token(None, params, &mut rand::thread_rng())
+    }
+
+    /// Samples the next token from `logits`, or from the last logits if `None`, with this
+    /// session's random number generator if it was seeded, and with `rng` otherwise.
+    fn sample_next_token(
+        &mut self,
+        logits: Option<&[f32]>,
+        params: &InferenceParameters,
+        rng: &mut impl rand::Rng,
+    ) -> TokenId {
+        let mut session_rng = self.rng.take();
+        let logits = logits.unwrap_or(&self.last_logits);
+        let token = match &mut session_rng {
+            Some(session_rng) => self.sample_from_logits(logits, params, session_rng),
+            None => self.sample_from_logits(logits, params, rng),
+        };
+        self.rng = session_rng;
+        token
+    }
+
+    /// The configuration this session was created with.
+    pub fn config(&self) -> &InferenceSessionConfig {
+        &self.config
+    }
+
+    /// The number of tokens that have been evaluated in this session, including the prompt.
+    pub fn n_tokens(&self) -> usize {
+        self.n_past
+    }
+
+    /// The number of tokens that can still be evaluated before the context is full.
+    pub fn remaining_context(&self) -> usize {
+        self.n_ctx.saturating_sub(self.n_past)
+    }
+
+    /// Reseeds this session's random number generator, which is then used for all of
+    /// its sampling. See [InferenceSessionConfig::rng_seed].
+    pub fn set_rng_seed(&mut self, seed: u64) {
+        self.rng = Some(StdRng::seed_from_u64(seed));
+    }
+
+    /// Discards the last `n` tokens of this session, so that inference continues
+    /// as if they had never been fed or inferred, and returns them.
+    ///
+    /// Their key/value memory is not cleared, but will be overwritten as new tokens
+    /// are evaluated. The token that is now last is evaluated again with `model` to
+    /// recompute the logits needed to sample the next token.
+    pub fn rewind(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        n: usize,
+    ) -> Result<Vec<TokenId>, RewindError> {
+        let available = self.n_past.min(self.tokens.len());
+        if n > available {
+            return Err(RewindError::NotEnoughTokens {
+                requested: n,
+                available,
+            });
+        }
+
+        let removed = self.tokens.split_off(self.tokens.len() - n);
+        self.n_past -= n;
+
+        match self.tokens.last() {
+            Some(&last) if self.n_past > 0 => {
+                self.n_past -= 1;
+                model.evaluate(self, params, &[last], &mut OutputRequest::default());
+            }
+            _ => self.last_logits.fill(0.0),
+        }
+
+        Ok(removed)
+    }
+
+    /// Makes room in the context by discarding half of the tokens after the first `keep`,
+    /// starting with the oldest. See [ContextOverflowPolicy::ShiftLeft].
+    ///
+    /// The remaining tokens are evaluated again at their new positions, as the positional
+    /// encodings of some models are baked into the key/value memory.
+    fn shift_context(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        keep: usize,
+    ) -> Result<(), InferenceError> {
+        let n_left = self.tokens.len().saturating_sub(keep);
+        let n_discard = n_left / 2;
+        if n_discard == 0 || self.n_past != self.tokens.len() {
+            return Err(InferenceError::ContextFull);
+        }
+
+        self.tokens.drain(keep..keep + n_discard);
+        self.n_past = keep;
+        let shifted = self.tokens[keep..].to_vec();
+        for batch in shifted.chunks(params.n_batch.max(1)) {
+            model.evaluate(self, params, batch, &mut OutputRequest::default());
+        }
+
+        Ok(())
+    }
+
     /// Obtains a serializable snapshot of the current inference status. This
     /// can be used to cache the state of the model and store them into a file.
     ///
@@ -481,20 +976,28 @@ impl InferenceSession {
         // Initialize key + value memory tensors
         let n_mem = n_layer * n_ctx;
         let n_elements = n_embd * n_mem;
-        let memory_k = session_ctx.new_tensor_1d(config.memory_k_type.into(), n_elements);
-        let memory_v = session_ctx.new_tensor_1d(config.memory_v_type.into(), n_elements);
+        let mut memory_k = session_ctx.new_tensor_1d(config.memory_k_type.into(), n_elements);
+        let mut memory_v = session_ctx.new_tensor_1d(config.memory_v_type.into(), n_elements);
+
+        // The attention masks hide any memory that hasn't been written yet, but
+        // zeroing it ensures that it never contains uninitialized data.
+        memory_k.zero_data();
+        memory_v.zero_data();
 
         InferenceSession {
             _session_ctx: session_ctx,
             memory_size: ctx_size,
             config,
+            n_ctx,
             memory_k,
             memory_v,
             n_past: 0,
             mem_per_token: 0,
             tokens: vec![],
             last_logits: vec![0.0; n_vocab],
+            rng: config.rng_seed.map(StdRng::seed_from_u64),
             scratch: scratch_buffers(),
+            cancel: None,
         }
     }
 }
@@ -508,13 +1011,16 @@ impl Clone for InferenceSession {
             _session_ctx: context,
             memory_size: self.memory_size,
             config: self.config,
+            n_ctx: self.n_ctx,
             memory_k,
             memory_v,
             n_past: self.n_past,
             mem_per_token: self.mem_per_token,
             tokens: self.tokens.clone(),
             last_logits: self.last_logits.clone(),
+            rng: self.rng.clone(),
             scratch: scratch_buffers(),
+            cancel: self.cancel.clone(),
         }
     }
 }
@@ -526,7 +1032,9 @@ pub enum InferenceError {
     /// A tokenization-related failure occurred.
     TokenizationFailed(#[from] TokenizationError),
     #[error("the context window is full")]
-    /// The context window for the model is full.
+    /// The context window for the model is full: evaluating the tokens would take the
+    /// number of evaluated tokens past [Model::n_context_tokens]. The session is left
+    /// unchanged.
     ContextFull,
     #[error("reached end of text")]
     /// The model has produced an end of text token, signalling that it thinks that the text should end here.
@@ -536,6 +1044,13 @@ pub enum InferenceError {
     #[error("the user-specified callback returned an error")]
     /// The user-specified callback returned an error.
     UserCallback(Option<Box<dyn std::error::Error>>),
+    #[error("invalid inference parameters")]
+    /// The [InferenceParameters] used for inference were invalid.
+    InvalidParameters(#[from] ParameterError),
+    #[error("inference was cancelled")]
+    /// The flag set with [InferenceSession::set_cancel_flag] was set. The tokens evaluated
+    /// before that are kept in the session.
+    Cancelled,
 }
 
 #[derive(Error, Debug)]
@@ -554,6 +1069,19 @@ pub enum SnapshotError {
     },
 }
 
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors encountered while rewinding an [InferenceSession].
+pub enum RewindError {
+    /// More tokens were requested to be discarded than have been evaluated.
+    #[error("cannot rewind {requested} tokens when only {available} have been evaluated")]
+    NotEnoughTokens {
+        /// The number of tokens that were requested to be discarded.
+        requested: usize,
+        /// The number of tokens that have been evaluated.
+        available: usize,
+    },
+}
+
 #[derive(serde::Serialize, Clone, PartialEq)]
 /// A serializable snapshot of the inference process.
 /// Can be created by calling [InferenceSession::get_snapshot].
@@ -625,16 +1153,50 @@ pub struct InferenceSessionConfig {
     pub memory_k_type: ModelKVMemoryType,
     /// The type of the memory V tensor.
     pub memory_v_type: ModelKVMemoryType,
+    /// The seed for the session's random number generator.
+    ///
+    /// If specified, every token the session samples, including those of
+    /// [InferenceSession::infer] and [InferenceSession::infer_next_token], is sampled with
+    /// this generator instead of the one that is passed in, so that a session with the
+    /// same seed, prompt and parameters reproduces the same tokens. If not specified, the
+    /// given random number generator is used, and [InferenceSession::sample] seeds one
+    /// from entropy.
+    #[serde(default)]
+    pub rng_seed: Option<u64>,
+    /// What to do when the context window is full while inferring tokens.
+    #[serde(default)]
+    pub context_overflow: ContextOverflowPolicy,
 }
 impl Default for InferenceSessionConfig {
     fn default() -> Self {
         Self {
             memory_k_type: ModelKVMemoryType::Float32,
             memory_v_type: ModelKVMemoryType::Float32,
+            rng_seed: None,
+            context_overflow: ContextOverflowPolicy::Error,
         }
     }
 }
 
+/// What an [InferenceSession] does when its context window is full and another
+/// token is inferred.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ContextOverflowPolicy {
+    /// Return [InferenceError::ContextFull].
+    #[default]
+    Error,
+    /// Keep the first `keep` tokens (e.g. the start of the prompt), discard the oldest
+    /// half of the tokens after them, and continue generating.
+    ///
+    /// The remaining tokens are evaluated again, which takes some time whenever the
+    /// context is shifted. If there is nothing to discard,
+    /// [InferenceError::ContextFull] is returned.
+    ShiftLeft {
+        /// The number of tokens at the start of the context that are never discarded.
+        keep: usize,
+    },
+}
+
 #[derive(Debug, PartialEq, Default, Clone, Copy)]
 /// Settings specific to [InferenceSession::infer].
 pub struct InferenceRequest<'a> {
@@ -652,6 +1214,13 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// Whether to hold back the last token of the prompt and constrain the first
+    /// generated token to start with its text.
+    ///
+    /// This lets a prompt that ends in the middle of a word (e.g. `"The quick bro"`)
+    /// be continued with the token the model would have used for the whole word,
+    /// instead of being forced to continue from an unusual token boundary.
+    pub token_healing: bool,
 }
 
 /// Statistics about the inference process.
@@ -661,9 +1230,9 @@ pub struct InferenceStats {
     pub feed_prompt_duration: std::time::Duration,
     /// How many tokens the prompt was.
     pub prompt_tokens: usize,
-    /// How long it took to predict new tokens.
+    /// How long it took to predict new tokens, not including feeding the prompt.
     pub predict_duration: std::time::Duration,
-    /// The number of predicted tokens.
+    /// The number of predicted tokens, not including the end-of-text token.
     pub predict_tokens: usize,
 }
 impl Default for InferenceStats {
@@ -701,6 +1270,49 @@ impl Display for InferenceStats {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+/// The throughput of a model, as measured by [KnownModel::benchmark](crate::KnownModel::benchmark).
+pub struct BenchmarkReport {
+    /// The timings that the throughput was calculated from.
+    pub stats: InferenceStats,
+    /// How many prompt tokens were evaluated per second.
+    pub prompt_tokens_per_second: f64,
+    /// How many tokens were generated per second.
+    pub predict_tokens_per_second: f64,
+}
+impl BenchmarkReport {
+    pub(crate) fn new(stats: InferenceStats) -> Self {
+        let per_second = |tokens: usize, duration: std::time::Duration| {
+            if tokens == 0 {
+                0.0
+            } else {
+                tokens as f64 / duration.as_secs_f64()
+            }
+        };
+
+        Self {
+            prompt_tokens_per_second: per_second(stats.prompt_tokens, stats.feed_prompt_duration),
+            predict_tokens_per_second: per_second(stats.predict_tokens, stats.predict_duration),
+            stats,
+        }
+    }
+}
+impl Display for BenchmarkReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", self.stats)?;
+        writeln!(
+            f,
+            "prompt_tokens_per_second: {:.2}",
+            self.prompt_tokens_per_second
+        )?;
+        write!(
+            f,
+            "predict_tokens_per_second: {:.2}",
+            self.predict_tokens_per_second
+        )
+    }
+}
+
 /// Allowed types for the model memory K/V tensors.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum ModelKVMemoryType {
@@ -727,6 +1339,11 @@ pub enum InferenceResponse {
     PromptToken(String),
     /// A token that has been generated via inference
     InferredToken(String),
+    /// The log-probability of a token that has been generated via inference, sent
+    /// before its [InferenceResponse::InferredToken] if [OutputRequest::want_logprobs]
+    /// is set. The text of a token that is not valid UTF-8 by itself is sent together
+    /// with that of the following tokens, after all of their log-probabilities.
+    InferredTokenLogprob(TokenLogprob),
     /// The inference session has generated an end-of-text token
     EotToken,
 }
@@ -752,9 +1369,750 @@ pub fn feed_prompt_callback<'a, E: std::error::Error + 'static>(
     }
 }
 
+fn token_logprob(logits: &[f32], token: TokenId, n_top: usize) -> TokenLogprob {
+    let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits
+        .iter()
+        .map(|logit| (logit - max_logit).exp())
+        .sum::<f32>()
+        .ln();
+    let logprob = |id: TokenId| logits[id as usize] - max_logit - log_sum;
+
+    let mut top_alternatives = (0..logits.len() as TokenId)
+        .map(|id| (id, logprob(id)))
+        .collect::<Vec<_>>();
+    let n_top = n_top.min(top_alternatives.len());
+    top_alternatives.partial_sort(n_top, |a, b| b.1.total_cmp(&a.1));
+    top_alternatives.truncate(n_top);
+
+    TokenLogprob {
+        token,
+        logprob: logprob(token),
+        top_alternatives,
+    }
+}
+
 fn scratch_buffers() -> [ggml::Buffer; 2] {
     [
         ggml::Buffer::new(SCRATCH_SIZE),
         ggml::Buffer::new(SCRATCH_SIZE),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::common::ScriptedModel;
+
+    #[test]
+    fn test_infer_text_until_end_of_text() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let mut session = model.start_session(Default::default());
+
+        let text = session
+            .infer_text(
+                &model,
+                "h",
+                model.inference_parameters(),
+                16,
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+        assert_eq!(text, "éi");
+    }
+
+    #[test]
+    fn test_infer_text_drops_partial_character_at_cutoff() {
+        let model = ScriptedModel::new(vec![5, 3, 4, 0]);
+        let mut session = model.start_session(Default::default());
+
+        let text = session
+            .infer_text(
+                &model,
+                "h",
+                model.inference_parameters(),
+                2,
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+        assert_eq!(text, "i");
+    }
+
+    #[test]
+    fn test_logit_bias_forces_and_bans_tokens() {
+        let model = ScriptedModel::new(vec![]);
+        let mut session = model.start_session(Default::default());
+        session.last_logits.fill(0.0);
+
+        let mut rng = rand::thread_rng();
+        let params = InferenceParameters {
+            top_k: model.vocabulary.len(),
+            top_p: 1.0,
+            logit_bias: [(2, f32::INFINITY)].into_iter().collect(),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            assert_eq!(session.sample_top_p_top_k(&params, &mut rng), 2);
+        }
+
+        let params = InferenceParameters {
+            logit_bias: [(3, f32::NEG_INFINITY)].into_iter().collect(),
+            ..params
+        };
+        for _ in 0..100 {
+            assert_ne!(session.sample_top_p_top_k(&params, &mut rng), 3);
+        }
+    }
+
+    #[test]
+    fn test_top_k_larger_than_the_vocabulary_keeps_every_token() {
+        let model = ScriptedModel::new(vec![]);
+        let mut session = model.start_session(Default::default());
+        session.last_logits.fill(0.0);
+
+        let params = InferenceParameters {
+            top_k: 40,
+            top_p: 1.0,
+            ..Default::default()
+        };
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+        let sampled = (0..200)
+            .map(|_| session.sample_top_p_top_k(&params, &mut rng))
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(sampled.len(), model.vocabulary.len());
+    }
+
+    #[test]
+    fn test_greedy_sampling_is_deterministic() {
+        let model = ScriptedModel::new(vec![]);
+        let mut session = model.start_session(Default::default());
+        session
+            .last_logits
+            .copy_from_slice(&[0.5, 1.0, 3.0, 2.5, -1.0, 0.0]);
+
+        let params = InferenceParameters {
+            temperature: 0.0,
+            // Strong enough to push token 2 below token 3 once it has been repeated.
+            repeat_penalty: 1.3,
+            ..Default::default()
+        };
+        let samples = (0..2)
+            .map(|seed| {
+                let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+                (0..10)
+                    .map(|_| session.sample_top_p_top_k(&params, &mut rng))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(samples[0], vec![2; 10]);
+        assert_eq!(samples[0], samples[1]);
+
+        // The repetition penalty and logit bias still apply before the argmax.
+        session.tokens.push(2);
+        assert_eq!(
+            session.sample_top_p_top_k(&params, &mut rand::thread_rng()),
+            3
+        );
+        let params = InferenceParameters {
+            logit_bias: [(3, -2.0)].into_iter().collect(),
+            ..params
+        };
+        assert_eq!(
+            session.sample_top_p_top_k(&params, &mut rand::thread_rng()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_seeded_sessions_sample_identically() {
+        let model = ScriptedModel::new(vec![]);
+        let params = InferenceParameters {
+            top_k: model.vocabulary.len(),
+            top_p: 1.0,
+            temperature: 1.0,
+            ..Default::default()
+        };
+        let sample = |session: &mut InferenceSession| {
+            session.last_logits.fill(0.0);
+            (0..32).map(|_| session.sample(&params)).collect::<Vec<_>>()
+        };
+
+        let config = InferenceSessionConfig {
+            rng_seed: Some(42),
+            ..Default::default()
+        };
+        let first = sample(&mut model.start_session(config));
+        let second = sample(&mut model.start_session(config));
+        assert_eq!(first, second);
+
+        let mut session = model.start_session(Default::default());
+        session.set_rng_seed(42);
+        assert_eq!(sample(&mut session), first);
+    }
+
+    #[test]
+    fn test_seeded_sessions_infer_identically() {
+        let model = ScriptedModel::new(vec![]);
+        let params = InferenceParameters {
+            top_k: model.vocabulary.len(),
+            top_p: 1.0,
+            // Flatten the scripted logits, and never end the text.
+            temperature: 100.0,
+            logit_bias: [(model.eot_token_id(), f32::NEG_INFINITY)].into(),
+            ..Default::default()
+        };
+        let infer = |session: &mut InferenceSession| {
+            session
+                .infer_text(&model, "h", &params, 32, &mut rand::thread_rng())
+                .unwrap();
+            session.tokens.clone()
+        };
+
+        let config = InferenceSessionConfig {
+            rng_seed: Some(7),
+            ..Default::default()
+        };
+        let first = infer(&mut model.start_session(config));
+        assert_eq!(first.len(), 2 + 32);
+        assert_eq!(infer(&mut model.start_session(config)), first);
+
+        let mut session = model.start_session(Default::default());
+        session.set_rng_seed(7);
+        assert_eq!(infer(&mut session), first);
+    }
+
+    #[test]
+    fn test_new_session_memory_is_zeroed() {
+        let session = InferenceSession::new(Default::default(), 4, 2, 8, 6);
+
+        for memory in [&session.memory_k, &session.memory_v] {
+            let mut bytes = vec![0xFFu8; memory.nbytes()];
+            unsafe { memory.read_data(0, &mut bytes) };
+            assert!(bytes.iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn test_logprobs_of_sampled_tokens() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let mut session = model.start_session(Default::default());
+        let logits = [0.5, 1.0, 3.0, 2.5, -1.0, 0.0];
+        session.last_logits.copy_from_slice(&logits);
+
+        let params = InferenceParameters {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        let mut output_request = OutputRequest {
+            want_logprobs: true,
+            n_top_logprobs: 2,
+            ..Default::default()
+        };
+        session
+            .infer_next_token(
+                &model,
+                &params,
+                &mut output_request,
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+
+        let probabilities = util::softmax(&logits);
+        let [logprob] = &output_request.logprobs[..] else {
+            panic!("expected one logprob, got {:?}", output_request.logprobs);
+        };
+        assert_eq!(logprob.token, 2);
+        assert!((logprob.logprob - probabilities[2].ln()).abs() < 1e-6);
+        let alternatives = logprob
+            .top_alternatives
+            .iter()
+            .map(|&(id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(alternatives, vec![2, 3]);
+        assert!((logprob.top_alternatives[1].1 - probabilities[3].ln()).abs() < 1e-6);
+
+        // `infer` records a logprob for every sampled token, including the end of text,
+        // and sends those of the generated tokens before their text.
+        let mut session = model.start_session(Default::default());
+        let mut output_request = OutputRequest {
+            want_logprobs: true,
+            ..Default::default()
+        };
+        let mut responses = vec![];
+        session
+            .infer::<std::convert::Infallible>(
+                &model,
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    prompt: "h".into(),
+                    ..Default::default()
+                },
+                &mut output_request,
+                |response| {
+                    match response {
+                        InferenceResponse::InferredTokenLogprob(logprob) => {
+                            responses.push(logprob.token.to_string())
+                        }
+                        InferenceResponse::InferredToken(text) => responses.push(text),
+                        _ => {}
+                    }
+                    Ok(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+        let tokens = output_request
+            .logprobs
+            .iter()
+            .map(|logprob| logprob.token)
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, vec![3, 4, 5, 0]);
+        assert_eq!(responses, ["3", "4", "é", "5", "i"]);
+    }
+
+    #[test]
+    fn test_token_healing_regenerates_the_last_prompt_token() {
+        let mut model = ScriptedModel::new(vec![6, 0]);
+        model.vocabulary.push_token(6, b"hi".to_vec(), 0.0);
+
+        let transcript = |token_healing: bool| {
+            let mut session = model.start_session(Default::default());
+            let mut text = String::new();
+            session
+                .infer::<std::convert::Infallible>(
+                    &model,
+                    &mut rand::thread_rng(),
+                    &InferenceRequest {
+                        prompt: "h".into(),
+                        maximum_token_count: Some(1),
+                        token_healing,
+                        ..Default::default()
+                    },
+                    &mut Default::default(),
+                    |response| {
+                        if let InferenceResponse::PromptToken(t)
+                        | InferenceResponse::InferredToken(t) = response
+                        {
+                            text.push_str(&t);
+                        }
+                        Ok(InferenceFeedback::Continue)
+                    },
+                )
+                .unwrap();
+            (text, session.tokens)
+        };
+
+        // Without healing, "hi" is generated after the prompt's "h".
+        assert_eq!(transcript(false), ("hhi".to_string(), vec![1, 2, 6]));
+        // With healing, "h" is held back and "hi" is generated in its place.
+        assert_eq!(transcript(true), ("hi".to_string(), vec![1, 6]));
+    }
+
+    #[test]
+    fn test_rewind_regenerates_the_same_continuation() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let params = model.inference_parameters();
+        let mut session = model.start_session(Default::default());
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(42);
+
+        let text = session
+            .infer_text(&model, "h", params, 16, &mut rng)
+            .unwrap();
+        assert_eq!(text, "éi");
+        assert_eq!(session.tokens, vec![1, 2, 3, 4, 5, 0]);
+
+        let removed = session.rewind(&model, params, 3).unwrap();
+        assert_eq!(removed, vec![4, 5, 0]);
+        assert_eq!(session.n_past, 3);
+
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(42);
+        let mut regenerated = vec![];
+        loop {
+            let result =
+                session.infer_next_token(&model, params, &mut Default::default(), &mut rng);
+            regenerated.push(*session.tokens.last().unwrap());
+            match result {
+                Ok(_) => {}
+                Err(InferenceError::EndOfText) => break,
+                Err(e) => panic!("unexpected error: {e}"),
+            }
+        }
+        assert_eq!(regenerated, removed);
+
+        assert_eq!(
+            session.rewind(&model, params, 7),
+            Err(RewindError::NotEnoughTokens {
+                requested: 7,
+                available: 6
+            })
+        );
+    }
+
+    #[test]
+    fn test_guided_inference_advances_both_sessions() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let params = model.inference_parameters();
+        let mut session = model.start_session(Default::default());
+        let mut negative = model.start_session(Default::default());
+        for session in [&mut session, &mut negative] {
+            session
+                .feed_prompt(&model, params, "h", &mut Default::default(), |_| {
+                    Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+                })
+                .unwrap();
+        }
+
+        let token = session
+            .infer_next_token_with_guidance(
+                &model,
+                &mut negative,
+                1.5,
+                params,
+                &mut Default::default(),
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+        assert_eq!(token, [0xC3]);
+        assert_eq!(session.tokens, vec![1, 2, 3]);
+        assert_eq!(negative.tokens, session.tokens);
+        assert_eq!(negative.n_past, session.n_past);
+
+        // A full context in either session, or a cancellation, leaves both untouched.
+        let infer = |session: &mut InferenceSession, negative: &mut InferenceSession| {
+            session.infer_next_token_with_guidance(
+                &model,
+                negative,
+                1.5,
+                params,
+                &mut Default::default(),
+                &mut rand::thread_rng(),
+            )
+        };
+        let cancel = Arc::new(AtomicBool::new(true));
+        for (full, cancelled) in [(true, false), (false, true)] {
+            for negative_is_affected in [false, true] {
+                let (mut session, mut negative) = (session.clone(), negative.clone());
+                let affected = if negative_is_affected {
+                    &mut negative
+                } else {
+                    &mut session
+                };
+                if full {
+                    affected.n_past = model.n_context_tokens();
+                }
+                if cancelled {
+                    affected.set_cancel_flag(Some(cancel.clone()));
+                }
+                let before = (session.clone(), negative.clone());
+
+                let result = infer(&mut session, &mut negative);
+                if full {
+                    assert!(matches!(result, Err(InferenceError::ContextFull)));
+                } else {
+                    assert!(matches!(result, Err(InferenceError::Cancelled)));
+                }
+                for (session, before) in [(&session, &before.0), (&negative, &before.1)] {
+                    assert_eq!(session.tokens, before.tokens);
+                    assert_eq!(session.n_past, before.n_past);
+                    assert_eq!(session.last_logits, before.last_logits);
+                }
+            }
+        }
+
+        // Both sessions advance with the token that was sampled, up to the end of the text.
+        assert_eq!(infer(&mut session, &mut negative).unwrap(), [0xA9]);
+        assert_eq!(infer(&mut session, &mut negative).unwrap(), b"i");
+        assert!(matches!(
+            infer(&mut session, &mut negative),
+            Err(InferenceError::EndOfText)
+        ));
+        assert_eq!(session.tokens, vec![1, 2, 3, 4, 5, 0]);
+        assert_eq!(negative.tokens, session.tokens);
+        assert_eq!(negative.n_past, session.n_past);
+    }
+
+    #[test]
+    fn test_feed_prompt_tokens_matches_text() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let params = InferenceParameters {
+            n_batch: 1,
+            ..model.inference_parameters().clone()
+        };
+        let callback = |_: &[u8]| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue);
+
+        let mut from_text = model.start_session(Default::default());
+        from_text
+            .feed_prompt(&model, &params, "hi", &mut Default::default(), callback)
+            .unwrap();
+
+        let mut from_tokens = model.start_session(Default::default());
+        from_tokens
+            .feed_prompt_tokens(
+                &model,
+                &params,
+                &[1, 2, 5],
+                &mut Default::default(),
+                callback,
+            )
+            .unwrap();
+
+        assert_eq!(from_tokens.tokens, vec![1, 2, 5]);
+        assert_eq!(from_tokens.tokens, from_text.tokens);
+        assert_eq!(from_tokens.n_past, from_text.n_past);
+        assert_eq!(from_tokens.last_logits, from_text.last_logits);
+
+        // Ids outside of the vocabulary are rejected before anything is evaluated.
+        let mut invalid = model.start_session(Default::default());
+        let result = invalid.feed_prompt_tokens(
+            &model,
+            &params,
+            &[1, 99],
+            &mut Default::default(),
+            callback,
+        );
+        assert!(matches!(
+            result,
+            Err(InferenceError::TokenizationFailed(
+                TokenizationError::InvalidTokenId(99)
+            ))
+        ));
+        assert_eq!(invalid.n_past, 0);
+    }
+
+    #[test]
+    fn test_context_budget_is_reported() {
+        let model = ScriptedModel::new(vec![]);
+        let params = model.inference_parameters();
+        let n_ctx = model.n_context_tokens();
+
+        let mut session = model.start_session(Default::default());
+        assert_eq!(session.n_tokens(), 0);
+        assert_eq!(session.remaining_context(), n_ctx);
+
+        // The beginning-of-text token is counted as well.
+        session
+            .feed_prompt(&model, params, "hhhh", &mut Default::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })
+            .unwrap();
+        assert_eq!(session.n_tokens(), 5);
+        assert_eq!(session.remaining_context(), n_ctx - 5);
+    }
+
+    #[test]
+    fn test_context_full_is_reported_instead_of_overflowing() {
+        let model = ScriptedModel::new(vec![]);
+        let params = model.inference_parameters();
+        let n_ctx = model.n_context_tokens();
+        let callback = |_: &[u8]| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue);
+
+        // The beginning-of-text token takes up one position in the context.
+        let mut session = model.start_session(Default::default());
+        let too_long = "h".repeat(n_ctx);
+        let result = session.feed_prompt(
+            &model,
+            params,
+            too_long.as_str(),
+            &mut Default::default(),
+            callback,
+        );
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+        assert_eq!(session.n_past, 0);
+        assert!(session.tokens.is_empty());
+
+        // A prompt that exactly fills the context is accepted...
+        let exact = "h".repeat(n_ctx - 1);
+        session
+            .feed_prompt(
+                &model,
+                params,
+                exact.as_str(),
+                &mut Default::default(),
+                callback,
+            )
+            .unwrap();
+        assert_eq!(session.n_past, n_ctx);
+
+        // ...but no further tokens can be inferred.
+        let result = session.infer_next_token(
+            &model,
+            params,
+            &mut Default::default(),
+            &mut rand::thread_rng(),
+        );
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+        assert_eq!(session.n_past, n_ctx);
+    }
+
+    #[test]
+    fn test_shift_left_continues_generating_past_the_context() {
+        let model = ScriptedModel::new(vec![5; 200]);
+        let params = model.inference_parameters();
+        let n_ctx = model.n_context_tokens();
+        let config = InferenceSessionConfig {
+            context_overflow: ContextOverflowPolicy::ShiftLeft { keep: 2 },
+            ..Default::default()
+        };
+        let mut session = model.start_session(config);
+        session
+            .feed_prompt(&model, params, "h", &mut Default::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })
+            .unwrap();
+
+        let mut rng = rand::thread_rng();
+        for _ in 0..2 * n_ctx {
+            let token = session
+                .infer_next_token(&model, params, &mut Default::default(), &mut rng)
+                .unwrap();
+            assert_eq!(token, b"i");
+            assert!(session.n_past <= n_ctx);
+            assert_eq!(session.n_past, session.tokens.len());
+        }
+        // The kept tokens are never discarded.
+        assert_eq!(session.tokens[..2], [1, 2]);
+
+        // Without a policy, the same session would have stopped.
+        let mut session = model.start_session(Default::default());
+        session.tokens = vec![2; n_ctx];
+        session.n_past = n_ctx;
+        let result = session.infer_next_token(&model, params, &mut Default::default(), &mut rng);
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+    }
+
+    #[test]
+    fn test_infer_rejects_invalid_parameters() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let mut session = model.start_session(Default::default());
+        let parameters = InferenceParameters {
+            temperature: -1.0,
+            ..model.inference_parameters.clone()
+        };
+
+        let result = session.infer::<std::convert::Infallible>(
+            &model,
+            &mut rand::thread_rng(),
+            &InferenceRequest {
+                prompt: "h".into(),
+                parameters: Some(&parameters),
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |_| Ok(InferenceFeedback::Continue),
+        );
+        assert!(matches!(
+            result,
+            Err(InferenceError::InvalidParameters(
+                ParameterError::InvalidTemperature(_)
+            ))
+        ));
+        assert_eq!(session.n_past, 0);
+    }
+
+    #[test]
+    fn test_infer_reports_stats() {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let mut session = model.start_session(Default::default());
+
+        let mut inferred_tokens = 0;
+        let stats = session
+            .infer::<std::convert::Infallible>(
+                &model,
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    prompt: "h".into(),
+                    ..Default::default()
+                },
+                &mut Default::default(),
+                |response| {
+                    if let InferenceResponse::InferredToken(_) = response {
+                        inferred_tokens += 1;
+                    }
+                    Ok(InferenceFeedback::Continue)
+                },
+            )
+            .unwrap();
+
+        // The prompt is the beginning-of-text token and "h".
+        assert_eq!(stats.prompt_tokens, 2);
+        // "é" is made up of two tokens, but is only reported once.
+        assert_eq!(stats.predict_tokens, 3);
+        assert_eq!(inferred_tokens, 2);
+        assert!(stats.feed_prompt_duration + stats.predict_duration > std::time::Duration::ZERO);
+    }
+
+    #[test]
+    fn test_cancelling_stops_generation_partway_through() {
+        let model = ScriptedModel::new(vec![5; 16]);
+        let mut session = model.start_session(Default::default());
+        let cancel = Arc::new(AtomicBool::new(false));
+        session.set_cancel_flag(Some(cancel.clone()));
+
+        // Cancel from another thread once the third token has been generated.
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let canceller = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || {
+                for _ in 0..3 {
+                    receiver.recv().unwrap();
+                }
+                cancel.store(true, Ordering::SeqCst);
+            }
+        });
+
+        let mut inferred_tokens = 0;
+        let result = session.infer::<std::convert::Infallible>(
+            &model,
+            &mut rand::thread_rng(),
+            &InferenceRequest {
+                prompt: "h".into(),
+                ..Default::default()
+            },
+            &mut Default::default(),
+            |response| {
+                if let InferenceResponse::InferredToken(_) = response {
+                    inferred_tokens += 1;
+                    sender.send(()).unwrap();
+                    if inferred_tokens == 3 {
+                        // Wait for the flag, so that the next token is not raced against it.
+                        while !cancel.load(Ordering::SeqCst) {
+                            std::thread::yield_now();
+                        }
+                    }
+                }
+                Ok(InferenceFeedback::Continue)
+            },
+        );
+        canceller.join().unwrap();
+
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        assert_eq!(inferred_tokens, 3);
+        // The prompt and the generated tokens were all evaluated, and nothing else.
+        assert_eq!(session.tokens, [1, 2, 5, 5, 5]);
+        assert_eq!(session.n_past, session.tokens.len());
+
+        // Once the flag is cleared, the session carries on from where it stopped.
+        cancel.store(false, Ordering::SeqCst);
+        let token = session
+            .infer_next_token(
+                &model,
+                model.inference_parameters(),
+                &mut Default::default(),
+                &mut rand::thread_rng(),
+            )
+            .unwrap();
+        assert_eq!(token, b"i");
+        assert_eq!(session.n_past, 6);
+
+        // Prompts are not fed while the flag is set either.
+        cancel.store(true, Ordering::SeqCst);
+        let result = session.feed_prompt::<std::convert::Infallible, _>(
+            &model,
+            model.inference_parameters(),
+            "hi",
+            &mut Default::default(),
+            |_| Ok(InferenceFeedback::Continue),
+        );
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        assert_eq!(session.n_past, 6);
+    }
+}
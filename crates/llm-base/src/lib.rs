@@ -7,9 +7,15 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+mod gguf;
 mod inference_session;
 mod loader;
 mod lora;
+mod prompt_template;
 mod quantize;
 mod vocabulary;
 
@@ -19,24 +25,30 @@ pub mod util;
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+pub use gguf::{GgufMetadata, GgufMetadataBuilder, GgufMetadataValue, FILE_TYPE_KEY};
+
 pub use inference_session::{
-    feed_prompt_callback, InferenceError, InferenceFeedback, InferenceRequest, InferenceResponse,
-    InferenceSession, InferenceSessionConfig, InferenceSnapshot, InferenceStats, ModelKVMemoryType,
+    feed_prompt_callback, BenchmarkReport, ContextOverflowPolicy, InferenceError,
+    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
+    InferenceSessionConfig, InferenceSnapshot, InferenceStats, ModelKVMemoryType, RewindError,
     SnapshotError,
 };
 pub use loader::{
     load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, LoadError,
-    LoadProgress, Loader, TensorLoader,
+    LoadProgress, Loader, ModelLoadError, StreamingTensorLoader, TensorLoader,
 };
 pub use lora::{LoraAdapter, LoraParameters};
 pub use memmap2::Mmap;
 pub use model::{
     Hyperparameters, KnownModel, Model, ModelDynamicOverrideValue, ModelDynamicOverrides,
-    ModelParameters, OutputRequest,
+    ModelParameters, ModelParametersBuilder, OutputRequest, TokenLogprob,
 };
-pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use prompt_template::{ChatMessage, ChatRole, PromptTemplate, PromptTemplateError};
+pub use quantize::{quantize, QuantizeError, QuantizeProgress, QuantizingTensorLoader};
 pub use util::TokenUtf8Buffer;
-pub use vocabulary::{InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Vocabulary};
+pub use vocabulary::{
+    InvalidTokenBias, Prompt, TokenBias, TokenId, TokenType, TokenizationError, Vocabulary,
+};
 
 #[derive(Clone, Debug, PartialEq)]
 /// The parameters for text generation.
@@ -45,36 +57,212 @@ pub use vocabulary::{InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationE
 /// but can be changed between calls.
 pub struct InferenceParameters {
     /// The number of threads to use.
+    ///
+    /// Defaults to the available parallelism of the machine, as evaluation is
+    /// compute-bound and scales with the number of cores.
     pub n_threads: usize,
     /// Controls batch/chunk size for prompt ingestion in
     /// [InferenceSession::feed_prompt].
+    ///
+    /// The prompt is split into batches of up to `n_batch` tokens, and each batch
+    /// is evaluated with a single call to [Model::evaluate]; the final batch may
+    /// be smaller. A value of 0 is treated as 1.
+    ///
+    /// Defaults to 8, which keeps the temporary memory needed for each batch small.
     pub n_batch: usize,
     /// The top K words by score are kept during sampling.
+    ///
+    /// Defaults to 40, which discards the long tail of unlikely tokens while still
+    /// leaving enough candidates for varied output.
     pub top_k: usize,
     /// The cumulative probability after which no more words are kept for sampling.
+    ///
+    /// Defaults to 0.95, which only removes the least likely 5% of the probability mass.
     pub top_p: f32,
     /// The penalty for repeating tokens. Higher values make the generation less
     /// likely to get into a loop, but may harm results when repetitive outputs
     /// are desired.
+    ///
+    /// Defaults to 1.1, which discourages loops without noticeably distorting the text.
     pub repeat_penalty: f32,
     /// Temperature (randomness) used for sampling. A higher number is more random.
+    ///
+    /// A temperature of zero selects the most likely token every time.
+    ///
+    /// Defaults to 0.8, which makes the output slightly more focused than the
+    /// model's own distribution.
     pub temperature: f32,
     /// A list of tokens to bias against in the process of generation.
     pub bias_tokens: TokenBias,
+    /// Biases added to the logits of specific tokens before sampling.
+    ///
+    /// Unlike [Self::bias_tokens], which replaces the logit of a token, these are
+    /// added after the repetition penalty and temperature have been applied. A
+    /// bias of [f32::INFINITY] forces a token to be sampled, and a bias of
+    /// [f32::NEG_INFINITY] prevents it from being sampled.
+    pub logit_bias: HashMap<TokenId, f32>,
     /// The number of tokens to consider for the repetition penalty.
+    ///
+    /// Defaults to 512, so that repetitions are penalized over a long stretch of text.
     pub repetition_penalty_last_n: usize,
 }
 impl Default for InferenceParameters {
     fn default() -> Self {
         Self {
-            n_threads: 8,
+            n_threads: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(8),
             n_batch: 8,
             top_k: 40,
             top_p: 0.95,
-            repeat_penalty: 1.30,
+            repeat_penalty: 1.1,
             temperature: 0.80,
             bias_tokens: TokenBias::default(),
+            logit_bias: HashMap::default(),
             repetition_penalty_last_n: 512,
         }
     }
 }
+impl InferenceParameters {
+    /// Checks that these parameters make sense for a model with `n_vocab` tokens,
+    /// returning the first problem found.
+    ///
+    /// This is called by [InferenceSession::infer]; callers that sample directly
+    /// may want to call it themselves.
+    pub fn validate(&self, n_vocab: usize) -> Result<(), ParameterError> {
+        if self.n_threads == 0 {
+            return Err(ParameterError::NoThreads);
+        }
+        if self.top_k == 0 || self.top_k > n_vocab {
+            return Err(ParameterError::TopKOutOfRange {
+                top_k: self.top_k,
+                n_vocab,
+            });
+        }
+        if !(self.top_p > 0.0 && self.top_p <= 1.0) {
+            return Err(ParameterError::TopPOutOfRange(self.top_p));
+        }
+        if !(self.repeat_penalty.is_finite() && self.repeat_penalty > 0.0) {
+            return Err(ParameterError::InvalidRepeatPenalty(self.repeat_penalty));
+        }
+        if !(self.temperature.is_finite() && self.temperature >= 0.0) {
+            return Err(ParameterError::InvalidTemperature(self.temperature));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+/// Errors encountered when validating [InferenceParameters].
+pub enum ParameterError {
+    /// [InferenceParameters::n_threads] was zero.
+    #[error("n_threads must be at least 1")]
+    NoThreads,
+    /// [InferenceParameters::top_k] was zero or larger than the vocabulary.
+    #[error("top_k must be between 1 and the vocabulary size of {n_vocab}, but was {top_k}")]
+    TopKOutOfRange {
+        /// The requested top K.
+        top_k: usize,
+        /// The number of tokens in the model's vocabulary.
+        n_vocab: usize,
+    },
+    /// [InferenceParameters::top_p] was not in `(0, 1]`.
+    #[error("top_p must be greater than 0 and at most 1, but was {0}")]
+    TopPOutOfRange(f32),
+    /// [InferenceParameters::repeat_penalty] was not a positive number.
+    #[error("repeat_penalty must be a positive number, but was {0}")]
+    InvalidRepeatPenalty(f32),
+    /// [InferenceParameters::temperature] was negative or not finite.
+    #[error("temperature must be zero or a positive number, but was {0}")]
+    InvalidTemperature(f32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inference_parameters_defaults() {
+        let params = InferenceParameters::default();
+        assert!(params.n_threads > 0);
+        assert_eq!(params.n_batch, 8);
+        assert_eq!(params.top_k, 40);
+        assert_eq!(params.top_p, 0.95);
+        assert_eq!(params.repeat_penalty, 1.1);
+        assert_eq!(params.temperature, 0.8);
+        assert_eq!(params.bias_tokens, TokenBias::default());
+        assert!(params.logit_bias.is_empty());
+        assert_eq!(params.repetition_penalty_last_n, 512);
+    }
+
+    #[test]
+    fn test_model_inference_parameters_can_be_overridden() {
+        let model_params = ModelParameters {
+            inference_parameters: InferenceParameters {
+                top_k: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let params = InferenceParameters {
+            temperature: 0.2,
+            ..model_params.inference_parameters.clone()
+        };
+        assert_eq!(params.temperature, 0.2);
+        assert_eq!(params.top_k, 10);
+        assert_eq!(params.top_p, InferenceParameters::default().top_p);
+    }
+
+    #[test]
+    fn test_inference_parameters_validation() {
+        let n_vocab = 100;
+        assert_eq!(InferenceParameters::default().validate(n_vocab), Ok(()));
+
+        let params = InferenceParameters {
+            top_k: 0,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(n_vocab),
+            Err(ParameterError::TopKOutOfRange { top_k: 0, n_vocab })
+        );
+
+        let params = InferenceParameters {
+            top_k: n_vocab + 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(n_vocab),
+            Err(ParameterError::TopKOutOfRange {
+                top_k: n_vocab + 1,
+                n_vocab
+            })
+        );
+
+        let params = InferenceParameters {
+            temperature: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(n_vocab),
+            Err(ParameterError::InvalidTemperature(-1.0))
+        );
+
+        let params = InferenceParameters {
+            repeat_penalty: -1.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            params.validate(n_vocab),
+            Err(ParameterError::InvalidRepeatPenalty(-1.0))
+        );
+
+        // Greedy sampling is still allowed.
+        let params = InferenceParameters {
+            temperature: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(params.validate(n_vocab), Ok(()));
+    }
+}
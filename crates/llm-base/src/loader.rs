@@ -7,8 +7,8 @@ use std::{
 };
 
 use crate::{
-    util, Hyperparameters, KnownModel, LoraAdapter, LoraParameters, ModelParameters, TokenId,
-    Vocabulary,
+    gguf::GgufMetadataValue, util, Hyperparameters, KnownModel, LoraAdapter, ModelParameters,
+    TokenId, Vocabulary,
 };
 pub use ggml::ContainerType;
 use ggml::{
@@ -26,6 +26,18 @@ pub struct FileType {
     /// The quantization version.
     pub quantization_version: u32,
 }
+impl FileType {
+    /// Creates a [FileType] for a model whose tensors are mostly of type `element_type`,
+    /// quantized with the given `quantization_version`.
+    ///
+    /// Returns `None` if there is no [FileTypeFormat] for `element_type`.
+    pub fn from_type(element_type: ggml::Type, quantization_version: u32) -> Option<Self> {
+        Some(Self {
+            format: element_type.try_into().ok()?,
+            quantization_version,
+        })
+    }
+}
 impl From<FileType> for i32 {
     fn from(value: FileType) -> Self {
         (value.quantization_version * ggml::QNT_VERSION_FACTOR) as i32
@@ -79,7 +91,7 @@ impl Display for FileType {
             FileTypeFormat::MostlyQ5_1 => write!(f, "q5_1"),
         }?;
 
-        write!(f, "_qnt{}", self.quantization_version)?;
+        write!(f, " (qnt v{})", self.quantization_version)?;
 
         Ok(())
     }
@@ -160,6 +172,16 @@ pub enum LoadProgress {
         /// The number of tensors in the part.
         tensor_count: usize,
     },
+    /// The file type declared in the hyperparameters does not match the type of the
+    /// model's weights, which suggests that the file is mislabeled or corrupted.
+    ///
+    /// Loading continues, as the weights are read according to their own types.
+    FileTypeMismatch {
+        /// The file type declared in the hyperparameters.
+        file_type: FileType,
+        /// The type of most of the weights in the file.
+        weight_type: ggml::Type,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -272,15 +294,67 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
-    /// Multiple parts of the model were found.
-    ///
-    /// Multi-part models are not supported. Please convert the model to a single part.
-    #[error("multipart models are not supported")]
-    MultipartNotSupported {
-        /// The paths that were found.
-        paths: Vec<PathBuf>,
+    /// The model could not be constructed from the tensors in the file.
+    #[error("could not construct the model from {path:?}")]
+    ModelLoadFailed {
+        /// The path that failed.
+        path: PathBuf,
+        /// The original error.
+        source: ModelLoadError,
+    },
+    /// The hyperparameters of this model cannot be read from GGUF metadata.
+    #[error("the model does not support reading hyperparameters from GGUF metadata")]
+    GgufUnsupported,
+    /// A required GGUF metadata key was missing.
+    #[error("the GGUF metadata key `{key}` is missing")]
+    MissingMetadata {
+        /// The key that was missing.
+        key: String,
+    },
+    /// A GGUF metadata value did not have the expected type.
+    #[error("the GGUF metadata key `{key}` should be of type {expected}, but was {value:?}")]
+    InvalidMetadataType {
+        /// The key of the value.
+        key: String,
+        /// The type that was expected.
+        expected: &'static str,
+        /// The value that was encountered.
+        value: GgufMetadataValue,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while constructing a model from the tensors provided by a [TensorLoader].
+pub enum ModelLoadError {
+    #[error("the tensor `{name}` is missing from the model")]
+    /// A tensor required by the model was not present in the file.
+    MissingTensor {
+        /// The name of the tensor.
+        name: String,
+    },
+    #[error("the tensor `{name}` has the shape {actual:?}, but {expected:?} was expected")]
+    /// A tensor did not have the shape that the model expected.
+    UnexpectedShape {
+        /// The name of the tensor.
+        name: String,
+        /// The shape the model expected.
+        expected: Vec<usize>,
+        /// The shape found in the file.
+        actual: Vec<usize>,
+    },
+    #[error("unsupported file type {0}")]
+    /// The model does not support the file type of the loaded file.
+    UnsupportedFileType(FileType),
+    #[error("could not load the tensor `{name}`")]
+    /// The tensor was present, but could not be loaded.
+    TensorLoadFailed {
+        /// The name of the tensor.
+        name: String,
+        /// The original error.
+        source: Box<LoadError>,
     },
 }
+
 impl From<util::FindAllModelFilesError> for LoadError {
     fn from(value: util::FindAllModelFilesError) -> Self {
         match value {
@@ -320,9 +394,36 @@ impl LoadError {
 }
 
 /// Used by models to fetch tensors from a loader.
-pub trait TensorLoader<E: std::error::Error> {
+pub trait TensorLoader {
     /// Gets a tensor from the loader.
-    fn load(&mut self, name: &str) -> Result<ggml::Tensor, E>;
+    fn load(&mut self, name: &str) -> Result<ggml::Tensor, ModelLoadError>;
+    /// Gets a tensor from the loader, and checks that its dimensions match `expected`.
+    ///
+    /// Trailing dimensions of size 1 are ignored, so `[n_embd]` and `[n_embd, 1]` are equivalent.
+    fn load_with_shape(
+        &mut self,
+        name: &str,
+        expected: &[usize],
+    ) -> Result<ggml::Tensor, ModelLoadError> {
+        fn trim(dims: &[usize]) -> Vec<usize> {
+            let len = dims.iter().rposition(|&d| d != 1).map_or(1, |i| i + 1);
+            dims[..len.min(dims.len())].to_vec()
+        }
+
+        let tensor = self.load(name)?;
+        let actual = tensor
+            .get_ne()
+            .map(|d| usize::try_from(d).expect("tensor dimensions are positive"));
+        if trim(&actual) != trim(expected) {
+            return Err(ModelLoadError::UnexpectedShape {
+                name: name.to_owned(),
+                expected: expected.to_vec(),
+                actual: trim(&actual),
+            });
+        }
+
+        Ok(tensor)
+    }
     /// Finish loading the model, and extract all of the state from the loader.
     fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>);
 }
@@ -330,8 +431,11 @@ pub trait TensorLoader<E: std::error::Error> {
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
-/// Note that the model must be a single-part model, and the model in `path`
-/// *must* match the architecture of `M`.
+/// If the model has been split into multiple parts (`model.bin`, `model.bin.1`, ...),
+/// all of the parts will be loaded and their tensors reassembled. Multi-part models
+/// are always read into memory, regardless of [ModelParameters::prefer_mmap].
+///
+/// Note that the model in `path` *must* match the architecture of `M`.
 ///
 /// # Panics
 ///
@@ -354,8 +458,8 @@ pub fn load<M: KnownModel>(
     }
 
     let paths = util::find_all_model_files(path)?;
-    if paths.len() != 1 {
-        return Err(LoadError::MultipartNotSupported { paths });
+    if paths.len() > 1 {
+        return load_multipart(&paths, params, overrides, load_progress_callback);
     }
 
     let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
@@ -364,7 +468,7 @@ pub fn load<M: KnownModel>(
     })?;
     let mut reader = BufReader::new(&file);
 
-    let mut loader = Loader::new(load_progress_callback);
+    let mut loader: Loader<M::Hyperparameters, _> = Loader::new(load_progress_callback);
 
     ggml::format::load(&mut reader, &mut loader)
         .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
@@ -378,28 +482,12 @@ pub fn load<M: KnownModel>(
         ..
     } = loader;
 
-    let quantization_version = (&hyperparameters as &M::Hyperparameters)
-        .file_type()
-        .map(|ft| ft.quantization_version)
-        .unwrap_or_default();
-    let quantization_version = if quantization_version == 0 {
-        // HACK: version 2 of the GGJT format changed the quantization algorithm,
-        // but two days after version 2, the quantization version mechanism was
-        // added. To work around this, we assume the quantization version if
-        // it's a version 2 model with a quantization version of 0.
-        if container_type == ggml::ContainerType::Ggjt(2) {
-            1
-        } else {
-            quantization_version
-        }
-    } else {
-        quantization_version
-    };
-
-    // TODO: this is temporary while we figure out how to handle this
-    if tensors.values().any(|t| t.element_type.is_quantized()) {
-        assert_eq!(quantization_version, 1, "quantization version must be 1");
-    }
+    check_quantization_version(&hyperparameters, container_type, &tensors);
+    check_file_type(
+        hyperparameters.file_type(),
+        &tensors,
+        &mut load_progress_callback,
+    );
 
     let use_mmap =
         params.prefer_mmap && container_type.support_mmap() && params.lora_adapters.is_none();
@@ -409,42 +497,7 @@ pub fn load<M: KnownModel>(
         .map(|ti| ti.calc_absolute_size(use_mmap))
         .sum::<usize>();
 
-    let mut lora_adapters: Option<Vec<LoraAdapter>> = None;
-    if let Some(lora_paths) = &params.lora_adapters {
-        let adapters: Result<Vec<_>, _> = lora_paths
-            .iter()
-            .map(|lora_path| {
-                // Read the LoRA file
-                let lora_file = File::open(lora_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: lora_path.to_owned(),
-                })?;
-                let mut lora_reader = BufReader::new(&lora_file);
-                // TODO: Consider updating the progress callback to report the progress of the LoRA file.
-                // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
-                let mut lora_loader: Loader<LoraParameters, _> = Loader::new(|_| {});
-                ggml::format::load(&mut lora_reader, &mut lora_loader)
-                    .map_err(|err| LoadError::from_format_error(err, lora_path.to_owned()))?;
-
-                // Collect the names of the tensors that should be patched
-                let tensors_to_patch = lora_loader
-                    .tensors
-                    .keys()
-                    .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
-                    .collect();
-
-                // Return the LoRA patches
-                Ok::<_, LoadError>(LoraAdapter {
-                    scaling: lora_loader.hyperparameters.calculate_scaling(),
-                    tensors: lora_loader.tensors,
-                    tensors_to_patch,
-                    file: lora_file,
-                    path: lora_path.to_owned(),
-                })
-            })
-            .collect();
-        lora_adapters = Some(adapters?);
-    }
+    let lora_adapters = load_lora_adapters(&params, &tensors)?;
 
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
     let context = Context::init(ctx_size, !use_mmap);
@@ -471,7 +524,162 @@ pub fn load<M: KnownModel>(
         loaded_tensors: Default::default(),
     };
 
-    let model = KnownModel::new(hyperparameters, params, overrides, vocabulary, tl)?;
+    let model =
+        KnownModel::new(hyperparameters, params, overrides, vocabulary, tl).map_err(|source| {
+            LoadError::ModelLoadFailed {
+                path: path.to_owned(),
+                source,
+            }
+        })?;
+
+    (load_progress_callback)(LoadProgress::Loaded {
+        file_size,
+        tensor_count: tensors_len,
+    });
+
+    Ok(model)
+}
+
+/// Loads a model that has been split into multiple parts.
+///
+/// Every part must have the same container type, hyperparameters and vocabulary.
+/// Each tensor is reassembled by concatenating its parts along the dimension
+/// specified by [KnownModel::split_dimension].
+fn load_multipart<M: KnownModel>(
+    paths: &[PathBuf],
+    params: ModelParameters,
+    overrides: Option<M::Overrides>,
+    mut load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let main_path = &paths[0];
+
+    let mut parts = vec![];
+    let mut header = None;
+    for part_path in paths {
+        let file = File::open(part_path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: part_path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+
+        let mut loader: Loader<M::Hyperparameters, _> = Loader::new(&mut load_progress_callback);
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, part_path.to_owned()))?;
+
+        let Loader {
+            hyperparameters,
+            vocabulary,
+            tensors,
+            container_type,
+            ..
+        } = loader;
+
+        match &header {
+            None => header = Some((container_type, hyperparameters, vocabulary)),
+            Some((main_container_type, main_hyperparameters, main_vocabulary)) => {
+                if container_type != *main_container_type
+                    || hyperparameters != *main_hyperparameters
+                    || vocabulary.id_to_token != main_vocabulary.id_to_token
+                {
+                    return Err(LoadError::InvariantBroken {
+                        path: Some(part_path.to_owned()),
+                        invariant: format!(
+                            "the header of {part_path:?} should match the header of {main_path:?}"
+                        ),
+                    });
+                }
+            }
+        }
+
+        parts.push(ModelPart {
+            path: part_path.to_owned(),
+            file,
+            tensors,
+        });
+    }
+    let (container_type, hyperparameters, vocabulary) = header.expect("there is at least one part");
+
+    // Work out the shape of every reassembled tensor.
+    let mut tensors = HashMap::new();
+    for (name, info) in &parts[0].tensors {
+        let split_dimension = M::split_dimension(name, info.n_dims);
+
+        let mut merged = TensorLoadInfo {
+            start_offset: 0,
+            ..info.clone()
+        };
+        for part in &parts[1..] {
+            let part_info = part.tensors.get(name).filter(|part_info| {
+                part_info.n_dims == info.n_dims && part_info.element_type == info.element_type
+            });
+            let Some(part_info) = part_info else {
+                return Err(LoadError::InvariantBroken {
+                    path: Some(part.path.to_owned()),
+                    invariant: format!("the tensor {name} should be present in every part"),
+                });
+            };
+
+            for (dim, (merged_size, &part_size)) in
+                merged.dims.iter_mut().zip(&part_info.dims).enumerate()
+            {
+                if Some(dim) == split_dimension {
+                    *merged_size += part_size;
+                } else if *merged_size != part_size {
+                    return Err(LoadError::TensorWrongSize {
+                        tensor_name: name.to_owned(),
+                        path: part.path.to_owned(),
+                    });
+                }
+            }
+        }
+        merged.n_elements = merged.dims().iter().product();
+
+        tensors.insert(name.to_owned(), (merged, split_dimension));
+    }
+
+    let merged_tensors: HashMap<_, _> = tensors
+        .values()
+        .map(|(info, _)| (info.name.clone(), info.clone()))
+        .collect();
+    check_quantization_version(&hyperparameters, container_type, &merged_tensors);
+    check_file_type(
+        hyperparameters.file_type(),
+        &merged_tensors,
+        &mut load_progress_callback,
+    );
+
+    let ctx_size = merged_tensors
+        .values()
+        .map(|ti| ti.calc_absolute_size(false))
+        .sum::<usize>();
+
+    let lora_adapters = load_lora_adapters(&params, &merged_tensors)?;
+
+    (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
+    let context = Context::init(ctx_size, true);
+
+    let file_size = parts
+        .iter()
+        .map(|part| part.file.metadata().map(|m| m.len()))
+        .sum::<Result<u64, _>>()?;
+
+    let tensors_len = tensors.len();
+    let tl = MultipartLoader {
+        parts,
+        tensors,
+        context,
+        lora_adapters,
+        load_progress_callback: &mut load_progress_callback,
+        loaded_tensors: Default::default(),
+    };
+
+    let model =
+        KnownModel::new(hyperparameters, params, overrides, vocabulary, tl).map_err(|source| {
+            LoadError::ModelLoadFailed {
+                path: main_path.to_owned(),
+                source,
+            }
+        })?;
 
     (load_progress_callback)(LoadProgress::Loaded {
         file_size,
@@ -481,6 +689,137 @@ pub fn load<M: KnownModel>(
     Ok(model)
 }
 
+fn check_quantization_version<Hp: Hyperparameters>(
+    hyperparameters: &Hp,
+    container_type: ggml::ContainerType,
+    tensors: &HashMap<String, TensorLoadInfo>,
+) {
+    let quantization_version = hyperparameters
+        .file_type()
+        .map(|ft| ft.quantization_version)
+        .unwrap_or_default();
+    let quantization_version = if quantization_version == 0 {
+        // HACK: version 2 of the GGJT format changed the quantization algorithm,
+        // but two days after version 2, the quantization version mechanism was
+        // added. To work around this, we assume the quantization version if
+        // it's a version 2 model with a quantization version of 0.
+        if container_type == ggml::ContainerType::Ggjt(2) {
+            1
+        } else {
+            quantization_version
+        }
+    } else {
+        quantization_version
+    };
+
+    // TODO: this is temporary while we figure out how to handle this
+    if tensors.values().any(|t| t.element_type.is_quantized()) {
+        assert_eq!(quantization_version, 1, "quantization version must be 1");
+    }
+}
+
+/// Checks that the weights, i.e. the tensors with more than one dimension, are mostly of
+/// the type declared by `file_type`, reporting [LoadProgress::FileTypeMismatch] if not.
+fn check_file_type(
+    file_type: Option<FileType>,
+    tensors: &HashMap<String, TensorLoadInfo>,
+    load_progress_callback: &mut impl FnMut(LoadProgress),
+) {
+    let Some(file_type) = file_type else {
+        return;
+    };
+    if let Some(weight_type) = mismatched_weight_type(file_type, tensors) {
+        load_progress_callback(LoadProgress::FileTypeMismatch {
+            file_type,
+            weight_type,
+        });
+    }
+}
+
+/// Returns the type of most of the weights in `tensors` if it does not match `file_type`.
+///
+/// If several types are equally common, the weights match if any of them does, and
+/// otherwise the first of them in the order of [ggml::Type]'s variants is returned.
+fn mismatched_weight_type(
+    file_type: FileType,
+    tensors: &HashMap<String, TensorLoadInfo>,
+) -> Option<ggml::Type> {
+    let mut elements_per_type: Vec<(ggml::Type, usize)> = vec![];
+    for tensor in tensors.values().filter(|t| t.n_dims > 1) {
+        match elements_per_type
+            .iter_mut()
+            .find(|(typ, _)| *typ == tensor.element_type)
+        {
+            Some((_, n_elements)) => *n_elements += tensor.n_elements,
+            None => elements_per_type.push((tensor.element_type, tensor.n_elements)),
+        }
+    }
+    let max_elements = elements_per_type.iter().map(|&(_, n)| n).max()?;
+    let mut weight_types = elements_per_type
+        .into_iter()
+        .filter(|&(_, n)| n == max_elements)
+        .map(|(typ, _)| typ)
+        .collect::<Vec<_>>();
+    weight_types.sort_by_key(|&typ| typ as u32);
+
+    let matches = |weight_type: ggml::Type| match file_type.format {
+        // Only the embeddings and output weights are stored as f16.
+        FileTypeFormat::MostlyQ4_1SomeF16 => weight_type == ggml::Type::Q4_1,
+        format => FileTypeFormat::try_from(weight_type) == Ok(format),
+    };
+    if weight_types.iter().any(|&typ| matches(typ)) {
+        None
+    } else {
+        weight_types.first().copied()
+    }
+}
+
+/// Opens the LoRA adapters specified in `params`, and checks that every tensor they
+/// patch exists in the model's `tensors`.
+fn load_lora_adapters(
+    params: &ModelParameters,
+    tensors: &HashMap<String, TensorLoadInfo>,
+) -> Result<Option<Vec<LoraAdapter>>, LoadError> {
+    let Some(lora_adapters) = &params.lora_adapters else {
+        return Ok(None);
+    };
+
+    let mut adapters = Vec::with_capacity(lora_adapters.len());
+    for (lora_path, scale) in lora_adapters {
+        let adapter = LoraAdapter::open(lora_path, *scale)?;
+        if let Some(tensor_name) = adapter
+            .tensors_to_patch
+            .iter()
+            .find(|name| !tensors.contains_key(*name))
+        {
+            return Err(LoadError::UnknownTensor {
+                tensor_name: tensor_name.to_owned(),
+                path: lora_path.to_owned(),
+            });
+        }
+        adapters.push(adapter);
+    }
+
+    Ok(Some(adapters))
+}
+
+/// Patches `tensor` with each of the `lora_adapters` in turn, if there are any.
+fn apply_lora_adapters(
+    lora_adapters: &mut Option<Vec<LoraAdapter>>,
+    info: &TensorLoadInfo,
+    tensor: &mut ggml::Tensor,
+    load_progress_callback: &mut dyn FnMut(LoadProgress),
+) -> Result<(), LoadError> {
+    for lora_adapter in lora_adapters.iter_mut().flatten() {
+        lora_adapter.patch(info, tensor)?;
+        load_progress_callback(LoadProgress::LoraApplied {
+            name: info.name.to_owned(),
+            source: lora_adapter.path.to_owned(),
+        });
+    }
+    Ok(())
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
@@ -558,12 +897,18 @@ struct MmapCompatibleLoader<'a> {
     load_progress_callback: &'a mut dyn FnMut(LoadProgress),
     loaded_tensors: HashMap<String, ggml::Tensor>,
 }
-impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
-    fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
-        let info = self.tensors.get(name).ok_or(LoadError::UnknownTensor {
-            tensor_name: String::from(name),
-            path: Default::default(),
-        })?;
+impl TensorLoader for MmapCompatibleLoader<'_> {
+    fn load(&mut self, name: &str) -> Result<ggml::Tensor, ModelLoadError> {
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| ModelLoadError::MissingTensor {
+                name: name.to_owned(),
+            })?;
+        let load_failed = |source| ModelLoadError::TensorLoadFailed {
+            name: name.to_owned(),
+            source: Box::new(source),
+        };
 
         let mut main_context = FileContext::new(
             &self.context,
@@ -572,17 +917,197 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
             self.mmap.as_ref(),
         );
 
-        let mut tensor = main_context.get_tensor(info)?;
+        let mut tensor = main_context.get_tensor(info).map_err(load_failed)?;
+
+        apply_lora_adapters(
+            &mut self.lora_adapters,
+            info,
+            &mut tensor,
+            self.load_progress_callback,
+        )
+        .map_err(load_failed)?;
+
+        (self.load_progress_callback)(LoadProgress::TensorLoaded {
+            current_tensor: self.loaded_tensors.len(),
+            tensor_count: self.tensors.len(),
+        });
+        self.loaded_tensors.insert(name.to_owned(), tensor.share());
+
+        Ok(tensor)
+    }
+
+    fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>) {
+        (self.context, self.loaded_tensors, self.mmap)
+    }
+}
+
+/// A [TensorLoader] that reads tensor data on demand from any [Read] + [Seek] source,
+/// such as an in-memory buffer or a remote store, instead of a file on disk.
+///
+/// The `tensors` should be the tensors that were found when the model was read from
+/// the same source with a [Loader], and the `context` must allocate tensor data and be
+/// large enough to hold all of their data.
+pub struct StreamingTensorLoader<'a, R: Read + Seek> {
+    reader: R,
+    tensors: HashMap<String, TensorLoadInfo>,
+    context: Context,
+    lora_adapters: Option<Vec<LoraAdapter>>,
+    load_progress_callback: &'a mut dyn FnMut(LoadProgress),
+    loaded_tensors: HashMap<String, ggml::Tensor>,
+}
+impl<'a, R: Read + Seek> StreamingTensorLoader<'a, R> {
+    /// Creates a new loader that reads the `tensors` from `reader` into `context`, and
+    /// patches them with the LoRA adapters of `params`.
+    pub fn new(
+        reader: R,
+        tensors: HashMap<String, TensorLoadInfo>,
+        context: Context,
+        params: &ModelParameters,
+        load_progress_callback: &'a mut dyn FnMut(LoadProgress),
+    ) -> Result<Self, LoadError> {
+        let lora_adapters = load_lora_adapters(params, &tensors)?;
+        Ok(Self {
+            reader,
+            tensors,
+            context,
+            lora_adapters,
+            load_progress_callback,
+            loaded_tensors: Default::default(),
+        })
+    }
+}
+impl<R: Read + Seek> TensorLoader for StreamingTensorLoader<'_, R> {
+    fn load(&mut self, name: &str) -> Result<ggml::Tensor, ModelLoadError> {
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| ModelLoadError::MissingTensor {
+                name: name.to_owned(),
+            })?;
+        let load_failed = |source| ModelLoadError::TensorLoadFailed {
+            name: name.to_owned(),
+            source: Box::new(source),
+        };
+
+        let ne = info.dims();
+        let mut tensor = match ne.len() {
+            1 => self.context.new_tensor_1d(info.element_type, ne[0]),
+            _ => self.context.new_tensor_2d(info.element_type, ne[0], ne[1]),
+        };
+
+        let data = unsafe { tensor.data() } as *mut u8;
+        if data.is_null() {
+            return Err(load_failed(LoadError::InvariantBroken {
+                path: None,
+                invariant: "the context of a StreamingTensorLoader must allocate tensor data"
+                    .to_owned(),
+            }));
+        }
+        // SAFETY: `data` is not null, so the context allocated the tensor's `nbytes()`
+        // bytes of data when it was created above. They are aligned for `u8`, belong to
+        // this tensor alone, and nothing else accesses them while `buf` is alive.
+        let buf: &mut [u8] = unsafe { std::slice::from_raw_parts_mut(data, tensor.nbytes()) };
+        self.reader
+            .seek(SeekFrom::Start(info.start_offset))
+            .and_then(|_| self.reader.read_exact(buf))
+            .map_err(|e| load_failed(LoadError::Io(e)))?;
+
+        apply_lora_adapters(
+            &mut self.lora_adapters,
+            info,
+            &mut tensor,
+            self.load_progress_callback,
+        )
+        .map_err(load_failed)?;
+
+        (self.load_progress_callback)(LoadProgress::TensorLoaded {
+            current_tensor: self.loaded_tensors.len(),
+            tensor_count: self.tensors.len(),
+        });
+        self.loaded_tensors.insert(name.to_owned(), tensor.share());
+
+        Ok(tensor)
+    }
+
+    fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>) {
+        (self.context, self.loaded_tensors, None)
+    }
+}
+
+/// One part of a multi-part model.
+struct ModelPart {
+    path: PathBuf,
+    file: File,
+    tensors: HashMap<String, TensorLoadInfo>,
+}
 
-        if let Some(lora_adapters) = &mut self.lora_adapters {
-            for lora_adapter in lora_adapters {
-                lora_adapter.patch(info, &mut tensor)?;
-                (self.load_progress_callback)(LoadProgress::LoraApplied {
+struct MultipartLoader<'a> {
+    parts: Vec<ModelPart>,
+    /// The reassembled tensors, and the dimension they were split along.
+    tensors: HashMap<String, (TensorLoadInfo, Option<usize>)>,
+    context: Context,
+    lora_adapters: Option<Vec<LoraAdapter>>,
+    load_progress_callback: &'a mut dyn FnMut(LoadProgress),
+    loaded_tensors: HashMap<String, ggml::Tensor>,
+}
+impl MultipartLoader<'_> {
+    fn read_parts(&mut self, name: &str) -> Result<Vec<Vec<u8>>, LoadError> {
+        self.parts
+            .iter_mut()
+            .map(|part| {
+                let info = &part.tensors[name];
+                let mut buf = vec![0u8; info.calc_size()];
+                part.file.seek(SeekFrom::Start(info.start_offset))?;
+                part.file.read_exact(&mut buf)?;
+                Ok(buf)
+            })
+            .collect()
+    }
+}
+impl TensorLoader for MultipartLoader<'_> {
+    fn load(&mut self, name: &str) -> Result<ggml::Tensor, ModelLoadError> {
+        let (info, split_dimension) =
+            self.tensors
+                .get(name)
+                .cloned()
+                .ok_or_else(|| ModelLoadError::MissingTensor {
                     name: name.to_owned(),
-                    source: lora_adapter.path.to_owned(),
-                });
+                })?;
+        let load_failed = |source| ModelLoadError::TensorLoadFailed {
+            name: name.to_owned(),
+            source: Box::new(source),
+        };
+
+        let parts = self.read_parts(name).map_err(load_failed)?;
+        let data = match split_dimension {
+            // Unsplit tensors are duplicated across every part, so any of them will do.
+            None => parts.into_iter().next().unwrap_or_default(),
+            Some(split_dimension) => {
+                let n_rows = info.dims().get(1).copied().unwrap_or(1);
+                concatenate_parts(&parts, n_rows, split_dimension)
             }
+        };
+
+        let ne = info.dims();
+        let mut tensor = match ne.len() {
+            1 => self.context.new_tensor_1d(info.element_type, ne[0]),
+            _ => self.context.new_tensor_2d(info.element_type, ne[0], ne[1]),
+        };
+        if tensor.nbytes() != data.len() {
+            return Err(load_failed(LoadError::TensorWrongSize {
+                tensor_name: name.to_owned(),
+                path: self.parts[0].path.to_owned(),
+            }));
         }
+        unsafe { tensor.write_data(&data) };
+
+        apply_lora_adapters(
+            &mut self.lora_adapters,
+            &info,
+            &mut tensor,
+            self.load_progress_callback,
+        )
+        .map_err(load_failed)?;
 
         (self.load_progress_callback)(LoadProgress::TensorLoaded {
             current_tensor: self.loaded_tensors.len(),
@@ -594,8 +1119,30 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
     }
 
     fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>) {
-        (self.context, self.loaded_tensors, self.mmap)
+        (self.context, self.loaded_tensors, None)
+    }
+}
+
+/// Reassembles the raw data of a tensor that was split across several parts.
+///
+/// When split along dimension 0, each part holds a slice of every row, so the
+/// rows are interleaved; when split along dimension 1, each part holds whole rows,
+/// so the parts are simply appended to each other.
+fn concatenate_parts(parts: &[Vec<u8>], n_rows: usize, split_dimension: usize) -> Vec<u8> {
+    let mut data = Vec::with_capacity(parts.iter().map(Vec::len).sum());
+    if split_dimension == 0 {
+        for row in 0..n_rows {
+            for part in parts {
+                let row_size = part.len() / n_rows;
+                data.extend_from_slice(&part[row * row_size..(row + 1) * row_size]);
+            }
+        }
+    } else {
+        for part in parts {
+            data.extend_from_slice(part);
+        }
     }
+    data
 }
 
 pub(crate) struct FileContext<'a> {
@@ -676,6 +1223,12 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
             "ggml ctx size = {:.2} MB\n",
             bytes as f64 / (1024.0 * 1024.0)
         ),
+        LoadProgress::FileTypeMismatch {
+            file_type,
+            weight_type,
+        } => println!(
+            "Warning: the file type is {file_type}, but the weights are mostly {weight_type}"
+        ),
         LoadProgress::TensorLoaded {
             current_tensor,
             tensor_count,
@@ -706,3 +1259,483 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        model::common::TestTensorLoader, InferenceParameters, InferenceSession,
+        InferenceSessionConfig, OutputRequest,
+    };
+
+    #[test]
+    fn test_file_type_roundtrip_and_display() {
+        let file_type = FileType::from_type(ggml::Type::Q4_0, 2).unwrap();
+        assert_eq!(file_type.format, FileTypeFormat::MostlyQ4_0);
+
+        let ftype: i32 = file_type.into();
+        assert_eq!(ftype, 2002);
+        assert_eq!(FileType::try_from(ftype), Ok(file_type));
+        assert_eq!(file_type.to_string(), "q4_0 (qnt v2)");
+
+        assert_eq!(FileType::from_type(ggml::Type::I32, 2), None);
+    }
+
+    #[test]
+    fn test_missing_tensor_is_reported_by_name() {
+        let path = crate::lora::tests::temp_path("llm-base-missing-tensor-test.bin");
+        std::fs::write(&path, []).unwrap();
+
+        let mut load_progress_callback = |_| {};
+        let mut loader = MmapCompatibleLoader {
+            path: path.clone(),
+            file: File::open(&path).unwrap(),
+            tensors: HashMap::default(),
+            context: Context::init(1024, true),
+            mmap: None,
+            lora_adapters: None,
+            load_progress_callback: &mut load_progress_callback,
+            loaded_tensors: Default::default(),
+        };
+
+        let result = loader.load("tok_embeddings.weight");
+        std::fs::remove_file(&path).unwrap();
+        match result {
+            Err(ModelLoadError::MissingTensor { name }) => {
+                assert_eq!(name, "tok_embeddings.weight")
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("loading a missing tensor should fail"),
+        }
+    }
+
+    #[test]
+    fn test_mismatched_tensor_shape_is_reported() {
+        let mut loader = TestTensorLoader::new([("output.weight", vec![4, 8])]);
+        assert!(loader.load_with_shape("output.weight", &[4, 8]).is_ok());
+
+        match loader.load_with_shape("output.weight", &[4, 16]) {
+            Err(ModelLoadError::UnexpectedShape {
+                name,
+                expected,
+                actual,
+            }) => {
+                assert_eq!(name, "output.weight");
+                assert_eq!(expected, vec![4, 16]);
+                assert_eq!(actual, vec![4, 8]);
+            }
+            Err(err) => panic!("unexpected error: {err}"),
+            Ok(_) => panic!("loading a mismatched tensor should fail"),
+        }
+    }
+
+    #[test]
+    fn test_file_type_must_match_weights() {
+        let tensor = |name: &str, n_dims, element_type| {
+            let info = TensorLoadInfo {
+                name: name.to_owned(),
+                n_dims,
+                dims: [64, if n_dims == 1 { 1 } else { 64 }],
+                n_elements: if n_dims == 1 { 64 } else { 64 * 64 },
+                element_type,
+                start_offset: 0,
+            };
+            (name.to_owned(), info)
+        };
+        let tensors = HashMap::from([
+            tensor("norm.weight", 1, ggml::Type::F32),
+            tensor("layer.0.weight", 2, ggml::Type::F16),
+            tensor("layer.1.weight", 2, ggml::Type::F16),
+            tensor("output.weight", 2, ggml::Type::Q4_0),
+        ]);
+        let file_type = |format| FileType {
+            format,
+            quantization_version: ggml::QNT_VERSION,
+        };
+
+        let mut reported = vec![];
+        let mut check = |file_type| {
+            check_file_type(file_type, &tensors, &mut |progress| {
+                if let LoadProgress::FileTypeMismatch {
+                    file_type,
+                    weight_type,
+                } = progress
+                {
+                    reported.push((file_type.format, weight_type));
+                }
+            })
+        };
+        check(Some(file_type(FileTypeFormat::MostlyF16)));
+        check(None);
+        check(Some(file_type(FileTypeFormat::MostlyQ4_0)));
+        assert_eq!(
+            reported,
+            vec![(FileTypeFormat::MostlyQ4_0, ggml::Type::F16)]
+        );
+
+        // When types are equally common, the weights match either of them, and a
+        // mismatch always reports the same type.
+        let tied = HashMap::from([
+            tensor("layer.0.weight", 2, ggml::Type::Q4_0),
+            tensor("layer.1.weight", 2, ggml::Type::F16),
+        ]);
+        for format in [FileTypeFormat::MostlyQ4_0, FileTypeFormat::MostlyF16] {
+            assert_eq!(mismatched_weight_type(file_type(format), &tied), None);
+        }
+        for _ in 0..8 {
+            let tied = tied.clone().into_iter().collect::<HashMap<_, _>>();
+            assert_eq!(
+                mismatched_weight_type(file_type(FileTypeFormat::MostlyQ8_0), &tied),
+                Some(ggml::Type::Q4_0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_concatenate_parts() {
+        let parts = vec![vec![1, 2, 5, 6], vec![3, 4, 7, 8]];
+        assert_eq!(
+            concatenate_parts(&parts, 2, 0),
+            vec![1, 2, 3, 4, 5, 6, 7, 8]
+        );
+        assert_eq!(
+            concatenate_parts(&parts, 2, 1),
+            vec![1, 2, 5, 6, 3, 4, 7, 8]
+        );
+    }
+
+    /// Saves a GGJT model with LoRA hyperparameters and a single 2x2 F32 tensor
+    /// named `weight`.
+    fn save_weight_model(values: &[f32]) -> Vec<u8> {
+        save_model(1, &[("weight", &[2, 2], values)])
+    }
+
+    /// Saves a GGJT model with the LoRA hyperparameters `r` (and `alpha`) and the
+    /// F32 `tensors`, given as their name, dimensions and values, in that order.
+    fn save_model(r: i32, tensors: &[(&str, &[usize], &[f32])]) -> Vec<u8> {
+        struct TensorsSaveHandler<'a>(i32, &'a [(&'a str, &'a [usize], &'a [f32])]);
+        impl ggml::format::SaveHandler<LoadError> for TensorsSaveHandler<'_> {
+            fn write_hyperparameters(
+                &mut self,
+                writer: &mut dyn std::io::Write,
+            ) -> Result<(), LoadError> {
+                util::write_i32(writer, self.0)?;
+                util::write_i32(writer, self.0)?;
+                Ok(())
+            }
+
+            fn tensor_data(
+                &mut self,
+                tensor_name: &str,
+            ) -> Result<ggml::format::TensorSaveInfo, LoadError> {
+                let (_, ne, values) = self
+                    .1
+                    .iter()
+                    .find(|(name, _, _)| *name == tensor_name)
+                    .expect("only the given tensors are saved");
+                Ok(ggml::format::TensorSaveInfo {
+                    n_dims: ne.len(),
+                    dims: [ne[0], ne.get(1).copied().unwrap_or(1)],
+                    element_type: ggml::Type::F32,
+                    data: values.iter().flat_map(|v| v.to_ne_bytes()).collect(),
+                })
+            }
+        }
+
+        let tensor_names: Vec<String> = tensors
+            .iter()
+            .map(|(name, _, _)| (*name).to_owned())
+            .collect();
+        let mut cursor = std::io::Cursor::new(vec![]);
+        ggml::format::save(
+            &mut cursor,
+            &mut TensorsSaveHandler(r, tensors),
+            ggml::format::SaveContainerType::GgjtV2,
+            &[],
+            &tensor_names,
+        )
+        .unwrap();
+        cursor.into_inner()
+    }
+
+    /// A model that only holds the contents of its `weight` tensor, and of its
+    /// optional `rows` and `bias` tensors.
+    struct WeightModel {
+        hyperparameters: crate::LoraParameters,
+        weight: Vec<f32>,
+        /// The shape and contents of the optional tensors that were present.
+        optional: HashMap<String, ([i64; 4], Vec<f32>)>,
+        mmapped: bool,
+        vocabulary: Vocabulary,
+        inference_parameters: InferenceParameters,
+    }
+    impl KnownModel for WeightModel {
+        type Hyperparameters = crate::LoraParameters;
+        type Overrides = ();
+
+        fn new(
+            hyperparameters: Self::Hyperparameters,
+            params: ModelParameters,
+            _overrides: Option<Self::Overrides>,
+            vocabulary: Vocabulary,
+            tensor_loader: impl TensorLoader,
+        ) -> Result<Self, ModelLoadError> {
+            let mut tl = tensor_loader;
+            let weight = unsafe { tl.load("weight")?.as_slice_f32() }.to_vec();
+            let mut optional = HashMap::new();
+            for name in ["rows", "bias"] {
+                match tl.load(name) {
+                    Ok(tensor) => {
+                        optional.insert(
+                            name.to_owned(),
+                            (tensor.get_ne(), unsafe { tensor.as_slice_f32() }.to_vec()),
+                        );
+                    }
+                    Err(ModelLoadError::MissingTensor { .. }) => {}
+                    Err(err) => return Err(err),
+                }
+            }
+            let (_context, _, mmap) = tl.finish();
+
+            Ok(Self {
+                hyperparameters,
+                weight,
+                optional,
+                mmapped: mmap.is_some(),
+                vocabulary,
+                inference_parameters: params.inference_parameters,
+            })
+        }
+
+        fn tensor_names(_hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+            vec!["weight".to_owned()]
+        }
+
+        fn split_dimension(name: &str, n_dims: usize) -> Option<usize> {
+            match (name, n_dims) {
+                ("rows", 2) => Some(1),
+                (_, 1) => None,
+                _ => Some(0),
+            }
+        }
+
+        fn hyperparameters(&self) -> &Self::Hyperparameters {
+            &self.hyperparameters
+        }
+
+        fn start_session(&self, _config: InferenceSessionConfig) -> InferenceSession {
+            unimplemented!()
+        }
+
+        fn evaluate(
+            &self,
+            _session: &mut InferenceSession,
+            _params: &InferenceParameters,
+            _input_tokens: &[TokenId],
+            _output_request: &mut OutputRequest,
+        ) {
+            unimplemented!()
+        }
+
+        fn vocabulary(&self) -> &Vocabulary {
+            &self.vocabulary
+        }
+
+        fn n_context_tokens(&self) -> usize {
+            0
+        }
+
+        fn bot_token_id(&self) -> Option<TokenId> {
+            None
+        }
+
+        fn eot_token_id(&self) -> TokenId {
+            0
+        }
+
+        fn inference_parameters(&self) -> &InferenceParameters {
+            &self.inference_parameters
+        }
+    }
+
+    #[test]
+    fn test_load_with_and_without_mmap() {
+        let values = [1.0f32, 2.0, 3.0, 4.0];
+        let path = crate::lora::tests::temp_path("llm-base-load-mmap-test.bin");
+        std::fs::write(&path, save_weight_model(&values)).unwrap();
+
+        let load = |prefer_mmap| {
+            let params = ModelParameters::builder().use_mmap(prefer_mmap).build();
+            crate::load::<WeightModel>(&path, params, None, |_| {}).unwrap()
+        };
+        let mmapped = load(true);
+        let read = load(false);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(mmapped.mmapped);
+        assert!(!read.mmapped);
+        assert_eq!(
+            mmapped.hyperparameters(),
+            &crate::LoraParameters { r: 1, alpha: 1 }
+        );
+        assert_eq!(mmapped.weight, values);
+        assert_eq!(read.weight, values);
+    }
+
+    #[test]
+    fn test_multipart_model_is_reassembled() {
+        let dir = crate::lora::tests::temp_path("llm-base-load-multipart-test");
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.bin");
+
+        // `weight` is 4x2 and split along its rows, `rows` is 2x4 and split into
+        // whole rows, and `bias` is copied into both parts. The parts store their
+        // tensors in different orders, so that they are found at different offsets.
+        let bias = [0.5f32, 1.5, 2.5];
+        std::fs::write(
+            &path,
+            save_model(
+                1,
+                &[
+                    ("weight", &[2, 2], &[0.0, 1.0, 4.0, 5.0]),
+                    ("rows", &[2, 2], &[10.0, 11.0, 12.0, 13.0]),
+                    ("bias", &[3], &bias),
+                ],
+            ),
+        )
+        .unwrap();
+        let second_part = [
+            ("bias", &[3][..], &bias[..]),
+            ("rows", &[2, 2], &[14.0, 15.0, 16.0, 17.0]),
+            ("weight", &[2, 2], &[2.0, 3.0, 6.0, 7.0]),
+        ];
+        std::fs::write(dir.join("model.bin.1"), save_model(1, &second_part)).unwrap();
+
+        let model =
+            crate::load::<WeightModel>(&path, ModelParameters::default(), None, |_| {}).unwrap();
+        assert_eq!(model.weight, [0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0]);
+        assert_eq!(
+            model.optional["rows"],
+            ([2, 4, 1, 1], (10..18).map(|v| v as f32).collect::<Vec<_>>())
+        );
+        assert_eq!(model.optional["bias"], ([3, 1, 1, 1], bias.to_vec()));
+
+        // Every part must have the same hyperparameters.
+        std::fs::write(dir.join("model.bin.1"), save_model(2, &second_part)).unwrap();
+        let result = crate::load::<WeightModel>(&path, ModelParameters::default(), None, |_| {});
+        assert!(matches!(result, Err(LoadError::InvariantBroken { .. })));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lora_adapters_are_applied_at_load() {
+        let values = [1.0f32, 2.0, 3.0, 4.0];
+        let model_path = crate::lora::tests::temp_path("llm-base-load-lora-model-test.bin");
+        let lora_path = crate::lora::tests::temp_path("llm-base-load-lora-adapter-test.bin");
+        std::fs::write(&model_path, save_weight_model(&values)).unwrap();
+        crate::lora::tests::write_lora(
+            &lora_path,
+            crate::LoraParameters { r: 1, alpha: 2 },
+            &[
+                ("weight.loraA", [1, 2], &[1.0, 2.0]),
+                ("weight.loraB", [1, 2], &[3.0, 4.0]),
+            ],
+        );
+
+        let params = ModelParameters::builder()
+            .lora_adapter(lora_path.clone(), 0.5)
+            .build();
+        let model = crate::load::<WeightModel>(&model_path, params, None, |_| {}).unwrap();
+        assert!(!model.mmapped);
+
+        // Applying the same adapter to the weights after loading gives the same result.
+        let context = Context::init(1024 * 1024, true);
+        let mut tensor = context.new_tensor_2d(ggml::Type::F32, 2, 2);
+        unsafe { tensor.write_data(bytemuck::cast_slice(&values)) };
+        let info = TensorLoadInfo {
+            name: "weight".to_owned(),
+            n_dims: 2,
+            dims: [2, 2],
+            n_elements: 4,
+            element_type: ggml::Type::F32,
+            start_offset: 0,
+        };
+        crate::LoraAdapter::open(&lora_path, 0.5)
+            .unwrap()
+            .patch(&info, &mut tensor)
+            .unwrap();
+        std::fs::remove_file(&model_path).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+        let patched = unsafe { tensor.as_slice_f32() }.to_vec();
+
+        assert_ne!(model.weight, values);
+        assert_eq!(model.weight, patched);
+    }
+
+    #[test]
+    fn test_streaming_tensor_loader_reads_from_memory() {
+        let values = [1.0f32, 2.0, 3.0, 4.0];
+        let load = |params: ModelParameters| {
+            let mut cursor = std::io::Cursor::new(save_weight_model(&values));
+            let mut loader: Loader<crate::LoraParameters, _> = Loader::new(|_| {});
+            ggml::format::load(&mut cursor, &mut loader).unwrap();
+
+            let ctx_size = loader
+                .tensors
+                .values()
+                .map(|ti| ti.calc_absolute_size(false))
+                .sum::<usize>();
+            let mut load_progress_callback = |_| {};
+            let tl = StreamingTensorLoader::new(
+                cursor,
+                loader.tensors,
+                Context::init(ctx_size, true),
+                &params,
+                &mut load_progress_callback,
+            )
+            .unwrap();
+            WeightModel::new(loader.hyperparameters, params, None, loader.vocabulary, tl)
+        };
+
+        let model = load(ModelParameters::default()).unwrap();
+        assert!(!model.mmapped);
+        assert_eq!(model.weight, values);
+
+        // LoRA adapters are applied like they are to models loaded from files.
+        let lora_path = crate::lora::tests::temp_path("llm-base-streaming-lora-test.bin");
+        crate::lora::tests::write_lora(
+            &lora_path,
+            crate::LoraParameters { r: 1, alpha: 2 },
+            &[
+                ("weight.loraA", [1, 2], &[1.0, 2.0]),
+                ("weight.loraB", [1, 2], &[3.0, 4.0]),
+            ],
+        );
+        let params = ModelParameters::builder()
+            .lora_adapter(lora_path.clone(), 0.5)
+            .build();
+        let model = load(params).unwrap();
+        std::fs::remove_file(&lora_path).unwrap();
+        // w + (B @ A) * (alpha / r) * scale
+        assert_eq!(model.weight, [4.0, 8.0, 7.0, 12.0]);
+
+        // A context that does not allocate tensor data cannot be read into.
+        let mut cursor = std::io::Cursor::new(save_weight_model(&values));
+        let mut loader: Loader<crate::LoraParameters, _> = Loader::new(|_| {});
+        ggml::format::load(&mut cursor, &mut loader).unwrap();
+        let mut load_progress_callback = |_| {};
+        let mut tl = StreamingTensorLoader::new(
+            cursor,
+            loader.tensors,
+            Context::init(1024, false),
+            &ModelParameters::default(),
+            &mut load_progress_callback,
+        )
+        .unwrap();
+        assert!(matches!(
+            tl.load("weight"),
+            Err(ModelLoadError::TensorLoadFailed { .. })
+        ));
+    }
+}
@@ -1,13 +1,14 @@
 use crate::{
     loader::FileContext, model::HyperparametersWriteError, util, FileType, Hyperparameters,
-    LoadError,
+    LoadError, Loader,
 };
 
 use ggml::format::TensorLoadInfo;
 use std::{
     collections::{HashMap, HashSet},
     fs::File,
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
 };
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
@@ -67,6 +68,40 @@ pub struct LoraAdapter {
 }
 
 impl LoraAdapter {
+    /// Opens the LoRA adapter at `path`.
+    ///
+    /// The adapter's weights are scaled by `scale` in addition to the scaling factor
+    /// stored in the adapter (`alpha / r`). Use a `scale` of 1.0 to apply the adapter
+    /// as it was trained.
+    pub fn open(path: &Path, scale: f32) -> Result<Self, LoadError> {
+        // Read the LoRA file
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        // TODO: Consider updating the progress callback to report the progress of the LoRA file.
+        // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
+        let mut loader: Loader<LoraParameters, _> = Loader::new(|_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        // Collect the names of the tensors that should be patched
+        let tensors_to_patch = loader
+            .tensors
+            .keys()
+            .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
+            .collect();
+
+        Ok(LoraAdapter {
+            scaling: scale * loader.hyperparameters.calculate_scaling(),
+            tensors: loader.tensors,
+            tensors_to_patch,
+            file,
+            path: path.to_owned(),
+        })
+    }
+
     /// Patch a tensor via LoRA
     pub fn patch(
         &mut self,
@@ -147,3 +182,132 @@ impl LoraAdapter {
             })
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Cursor, Seek, Write};
+
+    /// Returns a path in the temporary directory for the file `name`, which is unique
+    /// to this test process so that concurrent runs do not overwrite each other's files.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{name}", std::process::id()))
+    }
+
+    /// Writes a GGLA file containing the given `f32` tensors to `path`.
+    pub(crate) fn write_lora(
+        path: &Path,
+        parameters: LoraParameters,
+        tensors: &[(&str, [usize; 2], &[f32])],
+    ) {
+        let mut writer = Cursor::new(vec![]);
+        ggml::ContainerType::Ggla(1).write(&mut writer).unwrap();
+        parameters.write_ggml(&mut writer).unwrap();
+
+        for (name, dims, data) in tensors {
+            util::write_i32(&mut writer, 2).unwrap();
+            util::write_i32(&mut writer, name.len() as i32).unwrap();
+            util::write_u32(&mut writer, ggml::Type::F32.into()).unwrap();
+            for &dim in dims {
+                util::write_i32(&mut writer, dim as i32).unwrap();
+            }
+            writer.write_all(name.as_bytes()).unwrap();
+
+            let offset = writer.stream_position().unwrap();
+            let padding = ((offset + 31) & !31) - offset;
+            writer.write_all(&vec![0; padding as usize]).unwrap();
+            for &value in *data {
+                util::write_f32(&mut writer, value).unwrap();
+            }
+        }
+
+        std::fs::write(path, writer.into_inner()).unwrap();
+    }
+
+    /// Creates a 2x2 `f32` tensor filled with `value`, along with its load info.
+    fn base_tensor(context: &ggml::Context, value: f32) -> (TensorLoadInfo, ggml::Tensor) {
+        let info = TensorLoadInfo {
+            name: "weight".to_owned(),
+            n_dims: 2,
+            dims: [2, 2],
+            n_elements: 4,
+            element_type: ggml::Type::F32,
+            start_offset: 0,
+        };
+        let tensor = context.new_tensor_2d(ggml::Type::F32, 2, 2);
+        tensor.fill_f32(value);
+        (info, tensor)
+    }
+
+    #[test]
+    fn test_patch_adds_scaled_low_rank_update() {
+        let path = temp_path("llm-base-lora-patch-test.bin");
+        write_lora(
+            &path,
+            LoraParameters { r: 1, alpha: 2 },
+            &[
+                ("weight.loraA", [1, 2], &[1.0, 2.0]),
+                ("weight.loraB", [1, 2], &[3.0, 4.0]),
+            ],
+        );
+
+        let context = ggml::Context::init(1024 * 1024, true);
+        let (info, mut tensor) = base_tensor(&context, 1.0);
+
+        let mut adapter = LoraAdapter::open(&path, 0.25).unwrap();
+        assert_eq!(adapter.scaling, 0.5);
+        adapter.patch(&info, &mut tensor).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // w + (B @ A) * (alpha / r) * scale
+        assert_eq!(unsafe { tensor.as_slice_f32() }, [2.5, 4.0, 3.0, 5.0]);
+    }
+
+    #[test]
+    fn test_stacked_adapters_accumulate_updates() {
+        let paths = [
+            "llm-base-lora-stack-test-1.bin",
+            "llm-base-lora-stack-test-2.bin",
+        ]
+        .map(temp_path);
+        write_lora(
+            &paths[0],
+            LoraParameters { r: 1, alpha: 1 },
+            &[
+                ("weight.loraA", [1, 2], &[1.0, 2.0]),
+                ("weight.loraB", [1, 2], &[3.0, 4.0]),
+            ],
+        );
+        write_lora(
+            &paths[1],
+            LoraParameters { r: 1, alpha: 1 },
+            &[
+                ("weight.loraA", [1, 2], &[-1.0, 0.5]),
+                ("weight.loraB", [1, 2], &[2.0, 1.0]),
+            ],
+        );
+        let scales = [0.5, 2.0];
+
+        let context = ggml::Context::init(1024 * 1024, true);
+        let delta = |adapters: &[usize]| {
+            let (info, mut tensor) = base_tensor(&context, 0.0);
+            for &i in adapters {
+                let mut adapter = LoraAdapter::open(&paths[i], scales[i]).unwrap();
+                adapter.patch(&info, &mut tensor).unwrap();
+            }
+            unsafe { tensor.as_slice_f32() }.to_vec()
+        };
+
+        let first = delta(&[0]);
+        let second = delta(&[1]);
+        let combined = delta(&[0, 1]);
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        assert_eq!(first, vec![1.5, 3.0, 2.0, 4.0]);
+        assert_eq!(second, vec![-4.0, 2.0, -2.0, 1.0]);
+
+        let sum: Vec<f32> = first.iter().zip(&second).map(|(a, b)| a + b).collect();
+        assert_eq!(combined, sum);
+    }
+}
@@ -1,13 +1,32 @@
+use std::collections::HashMap;
+
 use ggml::{Context, Tensor};
 
-use crate::{InferenceSession, OutputRequest, TokenId};
+use crate::{
+    InferenceParameters, InferenceSession, InferenceSessionConfig, Mmap, Model, ModelLoadError,
+    OutputRequest, TensorLoader, TokenId, Vocabulary,
+};
 
 /// Common code to prepare a model to evaluate input
+///
+/// # Panics
+/// Panics if evaluating `input_tokens` would write past the end of the session's
+/// key/value memory, i.e. if `n_past + input_tokens.len()` exceeds the context size.
+/// [InferenceSession::feed_prompt] and [InferenceSession::infer_next_token] check
+/// this beforehand and return [InferenceError::ContextFull](crate::InferenceError::ContextFull).
 pub fn prepare_for_evaluate(
     n_layer: usize,
     session: &mut InferenceSession,
     input_tokens: &[TokenId],
 ) -> (Context, Tensor) {
+    assert!(
+        session.n_past + input_tokens.len() <= session.n_ctx,
+        "cannot evaluate {} tokens after {} past tokens: the context only holds {} tokens",
+        input_tokens.len(),
+        session.n_past,
+        session.n_ctx,
+    );
+
     // For the first run, we need to guess a maximum buffer size so we can measure
     // the actual memory consumption of the temporary ggml context.
     //
@@ -71,7 +90,10 @@ pub fn extract_logits(
     }
 }
 
-/// Extract embeddings from [OutputRequest] evaluation
+/// Extract embeddings from [OutputRequest] evaluation.
+///
+/// `embd` should be the hidden states after the model's final normalization,
+/// with `n_embd` values for each of the `n` evaluated tokens.
 pub fn extract_embeddings(
     output_request: &mut OutputRequest,
     embd: &Tensor,
@@ -99,3 +121,245 @@ pub fn update_session(session: &mut InferenceSession, ctx0: &Context, n_input: u
     // Adjust n_past to new length.
     session.n_past += n_input;
 }
+
+/// Checks that a new session for `model` has key/value memory for `n_layer` layers of
+/// `n_embd` values for each of the [Model::n_context_tokens], for the tests of the models.
+///
+/// # Panics
+/// Panics if it does not. The attention views built by the models are laid out with
+/// the context size the model was loaded with, not the one it was trained with, so
+/// the session memory must be sized the same way.
+#[doc(hidden)]
+pub fn assert_session_memory_matches_context_size(
+    model: &dyn Model,
+    n_layer: usize,
+    n_embd: usize,
+) {
+    let session = model.start_session(Default::default());
+    let n_elements = n_layer * model.n_context_tokens() * n_embd;
+    assert_eq!(session.memory_k.nelements(), n_elements);
+    assert_eq!(session.memory_v.nelements(), n_elements);
+}
+
+/// The deterministic, non-trivial value of the `i`th element of the tensors created by
+/// [TestTensorLoader], for the tests of the models.
+#[doc(hidden)]
+pub fn test_tensor_value(i: usize) -> f32 {
+    ((i * 37 % 101) as f32 - 50.0) / 100.0
+}
+
+/// A [TensorLoader] for the tests of the models, which creates the tensors of the given
+/// shapes, filled with [test_tensor_value], and reports every other tensor as missing.
+///
+/// Tensors with more than one dimension are created with the weight type, `F32` by default,
+/// and all others as `F32`, like the norms and biases of a converted model. Loading a
+/// tensor again returns the same tensor.
+#[doc(hidden)]
+pub struct TestTensorLoader {
+    shapes: HashMap<String, Vec<usize>>,
+    weight_type: ggml::Type,
+    context: Option<Context>,
+    alloc: bool,
+    loaded_tensors: HashMap<String, Tensor>,
+}
+impl TestTensorLoader {
+    /// Creates a loader for tensors of the given `shapes`, of up to three dimensions.
+    pub fn new<S: Into<String>>(shapes: impl IntoIterator<Item = (S, Vec<usize>)>) -> Self {
+        Self {
+            shapes: shapes
+                .into_iter()
+                .map(|(name, shape)| (name.into(), shape))
+                .collect(),
+            weight_type: ggml::Type::F32,
+            context: None,
+            alloc: true,
+            loaded_tensors: Default::default(),
+        }
+    }
+
+    /// Creates the tensors with more than one dimension as `weight_type`, which must be
+    /// `F32` or `F16` unless [without_data](Self::without_data) is used.
+    pub fn with_weight_type(mut self, weight_type: ggml::Type) -> Self {
+        self.weight_type = weight_type;
+        self
+    }
+
+    /// Creates the tensors without allocating their data, so that models of a real size
+    /// can be constructed, but not evaluated.
+    pub fn without_data(mut self) -> Self {
+        self.alloc = false;
+        self
+    }
+
+    fn element_type(&self, shape: &[usize]) -> ggml::Type {
+        if shape.len() > 1 {
+            self.weight_type
+        } else {
+            ggml::Type::F32
+        }
+    }
+
+    fn context(&mut self) -> &Context {
+        if self.context.is_none() {
+            let mut size = self.shapes.len() * ggml::tensor_overhead();
+            if self.alloc {
+                size += self
+                    .shapes
+                    .values()
+                    .map(|shape| {
+                        let n_elements = shape.iter().product::<usize>();
+                        ggml::format::tensor_size(self.element_type(shape), n_elements)
+                    })
+                    .sum::<usize>();
+            }
+            self.context = Some(Context::init(size, self.alloc));
+        }
+        self.context.as_ref().unwrap()
+    }
+}
+impl TensorLoader for TestTensorLoader {
+    fn load(&mut self, name: &str) -> Result<Tensor, ModelLoadError> {
+        if let Some(tensor) = self.loaded_tensors.get(name) {
+            return Ok(tensor.share());
+        }
+        let shape = self
+            .shapes
+            .get(name)
+            .ok_or_else(|| ModelLoadError::MissingTensor {
+                name: name.to_owned(),
+            })?
+            .clone();
+        let element_type = self.element_type(&shape);
+        let alloc = self.alloc;
+        let context = self.context();
+        let mut tensor = match shape[..] {
+            [ne0] => context.new_tensor_1d(element_type, ne0),
+            [ne0, ne1] => context.new_tensor_2d(element_type, ne0, ne1),
+            [ne0, ne1, ne2] => context.new_tensor_3d(element_type, ne0, ne1, ne2),
+            _ => panic!("cannot create the tensor `{name}` of shape {shape:?}"),
+        };
+        if alloc {
+            let values = (0..tensor.nelements()).map(test_tensor_value);
+            let data: Vec<u8> = match element_type {
+                ggml::Type::F32 => values.flat_map(f32::to_ne_bytes).collect(),
+                ggml::Type::F16 => values
+                    .flat_map(|value| half::f16::from_f32(value).to_ne_bytes())
+                    .collect(),
+                _ => panic!("cannot fill the {element_type} tensor `{name}` with values"),
+            };
+            unsafe { tensor.write_data(&data) };
+        }
+        self.loaded_tensors.insert(name.to_owned(), tensor.share());
+        Ok(tensor)
+    }
+
+    fn finish(mut self) -> (Context, HashMap<String, Tensor>, Option<Mmap>) {
+        self.context();
+        (self.context.unwrap(), self.loaded_tensors, None)
+    }
+}
+
+/// A [Model] for the tests of generation, which ignores its input and generates a fixed
+/// sequence of tokens after a single-token prompt.
+///
+/// Its vocabulary is the end of text (`0`), the beginning of text (`1`), `h` (`2`), the two
+/// bytes of `é` (`3` and `4`), and `i` (`5`). Each scripted token gets all the probability.
+#[doc(hidden)]
+pub struct ScriptedModel {
+    pub vocabulary: Vocabulary,
+    pub script: Vec<TokenId>,
+    pub inference_parameters: InferenceParameters,
+}
+impl ScriptedModel {
+    /// Creates a model that generates the tokens of `script`, then the end of text.
+    pub fn new(script: Vec<TokenId>) -> Self {
+        let mut vocabulary = Vocabulary::default();
+        // "é" is split across two tokens, as a byte-level tokenizer would do.
+        for (id, token) in [&b""[..], b"<s>", b"h", &[0xC3], &[0xA9], b"i"]
+            .into_iter()
+            .enumerate()
+        {
+            vocabulary.push_token(id as TokenId, token.to_vec(), 0.0);
+        }
+
+        Self {
+            vocabulary,
+            script,
+            inference_parameters: InferenceParameters {
+                top_k: 1,
+                ..Default::default()
+            },
+        }
+    }
+}
+impl Model for ScriptedModel {
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        InferenceSession::new(config, self.n_context_tokens(), 1, 1, self.vocabulary.len())
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        _params: &InferenceParameters,
+        input_tokens: &[TokenId],
+        _output_request: &mut OutputRequest,
+    ) {
+        session.n_past += input_tokens.len();
+
+        // The session's tokens include the beginning-of-text token and the
+        // single-token prompt.
+        let next_token = self
+            .script
+            .get(session.tokens.len().saturating_sub(2))
+            .copied()
+            .unwrap_or(self.eot_token_id());
+        for (id, logit) in session.last_logits.iter_mut().enumerate() {
+            *logit = if id == next_token as usize {
+                100.0
+            } else {
+                -100.0
+            };
+        }
+    }
+
+    fn vocabulary(&self) -> &Vocabulary {
+        &self.vocabulary
+    }
+
+    fn n_context_tokens(&self) -> usize {
+        64
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        Some(1)
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        0
+    }
+
+    fn inference_parameters(&self) -> &InferenceParameters {
+        &self.inference_parameters
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prepare_for_evaluate_fills_context() {
+        let mut session = InferenceSession::new(Default::default(), 4, 1, 8, 6);
+        session.n_past = 1;
+        let (_ctx0, embd) = prepare_for_evaluate(1, &mut session, &[1, 2, 3]);
+        assert_eq!(embd.nelements(), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "the context only holds 4 tokens")]
+    fn test_prepare_for_evaluate_rejects_context_overflow() {
+        let mut session = InferenceSession::new(Default::default(), 4, 1, 8, 6);
+        session.n_past = 2;
+        prepare_for_evaluate(1, &mut session, &[1, 2, 3]);
+    }
+}
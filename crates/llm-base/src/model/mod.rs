@@ -2,7 +2,6 @@
 
 use std::{
     collections::HashMap,
-    error::Error,
     fmt::Debug,
     io::{BufRead, Write},
     path::{Path, PathBuf},
@@ -12,8 +11,9 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
-    loader::TensorLoader, vocabulary::TokenId, FileType, InferenceParameters, InferenceSession,
-    InferenceSessionConfig, LoadError, LoadProgress, Vocabulary,
+    loader::TensorLoader, vocabulary::TokenId, BenchmarkReport, FileType, GgufMetadata,
+    GgufMetadataBuilder, InferenceError, InferenceParameters, InferenceSession,
+    InferenceSessionConfig, InferenceStats, LoadError, LoadProgress, ModelLoadError, Vocabulary,
 };
 
 /// Common functions for model evaluation
@@ -126,16 +126,41 @@ pub trait KnownModel: Send + Sync {
 
     /// Creates a new model from the provided [ModelParameters] hyperparameters.
     /// This function is called by the [load](crate::loader::load) function.
-    fn new<E: Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
         overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl TensorLoader<E>,
-    ) -> Result<Self, E>
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError>
     where
         Self: Sized;
 
+    /// Returns the names of the tensors that a model with the given `hyperparameters`
+    /// loads, e.g. `layers.{i}.attention.wo.weight` for every layer `i`.
+    ///
+    /// This can be used to check that a file contains every tensor before loading it.
+    /// Tensors that the model can do without are not included.
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String>
+    where
+        Self: Sized;
+
+    /// Returns the dimension along which the tensor `name` was split when the model was
+    /// saved as multiple parts, or `None` if every part holds a full copy of the tensor.
+    ///
+    /// By default, 1-dimensional tensors are assumed to be duplicated and all other
+    /// tensors are assumed to be split along their first dimension.
+    fn split_dimension(_name: &str, n_dims: usize) -> Option<usize>
+    where
+        Self: Sized,
+    {
+        (n_dims > 1).then_some(0)
+    }
+
+    /// Returns the hyperparameters the model was loaded with, such as its number of layers
+    /// or its [file type](Hyperparameters::file_type).
+    fn hyperparameters(&self) -> &Self::Hyperparameters;
+
     /// Starts a new `InferenceSession` for this model.
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession;
 
@@ -168,6 +193,109 @@ pub trait KnownModel: Send + Sync {
     /// [InferenceSession::infer]). This value is configured through
     /// [ModelParameters::inference_parameters].
     fn inference_parameters(&self) -> &InferenceParameters;
+
+    /// Measures the throughput of this model by evaluating a synthetic prompt of `prompt_len`
+    /// tokens in a new session, and then generating `gen_len` tokens with `params`.
+    ///
+    /// Generation does not stop at the end-of-text token, so that every run does the same
+    /// amount of work. Returns [InferenceError::ContextFull] if the prompt and the generated
+    /// tokens do not fit in the context.
+    fn benchmark(
+        &self,
+        prompt_len: usize,
+        gen_len: usize,
+        params: &InferenceParameters,
+    ) -> Result<BenchmarkReport, InferenceError>
+    where
+        Self: Sized,
+    {
+        if prompt_len + gen_len > self.n_context_tokens() {
+            return Err(InferenceError::ContextFull);
+        }
+
+        // The content of the prompt does not affect the amount of computation.
+        let n_vocab = self.vocabulary().len().max(1);
+        let prompt: Vec<TokenId> = (0..prompt_len).map(|i| (i % n_vocab) as TokenId).collect();
+
+        let mut session = self.start_session(Default::default());
+        let mut stats = InferenceStats::default();
+
+        let start_at = std::time::Instant::now();
+        for batch in prompt.chunks(params.n_batch.max(1)) {
+            self.evaluate(&mut session, params, batch, &mut Default::default());
+            session.tokens.extend_from_slice(batch);
+        }
+        stats.feed_prompt_duration = start_at.elapsed();
+        stats.prompt_tokens = prompt_len;
+
+        let predict_start_at = std::time::Instant::now();
+        for _ in 0..gen_len {
+            let token = session.sample(params);
+            session.tokens.push(token);
+            self.evaluate(&mut session, params, &[token], &mut Default::default());
+        }
+        stats.predict_duration = predict_start_at.elapsed();
+        stats.predict_tokens = gen_len;
+
+        Ok(BenchmarkReport::new(stats))
+    }
+
+    /// Computes the [perplexity](https://en.wikipedia.org/wiki/Perplexity) of the model on
+    /// `tokens`: the exponential of the mean negative log-likelihood of each token, given the
+    /// tokens before it. Lower is better; this is the usual way to compare the quality of
+    /// differently quantized versions of a model.
+    ///
+    /// The first token has nothing to be predicted from, so it is excluded. Sequences longer
+    /// than the context are evaluated in windows of [Model::n_context_tokens] tokens that
+    /// overlap by half, so that every token after the first window is predicted from at
+    /// least half a context. Returns `NaN` if there are fewer than two tokens.
+    fn perplexity(&self, tokens: &[TokenId], params: &InferenceParameters) -> f64
+    where
+        Self: Sized,
+    {
+        let n_ctx = self.n_context_tokens().max(2);
+        let mut nll = 0.0;
+        let mut count = 0;
+        // The index of the first token that has not been predicted yet.
+        let mut next = 1;
+        let mut start = 0;
+        while next < tokens.len() {
+            let window = &tokens[start..tokens.len().min(start + n_ctx)];
+
+            let mut session = self.start_session(Default::default());
+            let mut logits = vec![];
+            for batch in window.chunks(params.n_batch.max(1)) {
+                let mut output_request = OutputRequest {
+                    all_logits: Some(vec![]),
+                    ..Default::default()
+                };
+                self.evaluate(&mut session, params, batch, &mut output_request);
+                logits.extend(output_request.all_logits.unwrap_or_default());
+            }
+
+            // The logits at each position of the window predict the token after it.
+            let n_vocab = logits.len() / window.len();
+            for (i, position) in logits.chunks(n_vocab).enumerate() {
+                let target = start + i + 1;
+                if target < next || target >= start + window.len() {
+                    continue;
+                }
+
+                let max = position.iter().copied().fold(f32::NEG_INFINITY, f32::max) as f64;
+                let sum = position
+                    .iter()
+                    .map(|&logit| (logit as f64 - max).exp())
+                    .sum::<f64>();
+                nll += max + sum.ln() - position[tokens[target] as usize] as f64;
+                count += 1;
+            }
+
+            next = start + window.len();
+            start += n_ctx / 2;
+        }
+
+        (nll / count as f64).exp()
+    }
 }
 
 /// A type-erased model to allow for interacting with a model without knowing
@@ -244,13 +372,31 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
 /// without knowing what they are, as well as writing/reading them as required.
-pub trait Hyperparameters: Sized + Default + Debug {
+pub trait Hyperparameters: Sized + Default + Debug + PartialEq {
     /// Read the parameters in GGML format from a reader.
     fn read_ggml(reader: &mut dyn BufRead) -> Result<Self, LoadError>;
 
     /// Write the parameters in GGML format to a writer.
     fn write_ggml(&self, writer: &mut dyn Write) -> Result<(), HyperparametersWriteError>;
 
+    /// Read the parameters from the named keys of GGUF metadata.
+    ///
+    /// Models that do not support GGUF return [LoadError::GgufUnsupported], which is the default.
+    fn read_gguf(_metadata: &GgufMetadata) -> Result<Self, LoadError> {
+        Err(LoadError::GgufUnsupported)
+    }
+
+    /// Write the parameters to GGUF metadata.
+    ///
+    /// Models that do not support GGUF return [HyperparametersWriteError::GgufUnsupported],
+    /// which is the default.
+    fn write_gguf(
+        &self,
+        _metadata: &mut GgufMetadataBuilder,
+    ) -> Result<(), HyperparametersWriteError> {
+        Err(HyperparametersWriteError::GgufUnsupported)
+    }
+
     /// Get the number of tokens in the vocabulary.
     fn n_vocabulary(&self) -> usize;
 
@@ -269,21 +415,40 @@ pub enum HyperparametersWriteError {
     #[error("invalid integer conversion")]
     /// One of the integers encountered could not be converted to a more appropriate type.
     InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    #[error("the model does not support writing hyperparameters to GGUF metadata")]
+    /// The hyperparameters of this model cannot be written as GGUF metadata.
+    GgufUnsupported,
 }
 
 /// Parameters for tuning model instances
 pub struct ModelParameters {
     /// For [GGML formats](ggml::ContainerType) that support it, [mmap](https://en.wikipedia.org/wiki/Mmap)
     /// is the default. Although mmap typically improves performance, setting this value to `false` may
-    /// be preferred in resource-constrained environments.
+    /// be preferred in resource-constrained environments, or on filesystems where mmap is unreliable.
+    ///
+    /// When mmap is not used, the tensors are read into memory with buffered reads instead. This is
+    /// also the case for containers that do not support mmap, multi-part models, and models
+    /// with [LoRA adapters](Self::lora_adapters), regardless of this value.
     pub prefer_mmap: bool,
     /// The context size ("memory") the model should use when evaluating a prompt. A larger context
     /// consumes more resources, but produces more consistent and coherent responses.
+    ///
+    /// This takes precedence over any context size stored in the model file, and sizes the key/value
+    /// memory of every [InferenceSession] started by the model. Models with learned position
+    /// embeddings (e.g. GPT-2) cannot attend to more tokens than they were trained with, and cap
+    /// this at the context size stored in their file.
     pub n_context_tokens: usize,
     /// Default InferenceParameters to use when [evaluating](Model::evaluate) a prompt with this model.
     pub inference_parameters: InferenceParameters,
-    /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. If `None`, no adapters will be used.
-    pub lora_adapters: Option<Vec<PathBuf>>,
+    /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model, each paired
+    /// with the scale to apply it with. The adapters are applied in order, and their updates accumulate.
+    /// If `None`, no adapters will be used.
+    pub lora_adapters: Option<Vec<(PathBuf, f32)>>,
+    /// The number of layers to offload to the GPU.
+    ///
+    /// `ggml` is only built with its CPU backend, so this currently has no effect and every
+    /// layer is evaluated on the CPU. It defaults to `0`.
+    pub gpu_layers: usize,
 }
 
 impl Default for ModelParameters {
@@ -293,9 +458,71 @@ impl Default for ModelParameters {
             n_context_tokens: 2048,
             inference_parameters: Default::default(),
             lora_adapters: None,
+            gpu_layers: 0,
         }
     }
 }
+impl ModelParameters {
+    /// Creates a [ModelParametersBuilder], starting from the [default](Default) parameters.
+    pub fn builder() -> ModelParametersBuilder {
+        ModelParametersBuilder::default()
+    }
+}
+
+/// Builds [ModelParameters], leaving any unspecified parameter at its default value.
+#[derive(Default)]
+pub struct ModelParametersBuilder(ModelParameters);
+impl ModelParametersBuilder {
+    /// Sets [ModelParameters::n_context_tokens].
+    pub fn context_size(mut self, n_context_tokens: usize) -> Self {
+        self.0.n_context_tokens = n_context_tokens;
+        self
+    }
+
+    /// Sets the number of threads in [ModelParameters::inference_parameters].
+    pub fn n_threads(mut self, n_threads: usize) -> Self {
+        self.0.inference_parameters.n_threads = n_threads;
+        self
+    }
+
+    /// Sets [ModelParameters::prefer_mmap].
+    pub fn use_mmap(mut self, prefer_mmap: bool) -> Self {
+        self.0.prefer_mmap = prefer_mmap;
+        self
+    }
+
+    /// Sets [ModelParameters::gpu_layers], which currently has no effect.
+    pub fn gpu_layers(mut self, gpu_layers: usize) -> Self {
+        self.0.gpu_layers = gpu_layers;
+        self
+    }
+
+    /// Sets [ModelParameters::inference_parameters].
+    pub fn inference_parameters(mut self, inference_parameters: InferenceParameters) -> Self {
+        self.0.inference_parameters = inference_parameters;
+        self
+    }
+
+    /// Sets [ModelParameters::lora_adapters], applying each adapter with a scale of 1.0.
+    pub fn lora_adapters(mut self, lora_adapters: Vec<PathBuf>) -> Self {
+        self.0.lora_adapters = Some(lora_adapters.into_iter().map(|path| (path, 1.0)).collect());
+        self
+    }
+
+    /// Adds a LoRA adapter to [ModelParameters::lora_adapters], to be applied with `scale`.
+    pub fn lora_adapter(mut self, path: PathBuf, scale: f32) -> Self {
+        self.0
+            .lora_adapters
+            .get_or_insert_with(Vec::new)
+            .push((path, scale));
+        self
+    }
+
+    /// Finishes building the [ModelParameters].
+    pub fn build(self) -> ModelParameters {
+        self.0
+    }
+}
 
 /// Used in a call to [Model::evaluate] or [InferenceSession::infer] to request
 /// information from the model. If a value is set to `Some`, the `Vec` will be
@@ -305,9 +532,85 @@ pub struct OutputRequest {
     /// Returns all the logits for evaluation. A logit represents the likelihood
     /// that a given token will be generated based on the tokens that have been
     /// evaluated or generated so far. Output shape is `n_batch * n_vocab`.
+    ///
+    /// Set this to `Some(vec![])` to request the logits of every evaluated position,
+    /// e.g. to compute the perplexity of a text. The logits predicting the token after
+    /// position `i` are at `i * n_vocab..(i + 1) * n_vocab`; only those of the last
+    /// position are kept by the session for sampling.
     pub all_logits: Option<Vec<f32>>,
     /// Returns all the embeddings for an evaluation. An embedding is a vector
     /// that measures the relatedness of text strings. Output shape is
     /// `n_batch * n_embd`.
     pub embeddings: Option<Vec<f32>>,
+    /// Stops the evaluation after the final hidden states, skipping the
+    /// projection onto the vocabulary. This is useful when only
+    /// [Self::embeddings] are wanted, as the projection is often the single most
+    /// expensive part of the evaluation for models with a large vocabulary.
+    ///
+    /// When set, neither [Self::all_logits] nor the session's last logits are
+    /// updated, so the session should not be sampled from afterwards.
+    pub embeddings_only: bool,
+    /// Whether to record the log-probability of each token sampled by
+    /// [InferenceSession::infer_next_token] or [InferenceSession::infer] in
+    /// [Self::logprobs].
+    pub want_logprobs: bool,
+    /// The number of most likely tokens to record as alternatives for each
+    /// sampled token when [Self::want_logprobs] is set.
+    pub n_top_logprobs: usize,
+    /// The log-probabilities of the sampled tokens, in the order in which they
+    /// were sampled.
+    pub logprobs: Vec<TokenLogprob>,
+}
+
+/// The log-probability that the model assigned to a sampled token.
+///
+/// The probabilities are those of the model itself, before any biases,
+/// penalties or temperature were applied during sampling.
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenLogprob {
+    /// The token that was sampled.
+    pub token: TokenId,
+    /// The natural logarithm of the probability of the token.
+    pub logprob: f32,
+    /// The most likely tokens and their log-probabilities, from most to least likely.
+    pub top_alternatives: Vec<(TokenId, f32)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_parameters_builder_defaults() {
+        let params = ModelParameters::builder().context_size(512).build();
+        let defaults = ModelParameters::default();
+
+        assert_eq!(params.n_context_tokens, 512);
+        assert_eq!(params.prefer_mmap, defaults.prefer_mmap);
+        assert_eq!(params.inference_parameters, defaults.inference_parameters);
+        assert_eq!(params.lora_adapters, None);
+        assert_eq!(params.gpu_layers, 0);
+    }
+
+    #[test]
+    fn test_model_parameters_builder_setters() {
+        let params = ModelParameters::builder()
+            .n_threads(2)
+            .use_mmap(false)
+            .gpu_layers(8)
+            .lora_adapters(vec![PathBuf::from("adapter.bin")])
+            .lora_adapter(PathBuf::from("style.bin"), 0.5)
+            .build();
+
+        assert_eq!(params.inference_parameters.n_threads, 2);
+        assert!(!params.prefer_mmap);
+        assert_eq!(params.gpu_layers, 8);
+        assert_eq!(
+            params.lora_adapters,
+            Some(vec![
+                (PathBuf::from("adapter.bin"), 1.0),
+                (PathBuf::from("style.bin"), 0.5)
+            ])
+        );
+    }
 }
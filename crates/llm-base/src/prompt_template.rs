@@ -0,0 +1,176 @@
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The author of a [ChatMessage].
+pub enum ChatRole {
+    /// Instructions that set up the conversation.
+    System,
+    /// A message written by the user.
+    User,
+    /// A message generated by the model.
+    Assistant,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// A single message in a conversation.
+pub struct ChatMessage {
+    /// The author of the message.
+    pub role: ChatRole,
+    /// The text of the message.
+    pub content: String,
+}
+impl ChatMessage {
+    /// Creates a new [ChatMessage] with the given `role` and `content`.
+    pub fn new(role: ChatRole, content: impl Into<String>) -> Self {
+        Self {
+            role,
+            content: content.into(),
+        }
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+/// Errors encountered while rendering a [PromptTemplate].
+pub enum PromptTemplateError {
+    #[error("the conversation must end with a message from the user")]
+    /// The last message of the conversation was not written by the user.
+    MissingUserMessage,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+/// Formats a conversation into the prompt format that a chat model was trained on,
+/// e.g. `### Instruction:` / `### Response:` or `<|user|>` / `<|assistant|>`.
+///
+/// The [template](Self::template) may contain the following placeholders:
+/// - `{system}`: the content of the system messages, joined by newlines.
+/// - `{history}`: every message before the last one, each formatted with
+///   [user_turn](Self::user_turn) or [assistant_turn](Self::assistant_turn).
+/// - `{user}`: the content of the last message, which must be from the user.
+///
+/// The turn templates may contain a `{message}` placeholder for the content of
+/// the message. Placeholders that appear in the messages themselves are not replaced.
+pub struct PromptTemplate {
+    /// The template for the whole prompt.
+    pub template: String,
+    /// The template for each earlier user message in `{history}`.
+    pub user_turn: String,
+    /// The template for each earlier assistant message in `{history}`.
+    pub assistant_turn: String,
+}
+impl PromptTemplate {
+    /// Renders the prompt for the conversation in `messages`, which should end with
+    /// the user message that the model is to respond to.
+    pub fn render(&self, messages: &[ChatMessage]) -> Result<String, PromptTemplateError> {
+        let Some((last, earlier)) = messages.split_last() else {
+            return Err(PromptTemplateError::MissingUserMessage);
+        };
+        if last.role != ChatRole::User {
+            return Err(PromptTemplateError::MissingUserMessage);
+        }
+
+        let system = earlier
+            .iter()
+            .filter(|m| m.role == ChatRole::System)
+            .map(|m| m.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let history: String = earlier
+            .iter()
+            .filter_map(|m| {
+                let turn = match m.role {
+                    ChatRole::System => return None,
+                    ChatRole::User => &self.user_turn,
+                    ChatRole::Assistant => &self.assistant_turn,
+                };
+                Some(fill(turn, &[("message", &m.content)]))
+            })
+            .collect();
+
+        Ok(fill(
+            &self.template,
+            &[
+                ("system", &system),
+                ("history", &history),
+                ("user", &last.content),
+            ],
+        ))
+    }
+}
+
+/// Replaces each `{name}` in `template` with its value in a single pass, so that
+/// the values themselves are never searched for placeholders.
+fn fill(template: &str, values: &[(&str, &str)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let value = values.iter().find_map(|(name, value)| {
+            let placeholder = rest.strip_prefix('{')?.strip_prefix(name)?;
+            placeholder
+                .starts_with('}')
+                .then_some((name.len() + 2, *value))
+        });
+        match value {
+            Some((len, value)) => {
+                output.push_str(value);
+                rest = &rest[len..];
+            }
+            None => {
+                output.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alpaca() -> PromptTemplate {
+        PromptTemplate {
+            template: "{system}\n\n{history}### Instruction:\n{user}\n\n### Response:\n".into(),
+            user_turn: "### Instruction:\n{message}\n\n".into(),
+            assistant_turn: "### Response:\n{message}\n\n".into(),
+        }
+    }
+
+    #[test]
+    fn test_render_two_turn_conversation() {
+        let messages = [
+            ChatMessage::new(ChatRole::System, "You are a helpful assistant."),
+            ChatMessage::new(ChatRole::User, "Hi!"),
+            ChatMessage::new(ChatRole::Assistant, "Hello! How can I help?"),
+            ChatMessage::new(ChatRole::User, "What is {user}?"),
+        ];
+
+        assert_eq!(
+            alpaca().render(&messages).unwrap(),
+            "You are a helpful assistant.\n\n\
+             ### Instruction:\nHi!\n\n\
+             ### Response:\nHello! How can I help?\n\n\
+             ### Instruction:\nWhat is {user}?\n\n\
+             ### Response:\n"
+        );
+    }
+
+    #[test]
+    fn test_render_requires_final_user_message() {
+        let template = alpaca();
+        assert_eq!(
+            template.render(&[]),
+            Err(PromptTemplateError::MissingUserMessage)
+        );
+        assert_eq!(
+            template.render(&[
+                ChatMessage::new(ChatRole::User, "Hi!"),
+                ChatMessage::new(ChatRole::Assistant, "Hello!"),
+            ]),
+            Err(PromptTemplateError::MissingUserMessage)
+        );
+    }
+}
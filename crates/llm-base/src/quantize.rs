@@ -2,9 +2,14 @@
 
 use crate::{
     model::HyperparametersWriteError, Hyperparameters, KnownModel, LoadError, LoadProgress, Loader,
+    ModelLoadError, TensorLoader,
+};
+use ggml::{
+    format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo},
+    Context,
 };
-use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
 use half::f16;
+use memmap2::Mmap;
 use std::{
     collections::HashMap,
     io::{BufRead, Seek, Write},
@@ -134,6 +139,10 @@ impl QuantizeError {
 }
 
 /// Quantizes a model.
+///
+/// The model is read from a GGML, GGMF or GGJT container, and written to
+/// `save_container_type`. Models in a GGUF container cannot be quantized yet, as there
+/// is no reader or writer for GGUF tensors.
 pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     reader: &mut R,
     writer: &mut W,
@@ -269,8 +278,12 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
             element_type: tensor.element_type,
         });
 
-        // Quantize only 2D tensors
-        let quantize = tensor_name.contains("weight") && tensor.n_dims == 2;
+        let quantize = should_quantize(
+            tensor_name,
+            tensor.n_dims,
+            tensor.dims,
+            self.quantization_type,
+        );
         let raw_data = tensor.read_data(self.source_reader)?;
 
         if quantize && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16) {
@@ -284,29 +297,12 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         let (element_type, data) = if quantize {
             (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
 
-            let data_f32: Vec<f32> = match tensor.element_type {
-                ggml::Type::F32 => raw_data
-                    .chunks_exact(4)
-                    .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
-                    .collect(),
-                ggml::Type::F16 => raw_data
-                    .chunks_exact(2)
-                    .map(|chunk| {
-                        f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32()
-                    })
-                    .collect(),
-                _ => unreachable!(),
-            };
-
-            let result = match self.quantization_type {
-                ggml::Type::Q4_0 => {
-                    ggml::quantize_q4_0(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                ggml::Type::Q4_1 => {
-                    ggml::quantize_q4_1(&data_f32, tensor.n_elements, tensor.dims[0])
-                }
-                _ => unreachable!(),
-            };
+            let result = quantize_data(
+                &raw_data,
+                tensor.element_type,
+                self.quantization_type,
+                tensor.dims[0],
+            );
             let new_data = result.output;
 
             let mut history_new = vec![];
@@ -342,3 +338,234 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         })
     }
 }
+
+/// A [TensorLoader] that quantizes the weights loaded by another [TensorLoader] as they
+/// are loaded, so that an F16 or F32 model can be used quantized without first writing a
+/// quantized copy of it with [quantize].
+///
+/// The same tensors are quantized as by [quantize]; all other tensors, including weights
+/// that are already quantized, are copied unchanged. In particular, weights whose rows are
+/// not a multiple of 64 values are never quantized to Q4_0 or Q4_1, as ggml cannot multiply
+/// them. The tensors are held in a [Context] of this loader's own, and the memory of the
+/// `inner` loader is released by [finish](TensorLoader::finish).
+pub struct QuantizingTensorLoader<L: TensorLoader> {
+    inner: L,
+    quantization_type: ggml::Type,
+    context: Context,
+    loaded_tensors: HashMap<String, ggml::Tensor>,
+}
+impl<L: TensorLoader> QuantizingTensorLoader<L> {
+    /// Wraps `inner`, which loads the `tensors` found by a [Loader], so that their weights
+    /// are quantized to `quantization_type`.
+    ///
+    /// Only [Q4_0](ggml::Type::Q4_0) and [Q4_1](ggml::Type::Q4_1) are supported.
+    pub fn new(
+        inner: L,
+        tensors: &HashMap<String, TensorLoadInfo>,
+        quantization_type: ggml::Type,
+    ) -> Result<Self, QuantizeError> {
+        if !matches!(quantization_type, ggml::Type::Q4_0 | ggml::Type::Q4_1) {
+            return Err(QuantizeError::InvalidQuantizationTarget {
+                element_type: quantization_type,
+            });
+        }
+
+        let ctx_size = tensors
+            .values()
+            .map(|info| {
+                let element_type = if can_quantize(
+                    &info.name,
+                    info.n_dims,
+                    info.dims,
+                    info.element_type,
+                    quantization_type,
+                ) {
+                    quantization_type
+                } else {
+                    info.element_type
+                };
+                ggml::format::tensor_size(element_type, info.n_elements)
+            })
+            .sum::<usize>();
+
+        Ok(Self {
+            inner,
+            quantization_type,
+            context: Context::init(ctx_size, true),
+            loaded_tensors: Default::default(),
+        })
+    }
+}
+impl<L: TensorLoader> TensorLoader for QuantizingTensorLoader<L> {
+    fn load(&mut self, name: &str) -> Result<ggml::Tensor, ModelLoadError> {
+        let source = self.inner.load(name)?;
+        let source_type = source.get_type();
+        let n_dims = source.n_dims();
+        let ne = source.dims();
+        let mut data = vec![0u8; source.nbytes()];
+        // SAFETY: `data` is exactly as long as the tensor's data.
+        unsafe { source.read_data(0, &mut data) };
+
+        let (element_type, data) = if can_quantize(
+            name,
+            n_dims,
+            [ne[0], ne[1]],
+            source_type,
+            self.quantization_type,
+        ) {
+            let result = quantize_data(&data, source_type, self.quantization_type, ne[0]);
+            (self.quantization_type, result.output)
+        } else {
+            (source_type, data)
+        };
+
+        let mut tensor = match n_dims {
+            1 => self.context.new_tensor_1d(element_type, ne[0]),
+            2 => self.context.new_tensor_2d(element_type, ne[0], ne[1]),
+            _ => self
+                .context
+                .new_tensor_3d(element_type, ne[0], ne[1], ne[2]),
+        };
+        assert_eq!(tensor.nbytes(), data.len());
+        // SAFETY: the tensor was just created, and is exactly as long as `data`.
+        unsafe { tensor.write_data(&data) };
+        self.loaded_tensors.insert(name.to_owned(), tensor.share());
+
+        Ok(tensor)
+    }
+
+    fn finish(self) -> (Context, HashMap<String, ggml::Tensor>, Option<Mmap>) {
+        // The inner loader only holds the original weights, which have all been copied.
+        drop(self.inner.finish());
+        (self.context, self.loaded_tensors, None)
+    }
+}
+
+/// Quantizes the F32 or F16 `data` of a tensor with rows of `n_elements_0` values to
+/// `quantization_type`.
+fn quantize_data(
+    data: &[u8],
+    element_type: ggml::Type,
+    quantization_type: ggml::Type,
+    n_elements_0: usize,
+) -> ggml::QuantizationResult {
+    let data_f32: Vec<f32> = match element_type {
+        ggml::Type::F32 => data
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        ggml::Type::F16 => data
+            .chunks_exact(2)
+            .map(|chunk| f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32())
+            .collect(),
+        _ => unreachable!(),
+    };
+
+    match quantization_type {
+        ggml::Type::Q4_0 => ggml::quantize_q4_0(&data_f32, data_f32.len(), n_elements_0),
+        ggml::Type::Q4_1 => ggml::quantize_q4_1(&data_f32, data_f32.len(), n_elements_0),
+        _ => unreachable!(),
+    }
+}
+
+/// Whether [QuantizingTensorLoader] quantizes a tensor: it must be a weight matrix that
+/// [should be quantized](should_quantize), and its values must be unquantized floats.
+fn can_quantize(
+    tensor_name: &str,
+    n_dims: usize,
+    dims: [usize; 2],
+    element_type: ggml::Type,
+    quantization_type: ggml::Type,
+) -> bool {
+    matches!(element_type, ggml::Type::F32 | ggml::Type::F16)
+        && should_quantize(tensor_name, n_dims, dims, quantization_type)
+}
+
+/// Only 2D weight matrices are quantized, and only if their rows have a length that
+/// can be stored and multiplied once quantized; everything else keeps its original type.
+fn should_quantize(
+    tensor_name: &str,
+    n_dims: usize,
+    dims: [usize; 2],
+    quantization_type: ggml::Type,
+) -> bool {
+    tensor_name.contains("weight") && n_dims == 2 && dims[0] % row_multiple(quantization_type) == 0
+}
+
+/// The number of values that the rows of a tensor of `element_type` must be a multiple of.
+///
+/// ggml's Q4_0 and Q4_1 dot products work on pairs of blocks, so the GGML format
+/// requires their rows to hold a multiple of 64 values.
+fn row_multiple(element_type: ggml::Type) -> usize {
+    match element_type {
+        ggml::Type::Q4_0 | ggml::Type::Q4_1 => 64,
+        _ => ggml::blck_size(element_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::common::TestTensorLoader;
+
+    #[test]
+    fn test_quantizing_loader_skips_rows_ggml_cannot_multiply() {
+        let shapes = HashMap::from([
+            ("wide.weight".to_owned(), [64, 4]),
+            ("narrow.weight".to_owned(), [32, 4]),
+        ]);
+        let infos = shapes
+            .iter()
+            .map(|(name, &dims)| {
+                let info = TensorLoadInfo {
+                    name: name.clone(),
+                    n_dims: 2,
+                    dims,
+                    n_elements: dims[0] * dims[1],
+                    element_type: ggml::Type::F32,
+                    start_offset: 0,
+                };
+                (name.clone(), info)
+            })
+            .collect::<HashMap<_, _>>();
+        let inner = TestTensorLoader::new(
+            shapes
+                .iter()
+                .map(|(name, dims)| (name.clone(), dims.to_vec())),
+        );
+
+        let mut loader = QuantizingTensorLoader::new(inner, &infos, ggml::Type::Q4_0).unwrap();
+        let wide = loader.load("wide.weight").unwrap();
+        let narrow = loader.load("narrow.weight").unwrap();
+        assert_eq!(wide.get_type(), ggml::Type::Q4_0);
+        assert_eq!(narrow.get_type(), ggml::Type::F32);
+    }
+
+    #[test]
+    fn test_should_quantize() {
+        let q4_0 = ggml::Type::Q4_0;
+        assert!(should_quantize(
+            "layers.0.attention.wq.weight",
+            2,
+            [4096, 4096],
+            q4_0
+        ));
+        // Biases and normalization vectors are left alone.
+        assert!(!should_quantize(
+            "layers.0.attention.wq.bias",
+            2,
+            [4096, 1],
+            q4_0
+        ));
+        assert!(!should_quantize("norm.weight", 1, [4096, 1], q4_0));
+        // Rows that do not fill whole pairs of blocks cannot be stored or multiplied.
+        assert!(!should_quantize("output.weight", 2, [100, 32000], q4_0));
+        assert!(!should_quantize("output.weight", 2, [32, 32000], q4_0));
+        assert!(should_quantize(
+            "output.weight",
+            2,
+            [64, 32000],
+            ggml::Type::Q4_1
+        ));
+    }
+}
@@ -46,6 +46,9 @@ impl TokenUtf8Buffer {
 
     /// Add a token to the buffer. If the buffer contains a valid string of UTF-8 text,
     /// it is returned and the buffer is cleared for next use.
+    ///
+    /// If the buffer ends with an incomplete UTF-8 character, the complete characters
+    /// before it are returned and the incomplete bytes are held back until the next token.
     pub fn push(&mut self, token: &[u8]) -> Option<String> {
         self.0.extend_from_slice(token);
         match std::str::from_utf8(&self.0) {
@@ -54,6 +57,12 @@ impl TokenUtf8Buffer {
                 self.0 = vec![];
                 Some(out)
             }
+            Err(e) if e.error_len().is_none() && e.valid_up_to() > 0 => {
+                let rest = self.0.split_off(e.valid_up_to());
+                let out = String::from_utf8(std::mem::replace(&mut self.0, rest))
+                    .expect("the prefix was validated as UTF-8");
+                Some(out)
+            }
             Err(..) => {
                 for i in 1..self.0.len() {
                     let slice = &self.0[i..];
@@ -152,6 +161,24 @@ pub fn softmax(logits: &[f32]) -> Vec<f32> {
     probs
 }
 
+/// Combine the logits of a conditional and an unconditional (negative) context for
+/// classifier-free guidance, as `unconditional + scale * (conditional - unconditional)`.
+///
+/// A scale of 1 reproduces the conditional logits, and larger scales push the
+/// result further away from the unconditional logits.
+pub fn guided_logits(conditional: &[f32], unconditional: &[f32], scale: f32) -> Vec<f32> {
+    assert_eq!(
+        conditional.len(),
+        unconditional.len(),
+        "the conditional and unconditional logits must have the same length"
+    );
+    conditional
+        .iter()
+        .zip(unconditional)
+        .map(|(&c, &u)| u + scale * (c - u))
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -177,6 +204,25 @@ mod tests {
         assert_eq!(expected_paths.as_slice(), output_paths);
     }
 
+    #[test]
+    fn test_guided_logits() {
+        let conditional = [1.0, -2.0, 0.5];
+        let unconditional = [0.0, 1.0, 0.5];
+
+        assert_eq!(
+            guided_logits(&conditional, &unconditional, 1.0),
+            conditional
+        );
+        assert_eq!(
+            guided_logits(&conditional, &unconditional, 0.0),
+            unconditional
+        );
+        assert_eq!(
+            guided_logits(&conditional, &unconditional, 2.0),
+            vec![2.0, -5.0, 0.5]
+        );
+    }
+
     #[test]
     fn test_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -191,6 +237,14 @@ mod tests {
         assert_eq!(buffer.push(&[0xAC]).as_deref(), Some("€"));
     }
 
+    #[test]
+    fn test_partial_utf8_after_complete_characters() {
+        let mut buffer = TokenUtf8Buffer::new();
+        assert_eq!(buffer.push(&[b'a', 0xE2, 0x82]).as_deref(), Some("a"));
+        assert_eq!(buffer.push(&[0xAC]).as_deref(), Some("€"));
+        assert_eq!(buffer.push(b"b").as_deref(), Some("b"));
+    }
+
     #[test]
     fn test_invalid_prelude_for_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -1,7 +1,9 @@
-use std::{collections::HashMap, error::Error, fmt::Display, str::FromStr};
+use std::{collections::HashMap, error::Error, fmt::Display, ops::Range, str::FromStr};
 
 use thiserror::Error;
 
+use crate::{GgufMetadata, GgufMetadataValue, LoadError};
+
 /// The identifier of a token in a vocabulary.
 pub type TokenId = i32;
 pub(crate) type Token = Vec<u8>;
@@ -34,6 +36,53 @@ pub struct Vocabulary {
 
     /// The longest token in this vocabulary.
     pub max_token_length: usize,
+
+    /// Maps the ID of every token that is not a [TokenType::Normal] token to its type.
+    pub token_types: HashMap<TokenId, TokenType>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a token, as stored in the `tokenizer.ggml.token_type` metadata of GGUF models.
+pub enum TokenType {
+    /// A token that represents text.
+    Normal,
+    /// The token that stands in for unknown input.
+    Unknown,
+    /// A token with a special meaning, e.g. the beginning of text, that does not represent text.
+    Control,
+    /// A token that was added to the vocabulary by the user.
+    UserDefined,
+    /// A token that is never produced by the tokenizer.
+    Unused,
+    /// A byte-fallback token of the form `<0xXX>`.
+    Byte,
+}
+impl From<TokenType> for i32 {
+    fn from(value: TokenType) -> Self {
+        match value {
+            TokenType::Normal => 1,
+            TokenType::Unknown => 2,
+            TokenType::Control => 3,
+            TokenType::UserDefined => 4,
+            TokenType::Unused => 5,
+            TokenType::Byte => 6,
+        }
+    }
+}
+impl TryFrom<i32> for TokenType {
+    type Error = ();
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        Ok(match value {
+            1 => TokenType::Normal,
+            2 => TokenType::Unknown,
+            3 => TokenType::Control,
+            4 => TokenType::UserDefined,
+            5 => TokenType::Unused,
+            6 => TokenType::Byte,
+            _ => return Err(()),
+        })
+    }
 }
 
 impl Vocabulary {
@@ -59,11 +108,136 @@ impl Vocabulary {
         self.token_to_id.insert(content, id);
     }
 
+    /// Builds a vocabulary from the tokenizer stored in GGUF `metadata`.
+    ///
+    /// The tokens are read from `tokenizer.ggml.tokens`, and their scores and types from the
+    /// optional `tokenizer.ggml.scores` and `tokenizer.ggml.token_type` arrays. GGUF stores
+    /// tokens as text, so they are converted back to the bytes they represent according to
+    /// `tokenizer.ggml.model`: `llama` (SentencePiece) tokens use `▁` for spaces, and `gpt2`
+    /// tokens use GPT-2's mapping of bytes to printable characters.
+    pub fn from_gguf(metadata: &GgufMetadata) -> Result<Self, LoadError> {
+        const TOKENS_KEY: &str = "tokenizer.ggml.tokens";
+        const SCORES_KEY: &str = "tokenizer.ggml.scores";
+        const TYPES_KEY: &str = "tokenizer.ggml.token_type";
+        const MODEL_KEY: &str = "tokenizer.ggml.model";
+
+        /// Gets the array `key`, if present, which must have an entry for each of the `n` tokens.
+        fn optional_array<'a>(
+            metadata: &'a GgufMetadata,
+            key: &str,
+            n: usize,
+        ) -> Result<Option<&'a [GgufMetadataValue]>, LoadError> {
+            if metadata.get(key).is_none() {
+                return Ok(None);
+            }
+            let values = metadata.get_array(key)?;
+            if values.len() != n {
+                return Err(LoadError::InvariantBroken {
+                    path: None,
+                    invariant: format!("{key} has {n} entries, one per token"),
+                });
+            }
+            Ok(Some(values))
+        }
+
+        let tokens = metadata.get_array(TOKENS_KEY)?;
+        let scores = optional_array(metadata, SCORES_KEY, tokens.len())?;
+        let types = optional_array(metadata, TYPES_KEY, tokens.len())?;
+        let model = metadata
+            .get(MODEL_KEY)
+            .map(|_| metadata.get_str(MODEL_KEY))
+            .transpose()?;
+
+        let invalid_element = |key: &str, i: usize, value: &GgufMetadataValue, expected| {
+            LoadError::InvalidMetadataType {
+                key: format!("{key}[{i}]"),
+                expected,
+                value: value.clone(),
+            }
+        };
+
+        let mut vocabulary = Self::default();
+        for (i, value) in tokens.iter().enumerate() {
+            let id = TokenId::try_from(i)?;
+            let GgufMetadataValue::String(token) = value else {
+                return Err(invalid_element(TOKENS_KEY, i, value, "string"));
+            };
+            let score = match scores.map(|scores| &scores[i]) {
+                None => 0.0,
+                Some(GgufMetadataValue::Float32(score)) => *score,
+                Some(value) => return Err(invalid_element(SCORES_KEY, i, value, "f32")),
+            };
+            let token_type = match types.map(|types| &types[i]) {
+                None => TokenType::Normal,
+                Some(value @ GgufMetadataValue::Int32(token_type)) => {
+                    TokenType::try_from(*token_type)
+                        .map_err(|_| invalid_element(TYPES_KEY, i, value, "token type"))?
+                }
+                Some(value) => return Err(invalid_element(TYPES_KEY, i, value, "i32")),
+            };
+
+            let token = match model {
+                Some("llama") => token.replace('\u{2581}', " ").into_bytes(),
+                Some("gpt2") => decode_byte_level(token),
+                _ => token.as_bytes().to_vec(),
+            };
+            vocabulary.push_token(id, token, score);
+            if token_type != TokenType::Normal {
+                vocabulary.token_types.insert(id, token_type);
+            }
+        }
+
+        Ok(vocabulary)
+    }
+
+    /// Returns the [TokenType] of the token `id`, or `None` if `id` is not in the vocabulary.
+    pub fn token_type(&self, id: TokenId) -> Option<TokenType> {
+        let index = usize::try_from(id).ok()?;
+        (index < self.id_to_token.len()).then(|| {
+            self.token_types
+                .get(&id)
+                .copied()
+                .unwrap_or(TokenType::Normal)
+        })
+    }
+
     /// Converts a token index to the token it represents in this vocabulary.
     pub fn token(&self, idx: usize) -> &[u8] {
         &self.id_to_token[idx]
     }
 
+    /// Decodes a sequence of token IDs into the bytes they represent.
+    ///
+    /// Byte-fallback tokens of the form `<0xXX>`, as used by SentencePiece vocabularies,
+    /// are decoded to the raw byte they represent rather than their textual form.
+    ///
+    /// # Panics
+    /// - This function will panic if any of the `tokens` are not in the vocabulary.
+    pub fn decode(&self, tokens: &[TokenId]) -> Vec<u8> {
+        let mut bytes = vec![];
+        for &token_id in tokens {
+            let token = self
+                .token_bytes(token_id)
+                .unwrap_or_else(|| panic!("the token ID {token_id} is not in the vocabulary"));
+            bytes.extend_from_slice(token);
+        }
+        bytes
+    }
+
+    /// Returns the bytes that the token `id` represents, or `None` if `id` is not in the
+    /// vocabulary.
+    ///
+    /// As with [decode](Self::decode), byte-fallback tokens are decoded to the raw byte they
+    /// represent. The bytes of a single token need not be valid UTF-8; use a
+    /// [TokenUtf8Buffer](crate::TokenUtf8Buffer) to assemble them into text when streaming.
+    pub fn token_bytes(&self, id: TokenId) -> Option<&[u8]> {
+        let token = self.id_to_token.get(usize::try_from(id).ok()?)?;
+        Some(match byte_fallback(token) {
+            Some(byte) => std::slice::from_ref(&ALL_BYTES[byte as usize]),
+            None => token,
+        })
+    }
+
     /// Returns the number of tokens in the vocabulary.
     pub fn len(&self) -> usize {
         self.id_to_token.len()
@@ -130,6 +304,145 @@ impl Vocabulary {
 
         Ok(res)
     }
+
+    /// Tokenize a `text` with this vocabulary, returning each token along with the
+    /// range of bytes of `text` that it covers.
+    ///
+    /// The ranges are contiguous and cover all of `text`. If `bos` is set, the
+    /// beginning-of-string token is given the empty range `0..0`.
+    pub fn tokenize_with_spans(
+        &self,
+        text: &str,
+        bos: bool,
+    ) -> Result<Vec<(TokenId, Range<usize>)>, TokenizationError> {
+        let mut start = 0;
+        Ok(self
+            .tokenize(text, bos)?
+            .into_iter()
+            .map(|(token, id)| {
+                let span = start..start + token.len();
+                start = span.end;
+                (id, span)
+            })
+            .collect())
+    }
+
+    /// Tokenize a `text` with this vocabulary using up to `n_threads` threads, producing
+    /// the same tokens as [Self::tokenize].
+    ///
+    /// The text is split at whitespace that no token in the vocabulary can span, so that
+    /// each chunk is tokenized exactly as it would be as part of the whole text. If there
+    /// are no such split points, or a chunk cannot be tokenized on its own, the text is
+    /// tokenized on the current thread instead.
+    pub fn tokenize_parallel<'a>(
+        &'a self,
+        text: &str,
+        bos: bool,
+        n_threads: usize,
+    ) -> Result<Vec<(&'a [u8], TokenId)>, TokenizationError> {
+        let splits = self.parallel_split_points(text, n_threads);
+        if splits.is_empty() {
+            return self.tokenize(text, bos);
+        }
+
+        let bounds = std::iter::once(0)
+            .chain(splits)
+            .chain(std::iter::once(text.len()))
+            .collect::<Vec<_>>();
+        let chunks = std::thread::scope(|scope| {
+            let handles = bounds
+                .windows(2)
+                .map(|bounds| {
+                    let chunk = &text[bounds[0]..bounds[1]];
+                    scope.spawn(move || self.tokenize(chunk, false))
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("tokenizer thread panicked"))
+                .collect::<Result<Vec<_>, _>>()
+        });
+        let Ok(chunks) = chunks else {
+            return self.tokenize(text, bos);
+        };
+
+        let mut res = Vec::with_capacity(chunks.iter().map(Vec::len).sum::<usize>() + 1);
+        if bos {
+            // TODO: replace with vocab.bos
+            res.push((&[][..], 1));
+        }
+        res.extend(chunks.into_iter().flatten());
+        Ok(res)
+    }
+
+    /// Finds up to `n_threads - 1` positions at which `text` can be split into chunks of
+    /// roughly equal size that can be tokenized independently.
+    fn parallel_split_points(&self, text: &str, n_threads: usize) -> Vec<usize> {
+        let bytes = text.as_bytes();
+        let mut splits: Vec<usize> = vec![];
+        for i in 1..n_threads {
+            let target = (text.len() * i / n_threads).max(splits.last().map_or(1, |&s| s + 1));
+            let next_target = text.len() * (i + 1) / n_threads;
+            // Splitting before an ASCII whitespace byte never splits a codepoint.
+            let split = (target..next_target)
+                .find(|&b| bytes[b].is_ascii_whitespace() && !self.can_span(bytes, b));
+            splits.extend(split);
+        }
+        splits
+    }
+
+    /// Returns whether any token in this vocabulary covers both `bytes[b - 1]` and `bytes[b]`.
+    fn can_span(&self, bytes: &[u8], b: usize) -> bool {
+        let max_len = self.max_token_length;
+        (b.saturating_sub(max_len.saturating_sub(1))..b).any(|start| {
+            ((b + 1)..=(start + max_len).min(bytes.len()))
+                .any(|end| self.token_to_id.contains_key(&bytes[start..end]))
+        })
+    }
+}
+
+/// Converts a token of a GPT-2 style byte-level BPE vocabulary back to its bytes.
+///
+/// Such vocabularies represent the printable bytes as themselves, and map every other byte,
+/// in increasing order, to the characters from U+0100 onwards.
+fn decode_byte_level(token: &str) -> Vec<u8> {
+    let is_printable = |b: u8| matches!(b, b'!'..=b'~' | 0xA1..=0xAC | 0xAE..=0xFF);
+
+    let mut bytes = vec![];
+    for c in token.chars() {
+        let byte = match u32::from(c) {
+            c @ 0..=0xFF if is_printable(c as u8) => Some(c as u8),
+            c @ 0x100..=0x1FF => (0..=u8::MAX)
+                .filter(|&b| !is_printable(b))
+                .nth((c - 0x100) as usize),
+            _ => None,
+        };
+        match byte {
+            Some(byte) => bytes.push(byte),
+            None => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    bytes
+}
+
+/// Every byte value, indexed by itself, so that decoded byte-fallback tokens can be borrowed.
+const ALL_BYTES: [u8; 256] = {
+    let mut bytes = [0; 256];
+    let mut i = 0;
+    while i < bytes.len() {
+        bytes[i] = i as u8;
+        i += 1;
+    }
+    bytes
+};
+
+/// Returns the byte represented by a byte-fallback token (`<0xXX>`), if `token` is one.
+fn byte_fallback(token: &[u8]) -> Option<u8> {
+    let hex = token.strip_prefix(b"<0x")?.strip_suffix(b">")?;
+    if hex.len() != 2 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -287,3 +600,167 @@ impl std::fmt::Display for TokenBias {
         write!(f, "{:?}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_byte_fallback_tokens() {
+        let mut vocabulary = Vocabulary::default();
+        for (id, token) in ["<unk>", "<0xE2>", "<0x82>", "<0xAC>", "price", "<0x0A>"]
+            .into_iter()
+            .enumerate()
+        {
+            vocabulary.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+
+        let bytes = vocabulary.decode(&[4, 1, 2, 3, 5]);
+        assert_eq!(String::from_utf8(bytes).unwrap(), "price€\n");
+
+        assert_eq!(vocabulary.token_bytes(4), Some(&b"price"[..]));
+        assert_eq!(vocabulary.token_bytes(1), Some(&[0xE2][..]));
+        assert_eq!(vocabulary.token_bytes(6), None);
+        assert_eq!(vocabulary.token_bytes(-1), None);
+    }
+
+    #[test]
+    fn test_vocabulary_from_gguf() {
+        let strings = |values: &[&str]| {
+            GgufMetadataValue::Array(values.iter().map(|v| v.to_string().into()).collect())
+        };
+        let types = [
+            TokenType::Unknown,
+            TokenType::Control,
+            TokenType::Normal,
+            TokenType::Byte,
+        ];
+        let mut builder = crate::GgufMetadataBuilder::new();
+        builder
+            .insert("tokenizer.ggml.model", "llama".to_string())
+            .insert(
+                "tokenizer.ggml.tokens",
+                strings(&["<unk>", "<s>", "\u{2581}hello", "<0x0A>"]),
+            )
+            .insert(
+                "tokenizer.ggml.scores",
+                GgufMetadataValue::Array(vec![
+                    0.0f32.into(),
+                    0.0f32.into(),
+                    (-1.5f32).into(),
+                    0.0f32.into(),
+                ]),
+            )
+            .insert(
+                "tokenizer.ggml.token_type",
+                GgufMetadataValue::Array(types.iter().map(|&t| i32::from(t).into()).collect()),
+            );
+        let vocabulary = Vocabulary::from_gguf(&builder.build()).unwrap();
+
+        assert_eq!(vocabulary.len(), 4);
+        assert_eq!(vocabulary.token(2), b" hello");
+        assert_eq!(vocabulary.id_to_token_score, vec![0.0, 0.0, -1.5, 0.0]);
+        assert_eq!(vocabulary.decode(&[2, 3]), b" hello\n");
+        for (id, token_type) in types.into_iter().enumerate() {
+            assert_eq!(vocabulary.token_type(id as TokenId), Some(token_type));
+        }
+        assert_eq!(vocabulary.token_type(4), None);
+
+        // Byte-level BPE vocabularies map bytes such as spaces to printable characters.
+        let mut builder = crate::GgufMetadataBuilder::new();
+        builder
+            .insert("tokenizer.ggml.model", "gpt2".to_string())
+            .insert("tokenizer.ggml.tokens", strings(&["\u{120}hello", "!"]));
+        let vocabulary = Vocabulary::from_gguf(&builder.build()).unwrap();
+        assert_eq!(vocabulary.token(0), b" hello");
+        assert_eq!(vocabulary.token(1), b"!");
+
+        // Every token must have a score.
+        let mut builder = crate::GgufMetadataBuilder::new();
+        builder
+            .insert("tokenizer.ggml.tokens", strings(&["a", "b"]))
+            .insert(
+                "tokenizer.ggml.scores",
+                GgufMetadataValue::Array(vec![0.0f32.into()]),
+            );
+        assert!(matches!(
+            Vocabulary::from_gguf(&builder.build()),
+            Err(LoadError::InvariantBroken { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tokenize_parallel_matches_serial() {
+        let mut vocabulary = Vocabulary::default();
+        let tokens = [
+            "<unk>", "<s>", " the", "the ", "e q", "quick", "fox\n", "é", "ét",
+        ]
+        .into_iter()
+        .map(String::from)
+        .chain(
+            (b' '..=b'~')
+                .chain([b'\n'])
+                .map(|b| (b as char).to_string()),
+        );
+        for (id, token) in tokens.enumerate() {
+            vocabulary.push_token(id as TokenId, token.into_bytes(), 0.0);
+        }
+
+        let text = "The quick brown fox\njumps over the lazy dog. Décor vu, état the quick one. "
+            .repeat(200);
+        assert!(!vocabulary.parallel_split_points(&text, 4).is_empty());
+
+        let serial = vocabulary.tokenize(&text, true).unwrap();
+        for n_threads in [1, 2, 4, 7] {
+            let parallel = vocabulary
+                .tokenize_parallel(&text, true, n_threads)
+                .unwrap();
+            assert_eq!(parallel, serial);
+        }
+    }
+
+    #[test]
+    fn test_tokenize_with_spans() {
+        let mut vocabulary = Vocabulary::default();
+        for (id, token) in ["<unk>", "<s>", "h", "he", "llo", " ", "wor", "ld", "é"]
+            .into_iter()
+            .enumerate()
+        {
+            vocabulary.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+
+        let text = "hello world é";
+        let tokens = vocabulary.tokenize_with_spans(text, true).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (1, 0..0),
+                (3, 0..2),
+                (4, 2..5),
+                (5, 5..6),
+                (6, 6..9),
+                (7, 9..11),
+                (5, 11..12),
+                (8, 12..14),
+            ]
+        );
+
+        // The spans are contiguous and cover the whole input.
+        let mut end = 0;
+        for (id, span) in &tokens[1..] {
+            assert_eq!(span.start, end);
+            assert_eq!(vocabulary.decode(&[*id]), text.as_bytes()[span.clone()]);
+            end = span.end;
+        }
+        assert_eq!(end, text.len());
+    }
+
+    #[test]
+    fn test_byte_fallback_only_matches_byte_tokens() {
+        assert_eq!(byte_fallback(b"<0x41>"), Some(0x41));
+        assert_eq!(byte_fallback(b"<0x4>"), None);
+        assert_eq!(byte_fallback(b"<0xZZ>"), None);
+        assert_eq!(byte_fallback(b"<0x+4>"), None);
+        assert_eq!(byte_fallback(b"0x41"), None);
+    }
+}
@@ -114,6 +114,12 @@ fn load_progress_callback(
             "ggml ctx size = {}",
             bytesize::to_string(bytes as u64, false)
         ),
+        LoadProgress::FileTypeMismatch {
+            file_type,
+            weight_type,
+        } => log::warn!(
+            "The model's file type is {file_type}, but its weights are mostly {weight_type}"
+        ),
         LoadProgress::TensorLoaded {
             current_tensor,
             tensor_count,
@@ -0,0 +1,291 @@
+//! Downloading models from the [Hugging Face Hub](https://huggingface.co/models).
+//!
+//! This is only available with the `hf` feature.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{KnownModel, LoadError, LoadProgress, ModelParameters};
+
+/// The size of the chunks in which downloads are written to disk and reported.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+/// The progress of a download by [download_from_hf] or [download_from_url].
+pub struct DownloadProgress {
+    /// The number of bytes that have been downloaded so far, including those of a
+    /// previous, interrupted download that is being resumed.
+    pub downloaded: u64,
+    /// The total size of the file, if the server reported it.
+    pub total: Option<u64>,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while downloading a model.
+pub enum DownloadError {
+    #[error("the request for {url} failed")]
+    /// The HTTP request failed.
+    Http {
+        /// The URL that was requested.
+        url: String,
+        /// The original error.
+        source: Box<ureq::Error>,
+    },
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] io::Error),
+    #[error("downloaded {actual} bytes to {path:?}, but expected {expected} bytes")]
+    /// The downloaded file did not have the size reported by the server. The partial
+    /// download is kept, and will be resumed by the next download of the same file.
+    SizeMismatch {
+        /// The path of the partial download.
+        path: PathBuf,
+        /// The size reported by the server.
+        expected: u64,
+        /// The size of the downloaded file.
+        actual: u64,
+    },
+    #[error("could not load the downloaded model")]
+    /// The downloaded model could not be loaded.
+    Load(#[from] LoadError),
+}
+
+/// The URL from which `file` in the Hugging Face repository `repo` (e.g. `TheBloke/Llama-2-7B-GGML`)
+/// can be downloaded.
+pub fn hf_url(repo: &str, file: &str) -> String {
+    format!("https://huggingface.co/{repo}/resolve/main/{file}")
+}
+
+/// Downloads `file` from the Hugging Face repository `repo` into `cache_dir`, and returns
+/// its path. If the file has already been downloaded, it is not downloaded again.
+///
+/// See [download_from_url] for details on how the download is performed.
+pub fn download_from_hf(
+    repo: &str,
+    file: &str,
+    cache_dir: &Path,
+    progress_callback: impl FnMut(DownloadProgress),
+) -> Result<PathBuf, DownloadError> {
+    let path = cache_dir.join(repo.replace('/', "--")).join(file);
+    download_from_url(&hf_url(repo, file), &path, progress_callback)?;
+    Ok(path)
+}
+
+/// Downloads the file at `url` to `path`, unless `path` already exists.
+///
+/// The file is first downloaded to `path` with a `.partial` extension added, and only
+/// moved to `path` once its size has been checked against the size reported by the server.
+/// If a partial download already exists, it is resumed where it left off if the server
+/// supports it.
+pub fn download_from_url(
+    url: &str,
+    path: &Path,
+    mut progress_callback: impl FnMut(DownloadProgress),
+) -> Result<(), DownloadError> {
+    if path.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut partial_path = path.as_os_str().to_owned();
+    partial_path.push(".partial");
+    let partial_path = PathBuf::from(partial_path);
+
+    let resume_from = fs::metadata(&partial_path).map(|m| m.len()).unwrap_or(0);
+    let http_error = |source| DownloadError::Http {
+        url: url.to_owned(),
+        source: Box::new(source),
+    };
+    let mut request = ureq::get(url);
+    if resume_from > 0 {
+        request = request.set("Range", &format!("bytes={resume_from}-"));
+    }
+    let response = match request.call() {
+        Ok(response) => response,
+        // The partial download can not be resumed (e.g. because it is already complete,
+        // or the file has changed), so start over.
+        Err(ureq::Error::Status(416, _)) => {
+            fs::remove_file(&partial_path)?;
+            return download_from_url(url, path, progress_callback);
+        }
+        Err(err) => return Err(http_error(err)),
+    };
+
+    // Servers that do not support ranges respond with the whole file.
+    let resumed = response.status() == 206;
+    let mut downloaded = if resumed { resume_from } else { 0 };
+    let total = response
+        .header("Content-Length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .map(|length| downloaded + length);
+
+    let mut file = if resumed {
+        OpenOptions::new().append(true).open(&partial_path)?
+    } else {
+        File::create(&partial_path)?
+    };
+    let mut reader = response.into_reader();
+    let mut buf = vec![0; CHUNK_SIZE];
+    progress_callback(DownloadProgress { downloaded, total });
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            // The connection was closed early; the size check below reports this.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        file.write_all(&buf[..n])?;
+        downloaded += n as u64;
+        progress_callback(DownloadProgress { downloaded, total });
+    }
+    file.flush()?;
+    drop(file);
+
+    let actual = fs::metadata(&partial_path)?.len();
+    if let Some(expected) = total {
+        if actual != expected {
+            return Err(DownloadError::SizeMismatch {
+                path: partial_path,
+                expected,
+                actual,
+            });
+        }
+    }
+
+    fs::rename(&partial_path, path)?;
+    Ok(())
+}
+
+/// Downloads `file` from the Hugging Face repository `repo` into `cache_dir` with
+/// [download_from_hf], and then loads it with [load](crate::load).
+pub fn load_from_hf<M: KnownModel>(
+    repo: &str,
+    file: &str,
+    cache_dir: &Path,
+    params: ModelParameters,
+    overrides: Option<M::Overrides>,
+    download_progress_callback: impl FnMut(DownloadProgress),
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, DownloadError> {
+    let path = download_from_hf(repo, file, cache_dir, download_progress_callback)?;
+    Ok(crate::load(
+        &path,
+        params,
+        overrides,
+        load_progress_callback,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader},
+        net::TcpListener,
+        thread::JoinHandle,
+    };
+
+    /// Serves `body` over HTTP to `requests` clients, honouring `Range` headers. If
+    /// `truncate_to` is set, the connection is closed after that many bytes of the body.
+    fn serve(
+        body: Vec<u8>,
+        requests: usize,
+        truncate_to: Option<usize>,
+    ) -> (String, JoinHandle<Vec<Option<u64>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/blob.bin", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let mut ranges = vec![];
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(start) = line
+                        .strip_prefix("Range: bytes=")
+                        .or_else(|| line.strip_prefix("range: bytes="))
+                    {
+                        range = Some(start.trim_end_matches('-').parse::<u64>().unwrap());
+                    }
+                }
+                ranges.push(range);
+
+                let start = range.unwrap_or(0) as usize;
+                let status = if range.is_some() {
+                    "206 Partial Content"
+                } else {
+                    "200 OK"
+                };
+                let content = &body[start..];
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    content.len()
+                )
+                .unwrap();
+                let content = match truncate_to {
+                    Some(n) => &content[..n],
+                    None => content,
+                };
+                stream.write_all(content).unwrap();
+            }
+            ranges
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_download_is_validated_and_resumed() {
+        let body = (0..=255)
+            .cycle()
+            .take(3 * CHUNK_SIZE / 2)
+            .collect::<Vec<u8>>();
+        let dir = std::env::temp_dir().join(format!("llm-hf-test-{}", std::process::id()));
+        let path = dir.join("blob.bin");
+        let _ = fs::remove_dir_all(&dir);
+
+        // The first attempt is cut short, which is caught by the size check.
+        let (url, server) = serve(body.clone(), 1, Some(1000));
+        let err = download_from_url(&url, &path, |_| {}).unwrap_err();
+        assert!(
+            matches!(err, DownloadError::SizeMismatch { expected, actual: 1000, .. } if expected == body.len() as u64),
+            "{err:?}"
+        );
+        assert_eq!(server.join().unwrap(), vec![None]);
+        assert!(!path.exists());
+
+        // The second attempt resumes where the first left off.
+        let (url, server) = serve(body.clone(), 1, None);
+        let mut progress = vec![];
+        download_from_url(&url, &path, |p| progress.push(p)).unwrap();
+        assert_eq!(server.join().unwrap(), vec![Some(1000)]);
+        assert_eq!(fs::read(&path).unwrap(), body);
+        assert_eq!(
+            progress.first(),
+            Some(&DownloadProgress {
+                downloaded: 1000,
+                total: Some(body.len() as u64)
+            })
+        );
+        assert_eq!(
+            progress.last().map(|p| p.downloaded),
+            Some(body.len() as u64)
+        );
+
+        // The downloaded file is reused without making a request.
+        download_from_url("http://127.0.0.1:1/unreachable", &path, |_| {}).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
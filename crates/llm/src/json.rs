@@ -0,0 +1,154 @@
+//! Writing generated tokens as [newline-delimited JSON](http://ndjson.org/), e.g. to stream
+//! them to a client as server-sent events.
+//!
+//! This is only available with the `json` feature.
+
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{InferenceFeedback, InferenceResponse};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// A generated token, written as a single line of JSON such as `{"token":" world"}`.
+pub struct TokenEvent {
+    /// The text of the token.
+    pub token: String,
+    /// The log-probability of the token, if it is known. Left out of the JSON otherwise.
+    ///
+    /// If the text is made up of several tokens, e.g. because a character was split
+    /// across them, this is the sum of their log-probabilities.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub logprob: Option<f32>,
+}
+
+/// Writes `event` to `writer` as a single line of JSON, and flushes it so that it reaches
+/// the client immediately.
+pub fn write_event(writer: &mut impl Write, event: &TokenEvent) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, event)?;
+    writer.write_all(b"\n")?;
+    writer.flush()
+}
+
+/// Creates a callback for [InferenceSession::infer](crate::InferenceSession::infer) that
+/// writes every inferred token to `writer` as a [TokenEvent]. Prompt and snapshot tokens
+/// are not written.
+///
+/// The events include the log-probabilities of the tokens if they were requested with
+/// [OutputRequest::want_logprobs](crate::OutputRequest::want_logprobs).
+pub fn ndjson_callback<W: Write>(
+    mut writer: W,
+) -> impl FnMut(InferenceResponse) -> Result<InferenceFeedback, io::Error> {
+    let mut logprob = None;
+    move |response| {
+        match response {
+            InferenceResponse::InferredTokenLogprob(token_logprob) => {
+                *logprob.get_or_insert(0.0) += token_logprob.logprob;
+            }
+            InferenceResponse::InferredToken(token) => {
+                write_event(
+                    &mut writer,
+                    &TokenEvent {
+                        token,
+                        logprob: logprob.take(),
+                    },
+                )?;
+            }
+            _ => {}
+        }
+        Ok(InferenceFeedback::Continue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use llm_base::model::common::ScriptedModel;
+
+    use super::*;
+    use crate::{InferenceRequest, Model, OutputRequest};
+
+    fn generate(want_logprobs: bool) -> (Vec<TokenEvent>, OutputRequest) {
+        let model = ScriptedModel::new(vec![3, 4, 5, 0]);
+        let mut session = model.start_session(Default::default());
+        let mut output_request = OutputRequest {
+            want_logprobs,
+            ..Default::default()
+        };
+        let mut output = vec![];
+        session
+            .infer(
+                &model,
+                &mut rand::thread_rng(),
+                &InferenceRequest {
+                    prompt: "h".into(),
+                    ..Default::default()
+                },
+                &mut output_request,
+                ndjson_callback(&mut output),
+            )
+            .unwrap();
+
+        let events = String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<TokenEvent>(line).unwrap())
+            .collect();
+        (events, output_request)
+    }
+
+    #[test]
+    fn test_generated_tokens_are_written_as_ndjson() {
+        let (events, _) = generate(false);
+        assert_eq!(
+            events,
+            vec![
+                TokenEvent {
+                    token: "é".to_owned(),
+                    logprob: None,
+                },
+                TokenEvent {
+                    token: "i".to_owned(),
+                    logprob: None,
+                },
+            ]
+        );
+
+        let mut output = vec![];
+        write_event(
+            &mut output,
+            &TokenEvent {
+                token: " \"world\"\n".to_owned(),
+                logprob: Some(-0.5),
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            output,
+            b"{\"token\":\" \\\"world\\\"\\n\",\"logprob\":-0.5}\n"
+        );
+    }
+
+    #[test]
+    fn test_requested_logprobs_are_written() {
+        // "é" is made up of the first two generated tokens, and "i" of the third.
+        let (events, output_request) = generate(true);
+        let logprobs = output_request
+            .logprobs
+            .iter()
+            .map(|logprob| logprob.logprob)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                TokenEvent {
+                    token: "é".to_owned(),
+                    logprob: Some(logprobs[0] + logprobs[1]),
+                },
+                TokenEvent {
+                    token: "i".to_owned(),
+                    logprob: Some(logprobs[2]),
+                },
+            ]
+        );
+    }
+}
@@ -75,16 +75,27 @@ use std::{
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
     feed_prompt_callback, ggml::format as ggml_format, load, load_progress_callback_stdout,
-    quantize, ElementType, FileType, FileTypeFormat, InferenceError, InferenceFeedback,
-    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
+    quantize, BenchmarkReport, ChatMessage, ChatRole, ContextOverflowPolicy, ElementType, FileType,
+    FileTypeFormat, GgufMetadata, GgufMetadataBuilder, GgufMetadataValue, InferenceError,
+    InferenceFeedback, InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
     InferenceSessionConfig, InferenceSnapshot, InferenceStats, InvalidTokenBias, KnownModel,
     LoadError, LoadProgress, Loader, Model, ModelDynamicOverrideValue, ModelDynamicOverrides,
-    ModelKVMemoryType, ModelParameters, OutputRequest, Prompt, QuantizeError, QuantizeProgress,
-    SnapshotError, TokenBias, TokenId, TokenUtf8Buffer, TokenizationError, Vocabulary,
+    ModelKVMemoryType, ModelLoadError, ModelParameters, ModelParametersBuilder, OutputRequest,
+    ParameterError, Prompt, PromptTemplate, PromptTemplateError, QuantizeError, QuantizeProgress,
+    QuantizingTensorLoader, RewindError, SnapshotError, TokenBias, TokenId, TokenLogprob,
+    TokenType, TokenUtf8Buffer, TokenizationError, Vocabulary,
 };
 
 use serde::Serialize;
 
+#[cfg(feature = "hf")]
+pub mod hf;
+#[cfg(feature = "hf")]
+pub use hf::load_from_hf;
+
+#[cfg(feature = "json")]
+pub mod json;
+
 /// All available models.
 pub mod models {
     #[cfg(feature = "bloom")]
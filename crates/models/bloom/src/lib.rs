@@ -5,10 +5,15 @@
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    Mmap, ModelParameters, OutputRequest, TokenId, Vocabulary,
+    util, FileType, GgufMetadata, GgufMetadataBuilder, InferenceParameters, InferenceSession,
+    InferenceSessionConfig, KnownModel, Mmap, ModelLoadError, ModelParameters, OutputRequest,
+    TensorLoader, TokenId, Vocabulary,
 };
 
+/// The maximum ALiBi bias used by BLOOM, which gives head `h` of `n` a slope of
+/// `2^(-8h/n)`.
+const ALIBI_BIAS_MAX: f32 = 8.0;
+
 /// The BLOOM model. Ref: [Introducing BLOOM](https://bigscience.huggingface.co/blog/bloom)
 ///
 /// # Safety
@@ -39,16 +44,19 @@ impl KnownModel for Bloom {
     type Hyperparameters = Hyperparameters;
     type Overrides = ();
 
-    fn new<E: std::error::Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
         _overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl llm_base::TensorLoader<E>,
-    ) -> Result<Self, E> {
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError> {
         let mut tl = tensor_loader;
 
-        let tok_embeddings = tl.load("tok_embeddings.weight")?;
+        let tok_embeddings = tl.load_with_shape(
+            "tok_embeddings.weight",
+            &[hyperparameters.n_embd, hyperparameters.n_vocab],
+        )?;
 
         let norm = tl.load("norm.weight")?;
         let norm_b = tl.load("norm.bias")?;
@@ -109,6 +117,38 @@ impl KnownModel for Bloom {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        let mut names = vec![
+            "tok_embeddings.weight".to_owned(),
+            "norm.weight".to_owned(),
+            "norm.bias".to_owned(),
+            "output_norm.weight".to_owned(),
+            "output_norm.bias".to_owned(),
+            "output.weight".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("layers.{i}.attention_norm.weight"),
+                format!("layers.{i}.attention_norm.bias"),
+                format!("layers.{i}.attention.query_key_value.weight"),
+                format!("layers.{i}.attention.query_key_value.bias"),
+                format!("layers.{i}.attention.wo.weight"),
+                format!("layers.{i}.attention.wo.bias"),
+                format!("layers.{i}.ffn_norm.weight"),
+                format!("layers.{i}.ffn_norm.bias"),
+                format!("layers.{i}.feed_forward.w1.weight"),
+                format!("layers.{i}.feed_forward.w1.bias"),
+                format!("layers.{i}.feed_forward.w2.weight"),
+                format!("layers.{i}.feed_forward.w2.bias"),
+            ]);
+        }
+        names
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -157,6 +197,8 @@ impl KnownModel for Bloom {
             let input_self_attention = input_layer.share();
             let mut current: ggml::Tensor;
 
+            ctx0.use_scratch(Some(&mut session.scratch[0]));
+
             // norm
             {
                 current = ctx0.op_norm(&input_layer);
@@ -256,20 +298,18 @@ impl KnownModel for Bloom {
                 let k_q = ctx0.op_mul_mat(&big_k, &big_q);
 
                 // KQ_scaled = KQ / sqrt(n_embd/n_head)
-                let k_q_scaled = ctx0.op_scale(
-                    &k_q,
-                    &ctx0.new_f32(1.0 / f32::sqrt(n_embd as f32 / n_head as f32)),
-                );
+                let k_q_scaled =
+                    ctx0.op_scale_f32(&k_q, 1.0 / f32::sqrt(n_embd as f32 / n_head as f32));
 
                 //alibi
                 // KQ_scaled_alibi = KQ_scaled + alibi_bias
-                let k_q_scaled_alibi = ctx0.op_alibi(&k_q_scaled, n_past, n_head, 8f32);
+                let k_q_scaled_alibi = ctx0.op_alibi(&k_q_scaled, n_past, n_head, ALIBI_BIAS_MAX);
 
                 // KQ_masked = mask_past(KQ_scaled)
                 let k_q_masked = ctx0.op_diag_mask_inf(&k_q_scaled_alibi, n_past);
 
                 // KQ = soft_max(KQ_masked)
-                let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                let k_q_soft_max = ctx0.op_soft_max_inplace(&k_q_masked);
 
                 let memv_elsize = session.memory_v.element_size();
 
@@ -314,6 +354,8 @@ impl KnownModel for Bloom {
                 current = ctx0.op_add(&ctx0.op_repeat(&self.layers[il].wo_b, &current), &current);
             }
 
+            ctx0.use_scratch(Some(&mut session.scratch[1]));
+
             let input_feed_forward = ctx0.op_add(&current, &input_self_attention);
 
             // feed-forward network
@@ -353,6 +395,8 @@ impl KnownModel for Bloom {
             input_layer = current;
         }
 
+        ctx0.use_scratch(Some(&mut session.scratch[0]));
+
         // norm
         {
             input_layer = ctx0.op_norm(&input_layer);
@@ -369,19 +413,26 @@ impl KnownModel for Bloom {
             );
         }
 
+        // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor = input_layer.share();
+
         // lm_head
-        {
+        if !output_request.embeddings_only {
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
         }
 
+        ctx0.use_scratch(None);
+
         // run the computation
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -451,6 +502,32 @@ impl llm_base::Hyperparameters for Hyperparameters {
         Ok(())
     }
 
+    fn read_gguf(metadata: &GgufMetadata) -> Result<Self, llm_base::LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.get_countable("bloom.vocab_size")?,
+            n_embd: metadata.get_countable("bloom.embedding_length")?,
+            // `n_mult` is not used by BLOOM, and has no GGUF equivalent.
+            n_mult: 0,
+            n_head: metadata.get_countable("bloom.attention.head_count")?,
+            n_layer: metadata.get_countable("bloom.block_count")?,
+            file_type: metadata.get_file_type()?,
+        })
+    }
+
+    fn write_gguf(
+        &self,
+        metadata: &mut GgufMetadataBuilder,
+    ) -> Result<(), HyperparametersWriteError> {
+        metadata
+            .insert("general.architecture", "bloom".to_string())
+            .insert("bloom.vocab_size", u32::try_from(self.n_vocab)?)
+            .insert("bloom.embedding_length", u32::try_from(self.n_embd)?)
+            .insert("bloom.attention.head_count", u32::try_from(self.n_head)?)
+            .insert("bloom.block_count", u32::try_from(self.n_layer)?)
+            .insert_file_type(self.file_type);
+        Ok(())
+    }
+
     fn n_vocabulary(&self) -> usize {
         self.n_vocab
     }
@@ -480,3 +557,130 @@ struct Layer {
     pub w2: ggml::Tensor,
     pub w2_b: ggml::Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use llm_base::{FileTypeFormat, Hyperparameters as _};
+
+    /// The shapes of the tensors of a model with the given `hyperparameters`.
+    fn tensor_shapes(hyperparameters: &Hyperparameters) -> Vec<(String, Vec<usize>)> {
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = *hyperparameters;
+        Bloom::tensor_names(hyperparameters)
+            .into_iter()
+            .map(|name| {
+                let shape = match name.as_str() {
+                    "tok_embeddings.weight" | "output.weight" => vec![n_embd, n_vocab],
+                    name if name.ends_with("query_key_value.weight") => vec![n_embd, 3 * n_embd],
+                    name if name.ends_with("query_key_value.bias") => vec![3 * n_embd],
+                    name if name.ends_with("wo.weight") => vec![n_embd, n_embd],
+                    name if name.ends_with("w1.weight") => vec![n_embd, 4 * n_embd],
+                    name if name.ends_with("w1.bias") => vec![4 * n_embd],
+                    name if name.ends_with("w2.weight") => vec![4 * n_embd, n_embd],
+                    _ => vec![n_embd],
+                };
+                (name, shape)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loaded_model_exposes_the_file_hyperparameters() {
+        // The hyperparameters of `bigscience/bloom-560m`, converted to F16, as they are laid
+        // out in its GGML file.
+        let file: Vec<u8> = [250880i32, 1024, 1, 16, 24, 1]
+            .into_iter()
+            .flat_map(i32::to_le_bytes)
+            .collect();
+        let hyperparameters = Hyperparameters::read_ggml(&mut &file[..]).unwrap();
+
+        let model = Bloom::new(
+            hyperparameters,
+            ModelParameters::default(),
+            None,
+            Vocabulary::default(),
+            // The tensors of a real model are too large to fill in a test.
+            common::TestTensorLoader::new(tensor_shapes(&hyperparameters))
+                .with_weight_type(ggml::Type::F16)
+                .without_data(),
+        )
+        .unwrap();
+
+        let loaded = model.hyperparameters();
+        assert_eq!(loaded.n_vocab, 250880);
+        assert_eq!(loaded.n_embd, 1024);
+        assert_eq!(loaded.n_mult, 1);
+        assert_eq!(loaded.n_head, 16);
+        assert_eq!(loaded.n_layer, 24);
+        assert_eq!(loaded.n_vocabulary(), 250880);
+        assert_eq!(
+            loaded.file_type(),
+            Some(FileType {
+                format: FileTypeFormat::MostlyF16,
+                quantization_version: 0,
+            })
+        );
+        assert_eq!(model.layers.len(), 24);
+
+        let mut written = vec![];
+        loaded.write_ggml(&mut written).unwrap();
+        assert_eq!(written, file);
+    }
+
+    #[test]
+    fn can_read_hyperparameters_from_gguf_metadata() {
+        let file_type = FileType {
+            format: FileTypeFormat::MostlyQ4_0,
+            quantization_version: 2,
+        };
+        let mut builder = GgufMetadataBuilder::new();
+        builder
+            .insert("general.architecture", "bloom".to_string())
+            .insert("bloom.vocab_size", 250880u32)
+            .insert("bloom.embedding_length", 1024u32)
+            .insert("bloom.attention.head_count", 16u32)
+            .insert("bloom.block_count", 24u32)
+            .insert_file_type(file_type);
+        let metadata = builder.build();
+
+        let hyperparameters = Hyperparameters::read_gguf(&metadata).unwrap();
+        assert_eq!(
+            hyperparameters,
+            Hyperparameters {
+                n_vocab: 250880,
+                n_embd: 1024,
+                n_mult: 0,
+                n_head: 16,
+                n_layer: 24,
+                file_type,
+            }
+        );
+
+        let mut builder = GgufMetadataBuilder::new();
+        hyperparameters.write_gguf(&mut builder).unwrap();
+        assert_eq!(builder.build(), metadata);
+    }
+
+    #[test]
+    fn tensor_names_cover_every_layer() {
+        let hyperparameters = Hyperparameters {
+            n_layer: 2,
+            ..Default::default()
+        };
+        let names = Bloom::tensor_names(&hyperparameters);
+
+        assert_eq!(names.len(), 6 + 2 * 12);
+        for name in [
+            "tok_embeddings.weight",
+            "output.weight",
+            "layers.0.attention.wo.weight",
+            "layers.1.attention.wo.weight",
+            "layers.1.feed_forward.w2.bias",
+        ] {
+            assert!(names.iter().any(|n| n == name), "missing {name}");
+        }
+        assert!(!names.iter().any(|n| n.starts_with("layers.2.")));
+    }
+}
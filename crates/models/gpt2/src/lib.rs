@@ -6,7 +6,7 @@ use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
     util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, ModelParameters, OutputRequest, TokenId, Vocabulary,
+    LoadError, ModelLoadError, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
 };
 
 /// The GPT-2 model. Ref: [The Illustrated GPT-2](https://jalammar.github.io/illustrated-gpt2/)
@@ -34,20 +34,26 @@ impl KnownModel for Gpt2 {
     type Hyperparameters = Hyperparameters;
     type Overrides = ();
 
-    fn new<E: std::error::Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
         _overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl llm_base::TensorLoader<E>,
-    ) -> Result<Self, E> {
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError> {
         let mut tl = tensor_loader;
         // prepare memory for weights
         let ln_f_g = tl.load("model/ln_f/g")?;
         let ln_f_b = tl.load("model/ln_f/b")?;
         let wte = tl.load("model/wte")?;
         let wpe = tl.load("model/wpe")?;
-        let lm_head = tl.load("model/lm_head")?;
+        // GPT-2 ties its language modelling head to the token embeddings, so
+        // checkpoints converted from the original weights may not include it.
+        let lm_head = match tl.load("model/lm_head") {
+            Ok(lm_head) => lm_head,
+            Err(ModelLoadError::MissingTensor { .. }) => wte.share(),
+            Err(err) => return Err(err),
+        };
 
         let mut layers = Vec::new();
         for i in 0..hyperparameters.n_layer {
@@ -79,7 +85,9 @@ impl KnownModel for Gpt2 {
 
         Ok(Gpt2 {
             hyperparameters,
-            n_context_tokens,
+            // GPT-2 learns an embedding for each position, so it cannot attend to
+            // more tokens than it has position embeddings for.
+            n_context_tokens: n_context_tokens.min(hyperparameters.n_ctx),
             vocabulary,
             layers,
             ln_f_g,
@@ -93,10 +101,41 @@ impl KnownModel for Gpt2 {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        // `model/lm_head` is optional, as it is tied to `model/wte`.
+        let mut names = vec![
+            "model/ln_f/g".to_owned(),
+            "model/ln_f/b".to_owned(),
+            "model/wte".to_owned(),
+            "model/wpe".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("model/h{i}/ln_1/g"),
+                format!("model/h{i}/ln_1/b"),
+                format!("model/h{i}/ln_2/g"),
+                format!("model/h{i}/ln_2/b"),
+                format!("model/h{i}/attn/c_attn/w"),
+                format!("model/h{i}/attn/c_attn/b"),
+                format!("model/h{i}/attn/c_proj/w"),
+                format!("model/h{i}/attn/c_proj/b"),
+                format!("model/h{i}/mlp/c_fc/w"),
+                format!("model/h{i}/mlp/c_fc/b"),
+                format!("model/h{i}/mlp/c_proj/w"),
+                format!("model/h{i}/mlp/c_proj/b"),
+            ]);
+        }
+        names
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
-            self.hyperparameters.n_ctx,
+            self.n_context_tokens,
             self.hyperparameters.n_layer,
             self.hyperparameters.n_embd,
             self.hyperparameters.n_vocab,
@@ -148,6 +187,8 @@ impl KnownModel for Gpt2 {
         let mut gf = ggml::ComputationGraph::new(n_threads);
 
         for il in 0..n_layer {
+            ctx0.use_scratch(Some(&mut session.scratch[0]));
+
             // norm
             let mut current = ctx0.op_norm(&input_layer);
             current = ctx0.op_add(
@@ -254,6 +295,8 @@ impl KnownModel for Gpt2 {
                 &current,
             );
 
+            ctx0.use_scratch(Some(&mut session.scratch[1]));
+
             // add input
             current = ctx0.op_add(&current, &input_layer);
 
@@ -288,6 +331,8 @@ impl KnownModel for Gpt2 {
             input_layer = ctx0.op_add(&current, &ff_in);
         }
 
+        ctx0.use_scratch(Some(&mut session.scratch[0]));
+
         // normalization
         input_layer = ctx0.op_norm(&input_layer);
         input_layer = ctx0.op_add(
@@ -295,16 +340,25 @@ impl KnownModel for Gpt2 {
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
-        input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
+        // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor = input_layer.share();
+
+        if !output_request.embeddings_only {
+            input_layer = ctx0.op_mul_mat(&self.lm_head, &input_layer);
+        }
+
+        ctx0.use_scratch(None);
 
         // run the computation
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -313,7 +367,7 @@ impl KnownModel for Gpt2 {
     }
 
     fn n_context_tokens(&self) -> usize {
-        self.hyperparameters.n_ctx
+        self.n_context_tokens
     }
 
     fn bot_token_id(&self) -> Option<TokenId> {
@@ -421,3 +475,131 @@ struct Layer {
     c_mlp_proj_w: Tensor,
     c_mlp_proj_b: Tensor,
 }
+
+#[cfg(test)]
+impl Gpt2 {
+    /// This does *not* construct a valid model. All of the tensors are entirely
+    /// empty. However, it can be used to determine if some code will compile.
+    fn new_empty() -> Self {
+        let context = ggml::Context::init(1024 * 1024, true);
+
+        Self {
+            hyperparameters: Default::default(),
+            n_context_tokens: 0,
+            vocabulary: Default::default(),
+            ln_f_g: context.new_f32(0.0),
+            ln_f_b: context.new_f32(0.0),
+            wte: context.new_f32(0.0),
+            wpe: context.new_f32(0.0),
+            lm_head: context.new_f32(0.0),
+            layers: Default::default(),
+            inference_params: Default::default(),
+            _mmap: Default::default(),
+            _context: context,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// The shapes of the tensors of a model with the given `hyperparameters`, including
+    /// the optional `model/lm_head`.
+    fn tensor_shapes(hyperparameters: &Hyperparameters) -> HashMap<String, Vec<usize>> {
+        let Hyperparameters {
+            n_vocab,
+            n_ctx,
+            n_embd,
+            ..
+        } = *hyperparameters;
+
+        let mut shapes: HashMap<String, Vec<usize>> = Gpt2::tensor_names(hyperparameters)
+            .into_iter()
+            .map(|name| {
+                let shape = match name.rsplit_once("model/").unwrap().1 {
+                    "wte" => vec![n_embd, n_vocab],
+                    "wpe" => vec![n_embd, n_ctx],
+                    name if name.ends_with("c_attn/w") => vec![n_embd, 3 * n_embd],
+                    name if name.ends_with("c_attn/b") => vec![3 * n_embd],
+                    name if name.ends_with("attn/c_proj/w") => vec![n_embd, n_embd],
+                    name if name.ends_with("c_fc/w") => vec![n_embd, 4 * n_embd],
+                    name if name.ends_with("c_fc/b") => vec![4 * n_embd],
+                    name if name.ends_with("mlp/c_proj/w") => vec![4 * n_embd, n_embd],
+                    _ => vec![n_embd],
+                };
+                (name, shape)
+            })
+            .collect();
+        shapes.insert("model/lm_head".to_owned(), vec![n_embd, n_vocab]);
+        shapes
+    }
+
+    #[test]
+    fn lm_head_falls_back_to_token_embeddings() {
+        let hyperparameters = Hyperparameters {
+            n_vocab: 8,
+            n_ctx: 16,
+            n_embd: 8,
+            n_head: 2,
+            n_layer: 1,
+            file_type: Default::default(),
+        };
+        let load = |tl| {
+            Gpt2::new(
+                hyperparameters,
+                ModelParameters::default(),
+                None,
+                Default::default(),
+                tl,
+            )
+        };
+        let logits = |model: &Gpt2| {
+            let mut session = model.start_session(Default::default());
+            model.evaluate(
+                &mut session,
+                &InferenceParameters::default(),
+                &[1, 4, 2],
+                &mut OutputRequest::default(),
+            );
+            session.last_logits
+        };
+
+        // The test loader gives `model/lm_head` the same values as `model/wte`, so
+        // tying them must not change the logits.
+        let shapes = tensor_shapes(&hyperparameters);
+        let with_lm_head = load(common::TestTensorLoader::new(shapes.clone())).unwrap();
+        let mut without = shapes.clone();
+        without.remove("model/lm_head");
+        let without_lm_head = load(common::TestTensorLoader::new(without)).unwrap();
+
+        let expected = logits(&with_lm_head);
+        assert!(expected.iter().all(|x| x.is_finite()));
+        assert!(expected.iter().any(|&x| x != 0.0));
+        assert_eq!(logits(&without_lm_head), expected);
+
+        // Every other tensor is still required.
+        let mut without = shapes;
+        without.remove("model/h0/mlp/c_fc/b");
+        assert!(matches!(
+            load(common::TestTensorLoader::new(without)),
+            Err(ModelLoadError::MissingTensor { name }) if name == "model/h0/mlp/c_fc/b"
+        ));
+    }
+
+    #[test]
+    fn session_memory_matches_context_size() {
+        let mut model = Gpt2::new_empty();
+        model.hyperparameters = Hyperparameters {
+            n_ctx: 1024,
+            n_embd: 8,
+            n_layer: 2,
+            ..Default::default()
+        };
+        model.n_context_tokens = 16;
+
+        assert_eq!(model.n_context_tokens(), 16);
+        common::assert_session_memory_matches_context_size(&model, 2, 8);
+    }
+}
@@ -1,14 +1,13 @@
 //! An implementation of [GPT-J](https://huggingface.co/docs/transformers/model_doc/gptj) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::error::Error;
-
 use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
     util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
+    LoadError, Mmap, ModelLoadError, ModelParameters, OutputRequest, TensorLoader, TokenId,
+    Vocabulary,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -25,7 +24,7 @@ pub struct GptJ {
     ln_f_g: Tensor,
     ln_f_b: Tensor,
 
-    // position embedding
+    // token embedding
     wte: Tensor,
 
     // language model head & bias
@@ -49,13 +48,13 @@ impl KnownModel for GptJ {
     type Hyperparameters = Hyperparameters;
     type Overrides = ();
 
-    fn new<E: Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
         _overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl TensorLoader<E>,
-    ) -> Result<Self, E>
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError>
     where
         Self: Sized,
     {
@@ -110,10 +109,39 @@ impl KnownModel for GptJ {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        let mut names = vec![
+            "transformer.wte.weight".to_owned(),
+            "transformer.ln_f.weight".to_owned(),
+            "transformer.ln_f.bias".to_owned(),
+            "lm_head.weight".to_owned(),
+            "lm_head.bias".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("transformer.h.{i}.ln_1.weight"),
+                format!("transformer.h.{i}.ln_1.bias"),
+                format!("transformer.h.{i}.attn.q_proj.weight"),
+                format!("transformer.h.{i}.attn.k_proj.weight"),
+                format!("transformer.h.{i}.attn.v_proj.weight"),
+                format!("transformer.h.{i}.attn.out_proj.weight"),
+                format!("transformer.h.{i}.mlp.fc_in.weight"),
+                format!("transformer.h.{i}.mlp.fc_in.bias"),
+                format!("transformer.h.{i}.mlp.fc_out.weight"),
+                format!("transformer.h.{i}.mlp.fc_out.bias"),
+            ]);
+        }
+        names
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
-            self.hyperparameters.n_ctx,
+            self.n_context_tokens,
             self.hyperparameters.n_layer,
             self.hyperparameters.n_embd,
             self.hyperparameters.n_vocab,
@@ -156,6 +184,8 @@ impl KnownModel for GptJ {
         let mut gf = ggml::ComputationGraph::new(n_threads);
 
         for il in 0..n_layer {
+            ctx0.use_scratch(Some(&mut session.scratch[0]));
+
             // norm
             let mut current = ctx0.op_norm(&input_layer);
             current = ctx0.op_add(
@@ -253,8 +283,13 @@ impl KnownModel for GptJ {
             // self-attention projection
             current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
 
+            ctx0.use_scratch(Some(&mut session.scratch[1]));
+
             // feed-forward
-            let ff_in = current.share();
+            //
+            // The residual is added to the attention output first, so that the input of this
+            // layer is read before it is overwritten in the scratch buffer.
+            let ff_in = ctx0.op_add(&current, &input_layer);
 
             current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &input_sa);
             current = ctx0.op_add(
@@ -271,12 +306,12 @@ impl KnownModel for GptJ {
                 &current,
             );
 
-            current = ctx0.op_add(&current, &ff_in);
-
             // input for next layer
-            input_layer = ctx0.op_add(&current, &input_layer);
+            input_layer = ctx0.op_add(&current, &ff_in);
         }
 
+        ctx0.use_scratch(Some(&mut session.scratch[0]));
+
         // norm
         input_layer = ctx0.op_norm(&input_layer);
         input_layer = ctx0.op_add(
@@ -284,18 +319,27 @@ impl KnownModel for GptJ {
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
+        // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor = input_layer.share();
+
         // lm_head
-        input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
-        input_layer = ctx0.op_add(&ctx0.op_repeat(&self.lmh_b, &input_layer), &input_layer);
+        if !output_request.embeddings_only {
+            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+            input_layer = ctx0.op_add(&ctx0.op_repeat(&self.lmh_b, &input_layer), &input_layer);
+        }
+
+        ctx0.use_scratch(None);
 
         // run the computation
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -304,7 +348,7 @@ impl KnownModel for GptJ {
     }
 
     fn n_context_tokens(&self) -> usize {
-        self.hyperparameters.n_ctx
+        self.n_context_tokens
     }
 
     fn bot_token_id(&self) -> Option<TokenId> {
@@ -359,7 +403,7 @@ impl llm_base::Hyperparameters for Hyperparameters {
             return Err(LoadError::InvariantBroken {
                 path: None,
                 invariant: format!(
-                    "GPT2 model expected n_vocab {} found {}",
+                    "GPT-J model expected n_vocab {} found {}",
                     hyperparameters.n_vocab, n_vocab
                 ),
             });
@@ -457,4 +501,19 @@ mod tests {
             let _session = session;
         });
     }
+
+    #[test]
+    fn session_memory_matches_context_size() {
+        let mut model = GptJ::new_empty();
+        model.hyperparameters = Hyperparameters {
+            n_ctx: 2048,
+            n_embd: 8,
+            n_layer: 2,
+            ..Default::default()
+        };
+        model.n_context_tokens = 16;
+
+        assert_eq!(model.n_context_tokens(), 16);
+        common::assert_session_memory_matches_context_size(&model, 2, 8);
+    }
 }
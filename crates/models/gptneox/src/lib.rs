@@ -2,14 +2,13 @@
 //! This crate also supports the [RedPajama](https://www.together.xyz/blog/redpajama) GPT-NeoX model.
 #![deny(missing_docs)]
 
-use std::error::Error;
-
 use ggml::Tensor;
 use llm_base::{
     ggml::{self, ElementType},
     model::{common, HyperparametersWriteError},
     util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
+    LoadError, Mmap, ModelLoadError, ModelParameters, OutputRequest, TensorLoader, TokenId,
+    Vocabulary,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -50,13 +49,13 @@ impl KnownModel for GptNeoX {
     type Hyperparameters = Hyperparameters;
     type Overrides = ();
 
-    fn new<E: Error>(
+    fn new(
         hyperparameters: Hyperparameters,
         params: ModelParameters,
         _overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl TensorLoader<E>,
-    ) -> Result<Self, E>
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError>
     where
         Self: Sized,
     {
@@ -124,10 +123,40 @@ impl KnownModel for GptNeoX {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        let mut names = vec![
+            "gpt_neox.embed_in.weight".to_owned(),
+            "gpt_neox.final_layer_norm.weight".to_owned(),
+            "gpt_neox.final_layer_norm.bias".to_owned(),
+            "embed_out.weight".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("gpt_neox.layers.{i}.input_layernorm.weight"),
+                format!("gpt_neox.layers.{i}.input_layernorm.bias"),
+                format!("gpt_neox.layers.{i}.attention.query_key_value.weight"),
+                format!("gpt_neox.layers.{i}.attention.query_key_value.bias"),
+                format!("gpt_neox.layers.{i}.attention.dense.weight"),
+                format!("gpt_neox.layers.{i}.attention.dense.bias"),
+                format!("gpt_neox.layers.{i}.post_attention_layernorm.weight"),
+                format!("gpt_neox.layers.{i}.post_attention_layernorm.bias"),
+                format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight"),
+                format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias"),
+                format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight"),
+                format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias"),
+            ]);
+        }
+        names
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
-            self.hyperparameters.n_ctx,
+            self.n_context_tokens,
             self.hyperparameters.n_layer,
             self.hyperparameters.n_embd,
             self.hyperparameters.n_vocab,
@@ -171,6 +200,8 @@ impl KnownModel for GptNeoX {
         let mut gf = ggml::ComputationGraph::new(n_threads);
 
         for il in 0..n_layer {
+            ctx0.use_scratch(Some(&mut session.scratch[0]));
+
             // self-attention
             let mut current = ctx0.op_norm(&input_layer);
             current = ctx0.op_add(
@@ -321,13 +352,13 @@ impl KnownModel for GptNeoX {
                 &current,
             );
 
+            ctx0.use_scratch(Some(&mut session.scratch[1]));
+
             // feed-forward
-            let ff_in = if use_parallel_residual {
-                current.share()
-            } else {
-                let out_attn = current.share();
-                ctx0.op_add(&out_attn, &input_layer)
-            };
+            //
+            // The residual is added to the attention output first, so that the input of this
+            // layer is read before it is overwritten in the scratch buffer.
+            let ff_in = ctx0.op_add(&current, &input_layer);
 
             // feed-forward post attention layer norm
             if use_parallel_residual {
@@ -355,32 +386,37 @@ impl KnownModel for GptNeoX {
                 &current,
             );
 
-            if use_parallel_residual {
-                current = ctx0.op_add(&current, &ff_in);
-                // input for next layer
-                input_layer = ctx0.op_add(&current, &input_layer);
-            } else {
-                // input for next layer
-                input_layer = ctx0.op_add(&ff_in, &current);
-            }
+            // input for next layer
+            input_layer = ctx0.op_add(&current, &ff_in);
         }
 
+        ctx0.use_scratch(Some(&mut session.scratch[0]));
+
         input_layer = ctx0.op_norm(&input_layer);
         input_layer = ctx0.op_add(
             &ctx0.op_mul(&ctx0.op_repeat(&self.ln_f_g, &input_layer), &input_layer),
             &ctx0.op_repeat(&self.ln_f_b, &input_layer),
         );
 
-        input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+        // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor = input_layer.share();
+
+        if !output_request.embeddings_only {
+            input_layer = ctx0.op_mul_mat(&self.lmh_g, &input_layer);
+        }
+
+        ctx0.use_scratch(None);
 
         // run the computation
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -389,7 +425,7 @@ impl KnownModel for GptNeoX {
     }
 
     fn n_context_tokens(&self) -> usize {
-        self.hyperparameters.n_ctx
+        self.n_context_tokens
     }
 
     fn bot_token_id(&self) -> Option<TokenId> {
@@ -552,4 +588,20 @@ mod tests {
             let _session = session;
         });
     }
+
+    #[test]
+    fn session_memory_matches_context_size() {
+        let mut model = GptNeoX::new_empty();
+        model.hyperparameters = Hyperparameters {
+            n_ctx: 2048,
+            n_embd: 8,
+            n_layer: 2,
+            ..Default::default()
+        };
+        // Rotary position embeddings allow a larger context than the one trained with.
+        model.n_context_tokens = 4096;
+
+        assert_eq!(model.n_context_tokens(), 4096);
+        common::assert_session_memory_matches_context_size(&model, 2, 8);
+    }
 }
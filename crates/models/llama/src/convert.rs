@@ -63,6 +63,7 @@ fn load_vocabulary(path: &Path) -> Vocabulary {
         id_to_token_score,
         token_to_id,
         max_token_length,
+        token_types: HashMap::new(),
     }
 }
 
@@ -1,15 +1,14 @@
 //! An implementation of [LLaMA](https://huggingface.co/docs/transformers/model_doc/llama) for the `llm` ecosystem.
 #![deny(missing_docs)]
 
-use std::error::Error;
-
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
     util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, LoadProgress, Mmap, ModelParameters, OutputRequest, TensorLoader, TokenId,
-    Vocabulary,
+    LoadError, LoadProgress, Mmap, ModelDynamicOverrides, ModelLoadError, ModelParameters,
+    OutputRequest, TensorLoader, TokenId, Vocabulary,
 };
+use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "convert")]
 pub mod convert;
@@ -23,6 +22,9 @@ mod old_loader;
 pub struct Llama {
     hyperparameters: Hyperparameters,
     n_context_tokens: usize,
+    /// The number of key/value heads, which is less than `n_head` with grouped-query
+    /// attention. Read from the shape of the key weights.
+    n_head_kv: usize,
 
     vocabulary: Vocabulary,
 
@@ -46,15 +48,15 @@ unsafe impl Sync for Llama {}
 
 impl KnownModel for Llama {
     type Hyperparameters = Hyperparameters;
-    type Overrides = ();
+    type Overrides = LlamaOverrides;
 
-    fn new<E: Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
-        _overrides: Option<Self::Overrides>,
+        overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl TensorLoader<E>,
-    ) -> Result<Self, E> {
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError> {
         let mut tl = tensor_loader;
 
         let tok_embeddings = tl.load("tok_embeddings.weight")?;
@@ -78,6 +80,8 @@ impl KnownModel for Llama {
             layers.push(layer);
         }
 
+        let n_head_kv = n_head_kv(&hyperparameters, &layers, overrides.unwrap_or_default())?;
+
         let (_context, _tensors, _mmap) = tl.finish();
 
         let ModelParameters {
@@ -89,6 +93,7 @@ impl KnownModel for Llama {
         Ok(Self {
             hyperparameters,
             n_context_tokens,
+            n_head_kv,
             vocabulary,
             tok_embeddings,
             norm,
@@ -100,13 +105,56 @@ impl KnownModel for Llama {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        let mut names = vec![
+            "tok_embeddings.weight".to_owned(),
+            "norm.weight".to_owned(),
+            "output.weight".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("layers.{i}.attention_norm.weight"),
+                format!("layers.{i}.attention.wq.weight"),
+                format!("layers.{i}.attention.wk.weight"),
+                format!("layers.{i}.attention.wv.weight"),
+                format!("layers.{i}.attention.wo.weight"),
+                format!("layers.{i}.ffn_norm.weight"),
+                format!("layers.{i}.feed_forward.w1.weight"),
+                format!("layers.{i}.feed_forward.w2.weight"),
+                format!("layers.{i}.feed_forward.w3.weight"),
+            ]);
+        }
+        names
+    }
+
+    fn split_dimension(name: &str, n_dims: usize) -> Option<usize> {
+        // Matches the layout used by the original multi-part LLaMA checkpoints.
+        if n_dims == 1 {
+            None
+        } else if name == "tok_embeddings.weight"
+            || name.ends_with(".attention.wo.weight")
+            || name.ends_with(".feed_forward.w2.weight")
+        {
+            Some(0)
+        } else {
+            Some(1)
+        }
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     /// Starts a new `InferenceSession` for this model.
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        let Hyperparameters { n_embd, n_head, .. } = self.hyperparameters;
+        let n_embd_gqa = n_embd / n_head * self.n_head_kv;
+
         InferenceSession::new(
             config,
             self.n_context_tokens,
             self.hyperparameters.n_layer,
-            self.hyperparameters.n_embd,
+            n_embd_gqa,
             self.hyperparameters.n_vocab,
         )
     }
@@ -135,6 +183,8 @@ impl KnownModel for Llama {
             file_type: _,
         } = self.hyperparameters;
         let n_ctx = self.n_context_tokens;
+        let n_head_kv = self.n_head_kv;
+        let n_embd_gqa = n_embd / n_head * n_head_kv;
 
         let (ctx0, embd) = common::prepare_for_evaluate(n_layer, session, input_tokens);
 
@@ -177,7 +227,7 @@ impl KnownModel for Llama {
                     &ctx0.op_reshape_3d(
                         &ctx0.op_mul_mat(&self.layers[il].wk, &current),
                         n_embd / n_head,
-                        n_head,
+                        n_head_kv,
                         n,
                     ),
                     n_past,
@@ -190,21 +240,21 @@ impl KnownModel for Llama {
                     // compute the transposed [N, n_embd] V matrix
                     let v_current = ctx0.op_transpose(&ctx0.op_reshape_2d(
                         &ctx0.op_mul_mat(&self.layers[il].wv, &current),
-                        n_embd,
+                        n_embd_gqa,
                         n,
                     ));
 
                     let k = ctx0.op_view_1d(
                         &session.memory_k,
-                        n * n_embd,
-                        (memk_elsize * n_embd) * (il * n_ctx + n_past),
+                        n * n_embd_gqa,
+                        (memk_elsize * n_embd_gqa) * (il * n_ctx + n_past),
                     );
 
                     let v = ctx0.op_view_2d(
                         &session.memory_v,
-                        (n, n_embd),
+                        (n, n_embd_gqa),
                         n_ctx * memv_elsize,
-                        (il * n_ctx) * memv_elsize * n_embd + n_past * memv_elsize,
+                        (il * n_ctx) * memv_elsize * n_embd_gqa + n_past * memv_elsize,
                     );
 
                     // important: storing RoPE-ed version of K in the KV cache!
@@ -214,15 +264,15 @@ impl KnownModel for Llama {
 
                 let q = ctx0.op_permute(&q_current, 0, 2, 1, 3);
 
-                let k = ctx0.op_permute(
+                let mut k = ctx0.op_permute(
                     &ctx0.op_reshape_3d(
                         &ctx0.op_view_1d(
                             &session.memory_k,
-                            (n_past + n) * n_embd,
-                            il * n_ctx * memk_elsize * n_embd,
+                            (n_past + n) * n_embd_gqa,
+                            il * n_ctx * memk_elsize * n_embd_gqa,
                         ),
                         n_embd / n_head,
-                        n_head,
+                        n_head_kv,
                         n_past + n,
                     ),
                     0,
@@ -230,40 +280,67 @@ impl KnownModel for Llama {
                     1,
                     3,
                 );
+                if n_head_kv != n_head {
+                    k = repeat_kv_heads(
+                        &ctx0,
+                        &k,
+                        (n_embd / n_head, n_past + n),
+                        n_head_kv,
+                        n_head,
+                    );
+                }
 
                 // K * Q
                 let k_q = ctx0.op_mul_mat(&k, &q);
 
                 // KQ_scaled = KQ / sqrt(n_embd/n_head)
-                let k_q_scaled = ctx0.op_scale(
-                    &k_q,
-                    &ctx0.new_f32(1.0 / f32::sqrt(n_embd as f32 / n_head as f32)),
-                );
+                let k_q_scaled =
+                    ctx0.op_scale_f32(&k_q, 1.0 / f32::sqrt(n_embd as f32 / n_head as f32));
 
                 // KQ_masked = mask_past(KQ_scaled)
-                let k_q_masked = ctx0.op_diag_mask_inf(&k_q_scaled, n_past);
+                //
+                // a single token may attend to every cached token, so nothing needs masking
+                let k_q_masked = if n == 1 {
+                    k_q_scaled
+                } else {
+                    ctx0.op_diag_mask_inf(&k_q_scaled, n_past)
+                };
 
                 // KQ = soft_max(KQ_masked)
-                let k_q_soft_max = ctx0.op_soft_max(&k_q_masked);
+                let k_q_soft_max = ctx0.op_soft_max_inplace(&k_q_masked);
 
-                // split cached V into n_head heads
-                let v = ctx0.op_view_3d(
+                // split cached V into n_head_kv heads
+                let mut v = ctx0.op_view_3d(
                     &session.memory_v,
-                    (n_past + n, n_embd / n_head, n_head),
+                    (n_past + n, n_embd / n_head, n_head_kv),
                     (n_ctx * memv_elsize, n_ctx * memv_elsize * n_embd / n_head),
-                    il * n_ctx * memv_elsize * n_embd,
+                    il * n_ctx * memv_elsize * n_embd_gqa,
                 );
+                if n_head_kv != n_head {
+                    v = repeat_kv_heads(
+                        &ctx0,
+                        &v,
+                        (n_past + n, n_embd / n_head),
+                        n_head_kv,
+                        n_head,
+                    );
+                }
 
                 let k_q_v = ctx0.op_mul_mat(&v, &k_q_soft_max);
 
-                // KQV_merged = KQV.permute(0, 2, 1, 3)
-                let k_q_v_merged = ctx0.op_permute(&k_q_v, 0, 2, 1, 3);
+                current = if n == 1 {
+                    // with a single token, KQV is laid out as KQV_merged already
+                    ctx0.op_reshape_2d(&k_q_v, n_embd, n)
+                } else {
+                    // KQV_merged = KQV.permute(0, 2, 1, 3)
+                    let k_q_v_merged = ctx0.op_permute(&k_q_v, 0, 2, 1, 3);
 
-                // cur = KQV_merged.contiguous().view(n_embd, N)
-                current = ctx0.op_cpy(
-                    &k_q_v_merged,
-                    &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n),
-                );
+                    // cur = KQV_merged.contiguous().view(n_embd, N)
+                    ctx0.op_cpy(
+                        &k_q_v_merged,
+                        &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, n),
+                    )
+                };
 
                 // projection (no bias)
                 current = ctx0.op_mul_mat(&self.layers[il].wo, &current);
@@ -307,6 +384,7 @@ impl KnownModel for Llama {
         ctx0.use_scratch(Some(&mut session.scratch[0]));
 
         // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor;
 
         // norm
         {
@@ -314,10 +392,11 @@ impl KnownModel for Llama {
 
             // inpL = norm*inpL
             input_layer = ctx0.op_mul(&ctx0.op_repeat(&self.norm, &input_layer), &input_layer);
+            embeddings_tensor = input_layer.share();
         }
 
         // lm_head
-        {
+        if !output_request.embeddings_only {
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
         }
 
@@ -328,9 +407,11 @@ impl KnownModel for Llama {
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -366,8 +447,12 @@ impl Llama {
         let output = context.new_f32(0.0);
 
         Self {
-            hyperparameters: Default::default(),
+            hyperparameters: Hyperparameters {
+                n_head: 1,
+                ..Default::default()
+            },
             n_context_tokens: 0,
+            n_head_kv: 1,
             vocabulary: Default::default(),
             tok_embeddings,
             norm,
@@ -378,6 +463,131 @@ impl Llama {
             inference_parameters: Default::default(),
         }
     }
+
+    /// Constructs a tiny single-layer model with deterministic, non-trivial
+    /// weights that can actually be evaluated, using `n_head_kv` key/value heads.
+    fn new_tiny(n_head_kv: usize) -> Self {
+        let hyperparameters = Hyperparameters {
+            n_vocab: 8,
+            n_embd: 8,
+            n_mult: 4,
+            n_head: 2,
+            n_layer: 1,
+            n_rot: 4,
+            file_type: Default::default(),
+        };
+        let n_embd_gqa = hyperparameters.n_embd / hyperparameters.n_head * n_head_kv;
+        let tensor_loader =
+            common::TestTensorLoader::new(Self::tensor_shapes(&hyperparameters, 16, n_embd_gqa));
+
+        Self::new(
+            hyperparameters,
+            ModelParameters::builder().context_size(16).build(),
+            None,
+            Default::default(),
+            tensor_loader,
+        )
+        .unwrap()
+    }
+
+    /// The shapes of the tensors of a model with the given `hyperparameters`, a
+    /// feed-forward layer of `n_ff` values and `n_embd_gqa` key/value values.
+    fn tensor_shapes(
+        hyperparameters: &Hyperparameters,
+        n_ff: usize,
+        n_embd_gqa: usize,
+    ) -> Vec<(String, Vec<usize>)> {
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = *hyperparameters;
+        Self::tensor_names(hyperparameters)
+            .into_iter()
+            .map(|name| {
+                let shape = if name.ends_with("norm.weight") {
+                    vec![n_embd]
+                } else if name == "tok_embeddings.weight" || name == "output.weight" {
+                    vec![n_embd, n_vocab]
+                } else if name.ends_with("wk.weight") || name.ends_with("wv.weight") {
+                    vec![n_embd, n_embd_gqa]
+                } else if name.ends_with("w1.weight") || name.ends_with("w3.weight") {
+                    vec![n_embd, n_ff]
+                } else if name.ends_with("w2.weight") {
+                    vec![n_ff, n_embd]
+                } else {
+                    vec![n_embd, n_embd]
+                };
+                (name, shape)
+            })
+            .collect()
+    }
+}
+
+/// The options of a [Llama] model that can be overridden when it is loaded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LlamaOverrides {
+    /// The number of key/value heads, for models with grouped-query attention such as
+    /// LLaMA 2 70B. It is read from the shape of the key weights, so this only checks
+    /// it: loading fails if the weights have a different number of key/value heads.
+    pub n_head_kv: Option<usize>,
+}
+impl From<ModelDynamicOverrides> for LlamaOverrides {
+    fn from(overrides: ModelDynamicOverrides) -> Self {
+        Self {
+            n_head_kv: overrides
+                .get::<i64>("n_head_kv")
+                .and_then(|n_head_kv| n_head_kv.try_into().ok()),
+        }
+    }
+}
+impl From<LlamaOverrides> for ModelDynamicOverrides {
+    fn from(overrides: LlamaOverrides) -> Self {
+        let mut dynamic = Self::default();
+        if let Some(n_head_kv) = overrides.n_head_kv {
+            dynamic.insert("n_head_kv", n_head_kv as i64);
+        }
+        dynamic
+    }
+}
+
+/// Reads the number of key/value heads from the shape of the key and value weights of
+/// `layers`, and checks it against the `overrides`.
+fn n_head_kv(
+    hyperparameters: &Hyperparameters,
+    layers: &[Layer],
+    overrides: LlamaOverrides,
+) -> Result<usize, ModelLoadError> {
+    let Hyperparameters { n_embd, n_head, .. } = *hyperparameters;
+    let head_size = n_embd / n_head;
+    let Some(first) = layers.first() else {
+        return Ok(overrides.n_head_kv.unwrap_or(n_head));
+    };
+
+    let n_embd_gqa = first.wk.dims()[1];
+    let n_head_kv = overrides.n_head_kv.unwrap_or(n_embd_gqa / head_size);
+    let expected = [n_embd, head_size * n_head_kv];
+    for (i, layer) in layers.iter().enumerate() {
+        for (name, weight) in [("wk", &layer.wk), ("wv", &layer.wv)] {
+            let actual = &weight.dims()[..2];
+            if actual != expected {
+                return Err(ModelLoadError::UnexpectedShape {
+                    name: format!("layers.{i}.attention.{name}.weight"),
+                    expected: expected.to_vec(),
+                    actual: actual.to_vec(),
+                });
+            }
+        }
+    }
+
+    // Each key/value head must be shared by the same number of query heads.
+    if n_head_kv == 0 || n_head % n_head_kv != 0 {
+        return Err(ModelLoadError::UnexpectedShape {
+            name: "layers.0.attention.wk.weight".to_owned(),
+            expected: vec![n_embd, n_embd],
+            actual: expected.to_vec(),
+        });
+    }
+
+    Ok(n_head_kv)
 }
 
 /// LLaMA [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -435,6 +645,29 @@ impl llm_base::Hyperparameters for Hyperparameters {
     }
 }
 
+/// Repeats each of the `n_head_kv` heads of the 3D tensor `kv`, with dimensions
+/// `[ne0, ne1, n_head_kv]`, so that it is shared by its group of query heads.
+///
+/// Query head `h` uses key/value head `h / (n_head / n_head_kv)`.
+fn repeat_kv_heads(
+    ctx0: &ggml::Context,
+    kv: &ggml::Tensor,
+    (ne0, ne1): (usize, usize),
+    n_head_kv: usize,
+    n_head: usize,
+) -> ggml::Tensor {
+    // `op_repeat` requires F32 data, so copy the (possibly F16 and non-contiguous) memory first.
+    let kv = ctx0.op_cpy(
+        kv,
+        &ctx0.new_tensor_3d(ggml::Type::F32, ne0, ne1, n_head_kv),
+    );
+    let kv = ctx0.op_repeat(
+        &ctx0.op_reshape_4d(&kv, ne0, ne1, 1, n_head_kv),
+        &ctx0.new_tensor_4d(ggml::Type::F32, ne0, ne1, n_head / n_head_kv, n_head_kv),
+    );
+    ctx0.op_reshape_3d(&kv, ne0, ne1, n_head)
+}
+
 struct Layer {
     attention_norm: ggml::Tensor,
 
@@ -473,4 +706,449 @@ mod tests {
             let _session = session;
         });
     }
+
+    #[test]
+    fn all_logits_are_returned_for_every_position() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let tokens = [1, 4, 2, 7];
+        let n_vocab = model.hyperparameters.n_vocab;
+
+        let mut session = model.start_session(Default::default());
+        let mut output_request = OutputRequest {
+            all_logits: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(&mut session, &params, &tokens, &mut output_request);
+
+        let logits = output_request.all_logits.unwrap();
+        assert_eq!(logits.len(), n_vocab * tokens.len());
+        let positions = logits.chunks(n_vocab).collect::<Vec<_>>();
+        assert_eq!(positions[tokens.len() - 1], session.last_logits);
+
+        // Each position predicts the next token from a different prefix, which is the
+        // same as evaluating that prefix on its own.
+        for (i, position) in positions.iter().enumerate() {
+            assert!(i == 0 || *position != positions[i - 1]);
+
+            let mut prefix_session = model.start_session(Default::default());
+            model.evaluate(
+                &mut prefix_session,
+                &params,
+                &tokens[..=i],
+                &mut OutputRequest::default(),
+            );
+            for (a, b) in position.iter().zip(&prefix_session.last_logits) {
+                assert!((a - b).abs() < 1e-4, "{a} != {b}");
+            }
+        }
+    }
+
+    #[test]
+    fn embeddings_only_evaluation_matches_full_evaluation() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let tokens = [1, 4, 2];
+
+        let mut full_session = model.start_session(Default::default());
+        let mut full_request = OutputRequest {
+            all_logits: Some(vec![]),
+            embeddings: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(&mut full_session, &params, &tokens, &mut full_request);
+
+        let mut fast_session = model.start_session(Default::default());
+        let mut fast_request = OutputRequest {
+            embeddings: Some(vec![]),
+            embeddings_only: true,
+            ..Default::default()
+        };
+        model.evaluate(&mut fast_session, &params, &tokens, &mut fast_request);
+
+        let full_embeddings = full_request.embeddings.unwrap();
+        let fast_embeddings = fast_request.embeddings.unwrap();
+        assert_eq!(
+            full_embeddings.len(),
+            model.hyperparameters.n_embd * tokens.len()
+        );
+        assert!(full_embeddings.iter().any(|&x| x != 0.0));
+        assert_eq!(full_embeddings, fast_embeddings);
+
+        // The fast path never computes the logits.
+        assert_eq!(fast_request.all_logits, None);
+        assert!(fast_session.last_logits.iter().all(|&x| x == 0.0));
+        assert_eq!(fast_session.n_past, tokens.len());
+    }
+
+    #[test]
+    fn single_token_evaluation_matches_batched_evaluation() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let tokens = [1, 4, 2, 7];
+
+        let mut batched = model.start_session(Default::default());
+        model.evaluate(
+            &mut batched,
+            &params,
+            &tokens,
+            &mut OutputRequest::default(),
+        );
+
+        // Every token after the first is evaluated against the cached keys and values.
+        let mut single = model.start_session(Default::default());
+        for token in tokens {
+            model.evaluate(
+                &mut single,
+                &params,
+                &[token],
+                &mut OutputRequest::default(),
+            );
+        }
+
+        assert_eq!(single.n_past, batched.n_past);
+        assert!(single.last_logits.iter().any(|&x| x != 0.0));
+        for (a, b) in single.last_logits.iter().zip(&batched.last_logits) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn f16_model_can_be_quantized_while_loading() {
+        use llm_base::{ggml::format::TensorLoadInfo, QuantizingTensorLoader};
+        use std::collections::HashMap;
+
+        let hyperparameters = Hyperparameters {
+            n_vocab: 8,
+            n_embd: 64,
+            n_mult: 32,
+            n_head: 2,
+            n_layer: 1,
+            n_rot: 32,
+            file_type: Default::default(),
+        };
+        // Q4_0 rows must be a multiple of 64 values for ggml to multiply them.
+        let shapes = Llama::tensor_shapes(&hyperparameters, 128, 64);
+        let infos = shapes
+            .iter()
+            .map(|(name, shape)| {
+                let info = TensorLoadInfo {
+                    name: name.clone(),
+                    n_dims: shape.len(),
+                    dims: [shape[0], shape.get(1).copied().unwrap_or(1)],
+                    n_elements: shape.iter().product(),
+                    element_type: if shape.len() == 1 {
+                        ggml::Type::F32
+                    } else {
+                        ggml::Type::F16
+                    },
+                    start_offset: 0,
+                };
+                (name.clone(), info)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let load = |quantize: bool| {
+            let loader =
+                common::TestTensorLoader::new(shapes.clone()).with_weight_type(ggml::Type::F16);
+            let params = ModelParameters::builder().context_size(16).build();
+            let vocabulary = Vocabulary::default();
+            if quantize {
+                let loader = QuantizingTensorLoader::new(loader, &infos, ggml::Type::Q4_0).unwrap();
+                Llama::new(hyperparameters, params, None, vocabulary, loader).unwrap()
+            } else {
+                Llama::new(hyperparameters, params, None, vocabulary, loader).unwrap()
+            }
+        };
+        let f16 = load(false);
+        let q4_0 = load(true);
+
+        assert_eq!(f16.layers[0].wq.get_type(), ggml::Type::F16);
+        assert_eq!(q4_0.layers[0].wq.get_type(), ggml::Type::Q4_0);
+        assert_eq!(q4_0.layers[0].w2.get_type(), ggml::Type::Q4_0);
+        assert_eq!(q4_0.norm.get_type(), ggml::Type::F32);
+
+        let logits = |model: &Llama| {
+            let mut session = model.start_session(Default::default());
+            model.evaluate(
+                &mut session,
+                &InferenceParameters::default(),
+                &[1, 4, 2, 7],
+                &mut OutputRequest::default(),
+            );
+            session.last_logits
+        };
+        let expected = logits(&f16);
+        let actual = logits(&q4_0);
+        assert!(actual.iter().all(|x| x.is_finite()));
+        // Quantization only perturbs the weights slightly, so the logits stay close.
+        let error = actual
+            .iter()
+            .zip(&expected)
+            .map(|(a, b)| (a - b).abs())
+            .sum::<f32>();
+        let magnitude = expected.iter().map(|x| x.abs()).sum::<f32>();
+        assert!(
+            error < 0.25 * magnitude,
+            "{actual:?} is not close to {expected:?}"
+        );
+    }
+
+    #[test]
+    fn perplexity_is_finite_and_at_least_one() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let tokens = (0..24).map(|i| (i * 5 % 8) as TokenId).collect::<Vec<_>>();
+
+        // Within a single context window.
+        let short = model.perplexity(&tokens[..8], &params);
+        assert!(short.is_finite() && short >= 1.0, "{short}");
+        // Longer than the context of 16 tokens, so evaluated with a sliding window.
+        let long = model.perplexity(&tokens, &params);
+        assert!(long.is_finite() && long >= 1.0, "{long}");
+
+        // The first token is never predicted, so a single token has no perplexity.
+        assert!(model.perplexity(&tokens[..1], &params).is_nan());
+    }
+
+    #[test]
+    fn speculative_decoding_with_the_same_model_accepts_every_draft() {
+        let mut model = Llama::new_tiny(2);
+        for id in 0..model.hyperparameters.n_vocab {
+            let token = vec![b'a' + id as u8];
+            model.vocabulary.push_token(id as TokenId, token, 0.0);
+        }
+
+        let params = InferenceParameters {
+            temperature: 0.0,
+            // Keep generating for the whole test.
+            logit_bias: [(model.eot_token_id(), f32::NEG_INFINITY)].into(),
+            ..Default::default()
+        };
+        let prompt = [1, 4, 7];
+        let n_draft = 3;
+        let n_generated = 12;
+
+        let start = || {
+            let mut session = model.start_session(Default::default());
+            session
+                .feed_prompt_tokens(
+                    &model,
+                    &params,
+                    &prompt,
+                    &mut OutputRequest::default(),
+                    |_| Ok::<_, std::convert::Infallible>(llm_base::InferenceFeedback::Continue),
+                )
+                .unwrap();
+            session
+        };
+        let mut rng = rand::thread_rng();
+
+        let mut session = start();
+        let mut expected = vec![];
+        for _ in 0..n_generated {
+            let token = session
+                .infer_next_token(&model, &params, &mut OutputRequest::default(), &mut rng)
+                .unwrap();
+            expected.extend_from_slice(token);
+        }
+
+        let mut speculative_session = start();
+        let mut draft_session = start();
+        let mut generated = vec![];
+        while speculative_session.n_tokens() < prompt.len() + n_generated {
+            let tokens = speculative_session
+                .infer_next_tokens_speculative(
+                    &model,
+                    &model,
+                    &mut draft_session,
+                    n_draft,
+                    &params,
+                    &mut rng,
+                )
+                .unwrap();
+            assert_eq!(tokens.len(), n_draft + 1);
+            for token in tokens {
+                generated.extend_from_slice(model.vocabulary.token(token as usize));
+            }
+        }
+
+        assert_eq!(generated, expected);
+        assert_eq!(speculative_session.n_tokens(), session.n_tokens());
+        assert_eq!(draft_session.n_tokens(), session.n_tokens());
+        assert_eq!(speculative_session.n_past, session.n_past);
+        for (a, b) in speculative_session
+            .last_logits
+            .iter()
+            .zip(&session.last_logits)
+        {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn warmup_leaves_the_session_unchanged() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let tokens = [1, 4, 2];
+
+        let mut session = model.start_session(Default::default());
+        session.warmup(&model, &params);
+        assert_eq!(session.n_past, 0);
+        assert_eq!(session.n_tokens(), 0);
+        assert!(session.last_logits.iter().all(|&x| x == 0.0));
+        assert!(session.mem_per_token > 0);
+
+        model.evaluate(
+            &mut session,
+            &params,
+            &tokens,
+            &mut OutputRequest::default(),
+        );
+        assert_eq!(session.n_past, tokens.len());
+
+        let mut cold_session = model.start_session(Default::default());
+        model.evaluate(
+            &mut cold_session,
+            &params,
+            &tokens,
+            &mut OutputRequest::default(),
+        );
+        for (a, b) in session.last_logits.iter().zip(&cold_session.last_logits) {
+            assert!((a - b).abs() < 1e-4, "{a} != {b}");
+        }
+    }
+
+    #[test]
+    fn benchmark_reports_throughput() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+
+        let report = model.benchmark(8, 4, &params).unwrap();
+        assert_eq!(report.stats.prompt_tokens, 8);
+        assert_eq!(report.stats.predict_tokens, 4);
+        assert!(report.prompt_tokens_per_second > 0.0);
+        assert!(report.predict_tokens_per_second > 0.0);
+
+        let n_ctx = model.n_context_tokens();
+        assert!(matches!(
+            model.benchmark(n_ctx, 1, &params),
+            Err(llm_base::InferenceError::ContextFull)
+        ));
+    }
+
+    #[test]
+    fn grouped_query_attention_shrinks_kv_memory() {
+        let model = Llama::new_tiny(1);
+        let params = InferenceParameters::default();
+        let tokens = [1, 4, 2];
+
+        // The number of key/value heads is read from the weights.
+        assert_eq!(model.n_head_kv, 1);
+        let full_session = Llama::new_tiny(2).start_session(Default::default());
+        let mut session = model.start_session(Default::default());
+        for (memory, full_memory) in [
+            (&session.memory_k, &full_session.memory_k),
+            (&session.memory_v, &full_session.memory_v),
+        ] {
+            assert_eq!(memory.nelements() * 2, full_memory.nelements());
+        }
+
+        // Evaluate in two steps so that the cached keys and values are used as well.
+        let mut output_request = OutputRequest {
+            all_logits: Some(vec![]),
+            embeddings: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(&mut session, &params, &tokens[..2], &mut output_request);
+        model.evaluate(&mut session, &params, &tokens[2..], &mut output_request);
+
+        let n_vocab = model.hyperparameters.n_vocab;
+        let n_embd = model.hyperparameters.n_embd;
+        let logits = output_request.all_logits.unwrap();
+        let embeddings = output_request.embeddings.unwrap();
+        assert_eq!(logits.len(), n_vocab);
+        assert_eq!(embeddings.len(), n_embd);
+        assert!(logits.iter().chain(&embeddings).all(|x| x.is_finite()));
+        assert_eq!(session.last_logits, logits);
+        assert_eq!(session.n_past, tokens.len());
+    }
+
+    #[test]
+    fn n_head_kv_override_is_checked_against_the_weights() {
+        let hyperparameters = Llama::new_tiny(2).hyperparameters;
+        let load = |n_embd_gqa: usize, n_head_kv: Option<usize>| {
+            Llama::new(
+                hyperparameters,
+                ModelParameters::default(),
+                Some(LlamaOverrides { n_head_kv }),
+                Default::default(),
+                common::TestTensorLoader::new(Llama::tensor_shapes(
+                    &hyperparameters,
+                    16,
+                    n_embd_gqa,
+                )),
+            )
+        };
+
+        assert_eq!(load(4, Some(1)).unwrap().n_head_kv, 1);
+        assert_eq!(load(8, None).unwrap().n_head_kv, 2);
+        for (n_embd_gqa, n_head_kv, expected) in [
+            // The override disagrees with the weights.
+            (8, Some(1), vec![8, 4]),
+            (4, Some(2), vec![8, 8]),
+            // The weights do not hold whole heads.
+            (6, None, vec![8, 4]),
+            // The key/value heads cannot be shared evenly between the query heads.
+            (12, None, vec![8, 8]),
+        ] {
+            match load(n_embd_gqa, n_head_kv) {
+                Err(ModelLoadError::UnexpectedShape {
+                    name,
+                    expected: actual_expected,
+                    ..
+                }) => {
+                    assert_eq!(name, "layers.0.attention.wk.weight");
+                    assert_eq!(actual_expected, expected);
+                }
+                Err(err) => panic!("unexpected error: {err}"),
+                Ok(_) => panic!("{n_embd_gqa} key/value values with {n_head_kv:?} heads loaded"),
+            }
+        }
+
+        let overrides = LlamaOverrides { n_head_kv: Some(1) };
+        assert_eq!(
+            LlamaOverrides::from(ModelDynamicOverrides::from(overrides)),
+            overrides
+        );
+    }
+
+    #[test]
+    fn intermediate_tensors_are_kept_in_scratch_buffers() {
+        let model = Llama::new_tiny(2);
+        let params = InferenceParameters::default();
+        let n_embd = model.hyperparameters.n_embd;
+
+        let used_mem = |n: usize| {
+            let tokens = (0..n).map(|i| (i % 8) as TokenId).collect::<Vec<_>>();
+            let mut session = model.start_session(Default::default());
+            model.evaluate(
+                &mut session,
+                &params,
+                &tokens,
+                &mut OutputRequest::default(),
+            );
+            session.mem_per_token * n
+        };
+        let short = used_mem(2);
+        let long = used_mem(14);
+
+        // Only the input tokens and their embeddings are kept in the context; the
+        // attention scores alone would take several times this much.
+        let growth = long.saturating_sub(short);
+        assert!(
+            growth < 12 * n_embd * std::mem::size_of::<f32>() * 2,
+            "context memory grew by {growth} bytes"
+        );
+    }
 }
@@ -6,7 +6,7 @@ use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
     util, FileType, InferenceParameters, InferenceSession, InferenceSessionConfig, KnownModel,
-    LoadError, ModelParameters, OutputRequest, TokenId, Vocabulary,
+    LoadError, ModelLoadError, ModelParameters, OutputRequest, TensorLoader, TokenId, Vocabulary,
 };
 
 /// The MosaicML Pretrained Transformer (MPT) model. Ref: [Mosaic ML](https://www.mosaicml.com/blog/mpt-7b)
@@ -19,7 +19,7 @@ pub struct Mpt {
 
     vocabulary: Vocabulary,
 
-    // position embedding
+    // token embedding
     wte_weight: Tensor,
 
     // language model head
@@ -41,13 +41,13 @@ impl KnownModel for Mpt {
     type Hyperparameters = Hyperparameters;
     type Overrides = ();
 
-    fn new<E: std::error::Error>(
+    fn new(
         hyperparameters: Self::Hyperparameters,
         params: ModelParameters,
         _overrides: Option<Self::Overrides>,
         vocabulary: Vocabulary,
-        tensor_loader: impl llm_base::TensorLoader<E>,
-    ) -> Result<Self, E> {
+        tensor_loader: impl TensorLoader,
+    ) -> Result<Self, ModelLoadError> {
         let mut tl = tensor_loader;
 
         // prepare memory for weights
@@ -92,6 +92,28 @@ impl KnownModel for Mpt {
         })
     }
 
+    fn tensor_names(hyperparameters: &Self::Hyperparameters) -> Vec<String> {
+        let mut names = vec![
+            "transformer.wte.weight".to_owned(),
+            "transformer.norm_f.weight".to_owned(),
+        ];
+        for i in 0..hyperparameters.n_layer {
+            names.extend([
+                format!("transformer.blocks.{i}.norm_1.weight"),
+                format!("transformer.blocks.{i}.attn.Wqkv.weight"),
+                format!("transformer.blocks.{i}.attn.out_proj.weight"),
+                format!("transformer.blocks.{i}.norm_2.weight"),
+                format!("transformer.blocks.{i}.ffn.up_proj.weight"),
+                format!("transformer.blocks.{i}.ffn.down_proj.weight"),
+            ]);
+        }
+        names
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         InferenceSession::new(
             config,
@@ -118,6 +140,7 @@ impl KnownModel for Mpt {
             n_vocab,
             n_layer,
             alibi_bias_max,
+            clip_kqv,
             ..
         } = self.hyperparameters;
         let n_ctx = self.n_context_tokens;
@@ -139,6 +162,8 @@ impl KnownModel for Mpt {
         let mut gf = ggml::ComputationGraph::new(n_threads);
 
         for il in 0..n_layer {
+            ctx0.use_scratch(Some(&mut session.scratch[0]));
+
             let mut current = ctx0.op_norm(&input_layer);
             current = ctx0.op_mul(
                 &ctx0.op_repeat(&self.layers[il].norm_1_weight, &current),
@@ -146,6 +171,10 @@ impl KnownModel for Mpt {
             );
 
             current = ctx0.op_mul_mat(&self.layers[il].c_attn_wqkv_weight, &current);
+            // a non-positive clip value means the model was trained without clipping
+            if clip_kqv > 0.0 {
+                current = ctx0.op_clamp(&current, -clip_kqv, clip_kqv);
+            }
 
             let nb = current.get_nb()[1];
             let qcur = ctx0.op_view_2d(&current, (n_embd, n), nb, 0);
@@ -195,13 +224,10 @@ impl KnownModel for Mpt {
             );
 
             let kq = ctx0.op_mul_mat(&bigk, &q);
-            let kq_scaled = ctx0.op_scale(
-                &kq,
-                &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
-            );
+            let kq_scaled = ctx0.op_scale_f32(&kq, 1f32 / f32::sqrt(n_embd as f32 / n_head as f32));
             let kq_scaled_alibi = ctx0.op_alibi(&kq_scaled, n_past, n_head, alibi_bias_max);
             let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled_alibi, n_past);
-            let kq_softmax = ctx0.op_soft_max(&kq_masked);
+            let kq_softmax = ctx0.op_soft_max_inplace(&kq_masked);
 
             let v_trans = ctx0.op_cpy(
                 &ctx0.op_permute(
@@ -235,6 +261,8 @@ impl KnownModel for Mpt {
             // projection
             current = ctx0.op_mul_mat(&self.layers[il].c_attn_out_proj_weight, &current);
 
+            ctx0.use_scratch(Some(&mut session.scratch[1]));
+
             input_layer = ctx0.op_add(&input_layer, &current);
 
             current = ctx0.op_norm(&input_layer);
@@ -253,6 +281,8 @@ impl KnownModel for Mpt {
             input_layer = ctx0.op_add(&input_layer, &current);
         }
 
+        ctx0.use_scratch(Some(&mut session.scratch[0]));
+
         // norm
         input_layer = ctx0.op_norm(&input_layer);
         input_layer = ctx0.op_mul(
@@ -260,17 +290,26 @@ impl KnownModel for Mpt {
             &input_layer,
         );
 
+        // Used at the end to optionally extract the embeddings.
+        let embeddings_tensor = input_layer.share();
+
         // output embedding weight tied to input embedding
-        input_layer = ctx0.op_mul_mat(&self.wte_weight, &input_layer);
+        if !output_request.embeddings_only {
+            input_layer = ctx0.op_mul_mat(&self.wte_weight, &input_layer);
+        }
+
+        ctx0.use_scratch(None);
 
         // run the computation
         gf.build_forward_expand(&input_layer);
         ctx0.graph_compute(&mut gf);
 
         // finish evaluation
-        common::read_last_token(session, &input_layer, n_vocab, n);
-        common::extract_logits(output_request, &input_layer, n_vocab, n);
-        common::extract_embeddings(output_request, &embd, n_embd, n);
+        if !output_request.embeddings_only {
+            common::read_last_token(session, &input_layer, n_vocab, n);
+            common::extract_logits(output_request, &input_layer, n_vocab, n);
+        }
+        common::extract_embeddings(output_request, &embeddings_tensor, n_embd, n);
         common::update_session(session, &ctx0, input_tokens.len(), n);
     }
 
@@ -379,3 +418,154 @@ struct Layer {
     ffn_up_proj: Tensor,
     ffn_down_proj: Tensor,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The embeddings of the tokens of [probe_model]. They are orthogonal, and each has a
+    /// mean of zero and a variance of one, so that normalizing them leaves them unchanged.
+    const EMBEDDINGS: [[f32; 4]; 3] = [
+        [1.0, -1.0, 1.0, -1.0],
+        [1.0, 1.0, -1.0, -1.0],
+        [1.0, -1.0, -1.0, 1.0],
+    ];
+
+    /// Constructs a single-layer model with two heads, in which the tokens only attend to each
+    /// other through the ALiBi bias: the queries and keys are zero, the values are the token
+    /// embeddings, and the feed-forward layer is zero.
+    fn probe_model(alibi_bias_max: f32, clip_kqv: f32) -> Mpt {
+        let (n_embd, n_vocab) = (4, EMBEDDINGS.len());
+        let hyperparameters = Hyperparameters {
+            n_embd,
+            max_seq_len: 16,
+            n_head: 2,
+            n_layer: 1,
+            n_vocab,
+            alibi_bias_max,
+            clip_kqv,
+            file_type: Default::default(),
+        };
+        let shapes = Mpt::tensor_names(&hyperparameters).into_iter().map(|name| {
+            let shape = match name.rsplit_once('.').unwrap().0 {
+                "transformer.wte" => vec![n_embd, n_vocab],
+                name if name.ends_with("Wqkv") => vec![n_embd, 3 * n_embd],
+                name if name.ends_with("up_proj") => vec![n_embd, 4 * n_embd],
+                name if name.ends_with("down_proj") => vec![4 * n_embd, n_embd],
+                name if name.ends_with("out_proj") => vec![n_embd, n_embd],
+                _ => vec![n_embd],
+            };
+            (name, shape)
+        });
+        let mut model = Mpt::new(
+            hyperparameters,
+            ModelParameters::builder().context_size(16).build(),
+            None,
+            Default::default(),
+            common::TestTensorLoader::new(shapes),
+        )
+        .unwrap();
+
+        // Row `r` of a weight holds the `r`th output of multiplying it with a vector.
+        let identity_rows = |first_row: usize, n_rows: usize| {
+            let mut values = vec![0.0f32; n_rows * n_embd];
+            for i in 0..n_embd {
+                values[(first_row + i) * n_embd + i] = 1.0;
+            }
+            values
+        };
+        let write = |tensor: &mut Tensor, values: &[f32]| {
+            assert_eq!(tensor.nelements(), values.len());
+            unsafe { tensor.write_data(bytemuck::cast_slice(values)) };
+        };
+        write(&mut model.wte_weight, &EMBEDDINGS.concat());
+        write(&mut model.norm_f_weight, &[1.0; 4]);
+        let layer = &mut model.layers[0];
+        write(&mut layer.norm_1_weight, &[1.0; 4]);
+        write(&mut layer.norm_2_weight, &[1.0; 4]);
+        write(
+            &mut layer.c_attn_wqkv_weight,
+            &identity_rows(2 * n_embd, 3 * n_embd),
+        );
+        write(&mut layer.c_attn_out_proj_weight, &identity_rows(0, n_embd));
+        write(&mut layer.ffn_up_proj, &[0.0; 4 * 4 * 4]);
+        write(&mut layer.ffn_down_proj, &[0.0; 4 * 4 * 4]);
+        model
+    }
+
+    /// Evaluates the tokens `0..3` with `model`, and returns the output of the last token.
+    fn last_output(model: &Mpt) -> Vec<f32> {
+        let tokens: Vec<TokenId> = (0..EMBEDDINGS.len() as TokenId).collect();
+        let mut session = model.start_session(Default::default());
+        let mut output_request = OutputRequest {
+            embeddings: Some(vec![]),
+            ..Default::default()
+        };
+        model.evaluate(
+            &mut session,
+            &InferenceParameters::default(),
+            &tokens,
+            &mut output_request,
+        );
+
+        let embeddings = output_request.embeddings.unwrap();
+        embeddings[embeddings.len() - 4..].to_vec()
+    }
+
+    /// The [last_output] of [probe_model] with a bias of `alibi_bias_max`, when the values
+    /// are the token embeddings scaled by `value_scale`.
+    ///
+    /// Head `h` of the two heads holds the dimensions `2h` and `2h + 1`, and its bias lowers
+    /// the score of a token `d` positions back by `d * m`, with
+    /// `m = 2^-(alibi_bias_max * (h + 1) / 2)`. The output is the normalized sum of the last
+    /// token's embedding and the attention-weighted values.
+    fn expected_output(alibi_bias_max: f32, value_scale: f32) -> Vec<f32> {
+        let hidden: Vec<f32> = (0..4)
+            .map(|i| {
+                let m = 2f32.powf(-alibi_bias_max * (i / 2 + 1) as f32 / 2.0);
+                let weights = [-2.0 * m, -m, 0.0].map(f32::exp);
+                let sum: f32 = weights.iter().sum();
+                let attention: f32 = weights
+                    .iter()
+                    .zip(EMBEDDINGS)
+                    .map(|(weight, embedding)| weight / sum * value_scale * embedding[i])
+                    .sum();
+                EMBEDDINGS[2][i] + attention
+            })
+            .collect();
+        let mean = hidden.iter().sum::<f32>() / 4.0;
+        let variance = hidden.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / 4.0;
+        hidden
+            .iter()
+            .map(|x| (x - mean) / variance.sqrt())
+            .collect()
+    }
+
+    fn assert_outputs_match(output: &[f32], expected: &[f32]) {
+        assert!(
+            output
+                .iter()
+                .zip(expected)
+                .all(|(a, b)| (a - b).abs() < 1e-4),
+            "{output:?} does not match {expected:?}"
+        );
+    }
+
+    #[test]
+    fn alibi_bias_decays_attention_to_earlier_tokens() {
+        for alibi_bias_max in [1.0, 3.0] {
+            let output = last_output(&probe_model(alibi_bias_max, 0.0));
+            assert_outputs_match(&output, &expected_output(alibi_bias_max, 1.0));
+        }
+    }
+
+    #[test]
+    fn clip_kqv_clamps_the_attention_values() {
+        // The values are the token embeddings, which only hold -1 and 1, so a clip of 0.5
+        // halves them, while a clip of 2 does not change them.
+        for (clip_kqv, value_scale) in [(0.0, 1.0), (0.5, 0.5), (2.0, 1.0)] {
+            let output = last_output(&probe_model(1.0, clip_kqv));
+            assert_outputs_match(&output, &expected_output(1.0, value_scale));
+        }
+    }
+}